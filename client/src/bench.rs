@@ -0,0 +1,91 @@
+use color_eyre::eyre::{anyhow, Result};
+use common::protocol::WireFormat;
+use std::{fmt, net::SocketAddr};
+use tokio::time::{Duration, Instant};
+
+use crate::connect;
+
+/// Outcome of [`run`]: how long all creates took and how long each one did.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub creates: usize,
+    pub elapsed: Duration,
+    /// Per-create latency, sorted ascending.
+    latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    pub fn throughput(&self) -> f64 {
+        self.creates as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Latency at `p` (0.0 to 1.0) of the distribution, nearest rank.
+    pub fn percentile(&self, p: f64) -> Duration {
+        let Some(last) = self.latencies.len().checked_sub(1) else {
+            return Duration::ZERO;
+        };
+        self.latencies[(last as f64 * p).round() as usize]
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} creates in {:?} ({:.1} creates/sec)",
+            self.creates,
+            self.elapsed,
+            self.throughput()
+        )?;
+        write!(
+            f,
+            "latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99),
+            self.percentile(1.0)
+        )
+    }
+}
+
+/// Issue `count` creates spread over `concurrency` connections.
+///
+/// Creates have no reply, so each is followed by an empty page read and timed
+/// until that answer arrives. The notes are left to expire on the server.
+pub async fn run(
+    addr: SocketAddr,
+    format: WireFormat,
+    count: usize,
+    concurrency: usize,
+) -> Result<BenchReport> {
+    if concurrency == 0 {
+        return Err(anyhow!("concurrency must be at least 1"));
+    }
+    let started = Instant::now();
+    let workers: Vec<_> = (0..concurrency)
+        .map(|worker| {
+            let creates = count / concurrency + usize::from(worker < count % concurrency);
+            tokio::spawn(async move {
+                let mut client = connect(addr, format).await?;
+                let mut latencies = Vec::with_capacity(creates);
+                for i in 0..creates {
+                    let sent = Instant::now();
+                    client.create_note(&format!("bench {worker}-{i}")).await?;
+                    latencies.push(sent.elapsed());
+                }
+                client.disconnect().await?;
+                Ok::<_, color_eyre::Report>(latencies)
+            })
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(count);
+    for worker in workers {
+        latencies.extend(worker.await??);
+    }
+    latencies.sort();
+    Ok(BenchReport {
+        creates: latencies.len(),
+        elapsed: started.elapsed(),
+        latencies,
+    })
+}
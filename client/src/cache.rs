@@ -0,0 +1,99 @@
+use color_eyre::eyre::{anyhow, Result};
+use common::protocol::NoteSummary;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Notes saved by the last online `list --cache`, for `list --offline`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cache {
+    /// When the notes were fetched, in seconds since the Unix epoch.
+    fetched_at: u64,
+    pub notes: Vec<NoteSummary>,
+}
+
+impl Cache {
+    /// How long ago the notes were fetched.
+    pub fn age(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.saturating_sub(Duration::from_secs(self.fetched_at))
+    }
+
+    /// The cached notes, with ages counted up to now rather than to the fetch.
+    pub fn aged_notes(&self) -> Vec<NoteSummary> {
+        let age = self.age().as_secs();
+        self.notes
+            .iter()
+            .map(|note| NoteSummary {
+                age_secs: note.age_secs + age,
+                ..note.clone()
+            })
+            .collect()
+    }
+}
+
+/// Where the cache lives unless `--cache-file` says otherwise:
+/// `$XDG_CACHE_HOME/tempo/notes.json`, falling back to `~/.cache`.
+pub fn default_path(var: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    let dir = match (var("XDG_CACHE_HOME"), var("HOME")) {
+        (Some(cache), _) => PathBuf::from(cache),
+        (None, Some(home)) => Path::new(&home).join(".cache"),
+        (None, None) => return Err(anyhow!("no cache directory; pass --cache-file")),
+    };
+    Ok(dir.join("tempo").join("notes.json"))
+}
+
+/// Replace the cache at `path` with `notes`, stamped with the current time.
+pub fn save(path: &Path, notes: &[NoteSummary]) -> Result<()> {
+    let fetched_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let cache = Cache {
+        fetched_at,
+        notes: notes.to_vec(),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_vec(&cache)?)?;
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<Cache> {
+    match fs::read(path) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(anyhow!(
+            "no cached notes at {}; run `list --cache` online first",
+            path.display()
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_path_prefers_xdg_cache_home() -> Result<()> {
+        let xdg = |name: &str| (name == "XDG_CACHE_HOME").then(|| "/xdg".to_string());
+        assert_eq!(default_path(xdg)?, Path::new("/xdg/tempo/notes.json"));
+        let home = |name: &str| (name == "HOME").then(|| "/home/me".to_string());
+        assert_eq!(
+            default_path(home)?,
+            Path::new("/home/me/.cache/tempo/notes.json")
+        );
+        assert!(default_path(|_| None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_cache_is_an_error() {
+        let path = std::env::temp_dir().join("tempo-cache-missing.json");
+        let err = load(&path).unwrap_err();
+        assert!(err.to_string().contains("list --cache"), "{err}");
+    }
+}
@@ -1,19 +1,180 @@
 use clap::{Parser, Subcommand};
+use common::{
+    protocol::{Compression, QuerySort},
+    ClientID, NoteID,
+};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 pub struct Args {
+    /// Server address as host:port, or several separated by commas to fail over between in order
     #[arg(short, long)]
     pub url: Option<String>,
+    /// Log at debug level, unless RUST_LOG says otherwise
     #[arg(short, long, default_value = "false")]
     pub verbose: bool,
+    /// Compression codec to negotiate with the server (none, lz4)
+    #[arg(short, long, default_value_t = Compression::None)]
+    pub compression: Compression,
+    /// Speak newline-delimited JSON; the server must run with --json-protocol
+    #[arg(long)]
+    pub json_protocol: bool,
+    /// Send note bodies length-prefixed, so they may contain line endings
+    #[arg(long, conflicts_with = "json_protocol")]
+    pub length_prefixed: bool,
+    /// Token to authenticate with, for servers started with --auth-tokens
+    #[arg(long)]
+    pub token: Option<String>,
+    /// Connect over TLS
+    #[arg(long)]
+    pub tls: bool,
+    /// PEM certificate to trust for --tls instead of the usual web roots, e.g. a self-signed one
+    #[arg(long, requires = "tls")]
+    pub ca_cert: Option<PathBuf>,
+    /// Times to try connecting again if the server can't be reached or sends no id
+    #[arg(long, default_value_t = 0)]
+    pub retries: u32,
+    /// Milliseconds to wait before the first retry, doubling for each one after
+    #[arg(long, default_value_t = 500, requires = "retries")]
+    pub retry_delay: u64,
+    /// Largest frame to accept from the server, in bytes
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    pub max_frame_size: usize,
     #[command(subcommand)]
     pub command: SubCommand,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum SubCommand {
-    New { note: String },
-    List,
+    New {
+        #[arg(required_unless_present = "template")]
+        note: Option<String>,
+        /// Create the note from a server-side template instead
+        #[arg(long, conflicts_with = "note")]
+        template: Option<String>,
+        /// Template placeholder value, as key=value; may be repeated
+        #[arg(long = "var", value_parser = parse_var, requires = "template")]
+        vars: Vec<(String, String)>,
+        /// Metadata attached to the note, as key=value; may be repeated
+        #[arg(long = "meta", value_parser = parse_var, conflicts_with = "template")]
+        metadata: Vec<(String, String)>,
+        /// Seconds until the note expires, instead of the server's note timeout
+        #[arg(long, conflicts_with_all = ["template", "metadata"])]
+        ttl: Option<u64>,
+    },
+    List {
+        /// Text printed before each note
+        #[arg(long, default_value = "- ")]
+        list_sep: String,
+        /// Terminate each note with a NUL byte and print nothing else, for `xargs -0`
+        #[arg(long, conflicts_with = "list_sep")]
+        print0: bool,
+        /// Save the fetched notes to the cache file, replacing what it held
+        #[arg(long)]
+        cache: bool,
+        /// List the cached notes without connecting to the server
+        #[arg(long, conflicts_with = "cache")]
+        offline: bool,
+        /// Cache file to use instead of $XDG_CACHE_HOME/tempo/notes.json
+        #[arg(long)]
+        cache_file: Option<PathBuf>,
+        /// Skip this many notes, in id order
+        #[arg(long, default_value_t = 0, conflicts_with_all = ["cache", "offline"])]
+        offset: u64,
+        /// List at most this many notes
+        #[arg(long, conflicts_with_all = ["cache", "offline"])]
+        limit: Option<u64>,
+    },
+    /// List the notes containing the given text, ignoring case; all notes if it is empty
+    Search { text: String },
+    /// Remove a note before it expires
+    Delete { id: NoteID },
+    /// Make another client the owner of a note
+    Transfer { id: NoteID, to: ClientID },
+    /// Exchange the bodies of two notes
+    Swap { a: NoteID, b: NoteID },
+    /// Print the note with the given id, or the notes with several ids,
+    /// listing those that do not exist
+    Get {
+        #[arg(required = true)]
+        ids: Vec<NoteID>,
+    },
+    /// Show everything the server knows about one note
+    Describe { id: NoteID },
+    /// Print a page of the notes matching some filters, in a chosen order
+    Query {
+        /// Only notes whose body contains this text, ignoring case
+        #[arg(long)]
+        contains: Option<String>,
+        /// Only notes with this tag
+        #[arg(long)]
+        tag: Option<String>,
+        /// Only notes created within the last this many seconds
+        #[arg(long)]
+        since: Option<u64>,
+        /// Order of the notes (id, created, ttl)
+        #[arg(long, default_value_t = QuerySort::Id)]
+        sort: QuerySort,
+        /// Matching notes to skip
+        #[arg(long, default_value_t = 0)]
+        offset: u32,
+        /// Most notes to print
+        #[arg(long, default_value_t = 20)]
+        limit: u32,
+    },
+    /// Add to a note holding an integer and print the new value
+    Incr {
+        id: NoteID,
+        #[arg(allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// Add a line to the end of a note and print the note's new length
+    Append { id: NoteID, text: String },
+    /// Measure create throughput and latency against the server
+    Bench {
+        /// Total creates to issue
+        #[arg(long, default_value_t = 1000)]
+        count: usize,
+        /// Connections to spread the creates over
+        #[arg(long, default_value_t = 4)]
+        concurrency: usize,
+    },
+    /// Show server counters, including how many of each command it has handled
+    Stats,
+    /// Print how many notes the server holds
+    Count,
+    /// Show the settings the server is running with
+    Config,
+    /// Measure round-trip time with pipelined pings
+    Ping {
+        /// Pings to send
+        #[arg(long, default_value_t = 10)]
+        count: u64,
+    },
+    /// Compare a snapshot saved with `list --cache` against the server's notes
+    Diff {
+        /// Snapshot to compare from
+        #[arg(long)]
+        before: PathBuf,
+        /// Snapshot to compare to instead of the server's current notes
+        #[arg(long)]
+        after: Option<PathBuf>,
+    },
+    /// Wait until a note containing the given text exists
+    Wait {
+        #[arg(long)]
+        contains: String,
+        /// Seconds to wait before giving up
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+    },
+}
+
+fn parse_var(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or(format!("expected key=value, got {s:?}"))?;
+    Ok((key.to_string(), value.to_string()))
 }
 
 pub fn parse() -> Args {
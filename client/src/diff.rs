@@ -0,0 +1,56 @@
+use common::{protocol::NoteSummary, NoteID};
+use std::{collections::BTreeMap, fmt};
+
+/// How the notes of two snapshots differ, matched up by id.
+#[derive(Debug, Default, PartialEq)]
+pub struct NoteDiff {
+    /// Notes only in the later snapshot.
+    pub added: Vec<NoteSummary>,
+    /// Notes only in the earlier snapshot.
+    pub removed: Vec<NoteSummary>,
+    /// Notes in both whose bodies differ, as `(before, after)`.
+    pub changed: Vec<(NoteSummary, NoteSummary)>,
+}
+
+impl NoteDiff {
+    /// Compare `before` with `after`. Ages are ignored, since they always move on.
+    pub fn new(before: &[NoteSummary], after: &[NoteSummary]) -> Self {
+        let by_id = |notes: &[NoteSummary]| -> BTreeMap<NoteID, NoteSummary> {
+            notes.iter().map(|note| (note.id, note.clone())).collect()
+        };
+        let mut before = by_id(before);
+        let mut diff = Self::default();
+        for (id, note) in by_id(after) {
+            match before.remove(&id) {
+                None => diff.added.push(note),
+                Some(old) if old.body != note.body => diff.changed.push((old, note)),
+                Some(_) => {}
+            }
+        }
+        diff.removed = before.into_values().collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// One line per note: `+` added, `-` removed, `~` changed with the old and new bodies.
+impl fmt::Display for NoteDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes.");
+        }
+        for note in &self.added {
+            writeln!(f, "+ [#{}] {}", note.id, note.body)?;
+        }
+        for note in &self.removed {
+            writeln!(f, "- [#{}] {}", note.id, note.body)?;
+        }
+        for (before, after) in &self.changed {
+            writeln!(f, "~ [#{}] {} -> {}", after.id, before.body, after.body)?;
+        }
+        Ok(())
+    }
+}
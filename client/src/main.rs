@@ -1,78 +1,536 @@
 use color_eyre::eyre::{anyhow, Result};
 use common::{
-    protocol::{Command, Frame},
-    Connection, WS_URL,
+    protocol::{
+        Command, Compression, EffectiveConfig, Frame, NoteBatch, NoteDescription, NoteFilter,
+        NoteSummary, QueryResult, QuerySort, Role, Stats, WireFormat, PROTOCOL_VERSION,
+    },
+    ClientID, Connection, NoteID, Stream, WS_URL,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    future::Future,
+    io::{self, Write},
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
 };
-use std::{env, net::ToSocketAddrs};
 use tokio::{
     net::TcpStream,
     time::{Duration, Instant},
 };
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+use tracing_subscriber::EnvFilter;
+mod bench;
+mod cache;
 mod cli;
+mod diff;
+mod tls;
+
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     let args = cli::parse();
-    let ws_url = args
-        .url
-        .unwrap_or(env::var("TEMPO_SERVER_URL").unwrap_or(WS_URL.to_string()));
-
-    let ws_url = ws_url.to_socket_addrs()?.collect::<Vec<_>>()[0];
-    println!("Connecting to {}", ws_url);
-    let mut client = connect(ws_url).await?;
+    // Logs go to stderr, so `list --print0` output can be piped as-is.
+    let level = if args.verbose { "debug" } else { "warn" };
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| level.into()))
+        .init();
+    if let cli::SubCommand::List {
+        list_sep,
+        print0,
+        offline: true,
+        cache_file,
+        ..
+    } = args.command
+    {
+        let cache = cache::load(&cache_path(cache_file)?)?;
+        eprintln!(
+            "Offline: showing notes cached {}s ago, which may be stale",
+            cache.age().as_secs()
+        );
+        io::stdout().write_all(render_notes(&cache.aged_notes(), &list_sep, print0).as_bytes())?;
+        return Ok(());
+    }
+    if let cli::SubCommand::Diff {
+        ref before,
+        after: Some(ref after),
+    } = args.command
+    {
+        print!(
+            "{}",
+            diff::NoteDiff::new(&cache::load(before)?.notes, &cache::load(after)?.notes)
+        );
+        return Ok(());
+    }
+    let url = server_url(args.url, |name| env::var(name).ok());
+    let targets = resolve(&url)?;
+    let format = if args.json_protocol {
+        WireFormat::Json
+    } else if args.length_prefixed {
+        WireFormat::LengthPrefixed
+    } else {
+        WireFormat::Binary
+    };
+    let retry = Retry {
+        retries: args.retries,
+        delay: Duration::from_millis(args.retry_delay),
+    };
+    let (addr, mut client) = if args.tls {
+        let connector = tls::connector(args.ca_cert.as_deref())?;
+        retry
+            .run(|| {
+                failover(&targets, |host, addr| {
+                    connect_tls(addr, format, &connector, host)
+                })
+            })
+            .await?
+    } else {
+        retry
+            .run(|| failover(&targets, |_, addr| connect(addr, format)))
+            .await?
+    };
+    client
+        .connection
+        .set_max_frame_size(Some(args.max_frame_size));
+    if args.compression != Compression::None {
+        client.negotiate(args.compression).await?;
+    }
+    if let Some(token) = &args.token {
+        client.authenticate(token).await?;
+    }
 
     match args.command {
-        cli::SubCommand::New { note } => {
-            client.create_note(&note).await?;
-        }
-        cli::SubCommand::List => {
-            let notes = client.read_notes().await?;
-            println!("Notes:");
-            for note in notes {
-                println!("- {}", note);
+        cli::SubCommand::New {
+            note,
+            template,
+            vars,
+            metadata,
+            ttl,
+        } => {
+            let id = match (note, template, ttl) {
+                (_, Some(name), _) => client.create_from_template(name, vars).await?,
+                (Some(note), None, Some(ttl)) => {
+                    client
+                        .create_note_with_ttl(&note, Duration::from_secs(ttl))
+                        .await?
+                }
+                (Some(note), None, None) if metadata.is_empty() => {
+                    client.create_note(&note).await?
+                }
+                (Some(note), None, None) => {
+                    client
+                        .create_note_with_metadata(&note, metadata.into_iter().collect())
+                        .await?
+                }
+                (None, None, _) => unreachable!("clap requires a note or a template"),
+            };
+            println!("Created note #{id}");
+        }
+        cli::SubCommand::List {
+            list_sep,
+            print0,
+            cache,
+            cache_file,
+            offset,
+            limit,
+            ..
+        } => {
+            let notes = if offset > 0 || limit.is_some() {
+                let (notes, more) = client.list_page(offset, limit.unwrap_or(u64::MAX)).await?;
+                if more {
+                    eprintln!(
+                        "More notes follow; pass --offset {}",
+                        offset + notes.len() as u64
+                    );
+                }
+                notes
+            } else {
+                client.read_notes().await?
+            };
+            if cache {
+                cache::save(&cache_path(cache_file)?, &notes)?;
+            }
+            io::stdout().write_all(render_notes(&notes, &list_sep, print0).as_bytes())?;
+        }
+        cli::SubCommand::Search { text } => {
+            let notes = client.search(&text).await?;
+            io::stdout().write_all(render_notes(&notes, "- ", false).as_bytes())?;
+        }
+        cli::SubCommand::Delete { id } => {
+            client.delete_note(id).await?;
+            println!("Deleted note #{id}");
+        }
+        cli::SubCommand::Transfer { id, to } => {
+            client.transfer_note(id, to).await?;
+            println!("Transferred note #{id} to client {to}");
+        }
+        cli::SubCommand::Swap { a, b } => {
+            client.swap_notes(a, b).await?;
+            println!("Swapped notes #{a} and #{b}");
+        }
+        cli::SubCommand::Incr { id, delta } => {
+            println!("{}", client.increment(id, delta).await?);
+        }
+        cli::SubCommand::Append { id, text } => {
+            println!("{}", client.append_note(id, &text).await?);
+        }
+        cli::SubCommand::Get { ids } => match ids[..] {
+            [id] => match client.get_note(id).await? {
+                Some(body) => println!("{body}"),
+                None => println!("not found"),
+            },
+            _ => {
+                let batch = client.get_many(ids).await?;
+                print!("{}", render_batch(&batch));
+            }
+        },
+        cli::SubCommand::Describe { id } => {
+            let description = client.describe(id).await?;
+            print!("{}", render_description(&description));
+        }
+        cli::SubCommand::Query {
+            contains,
+            tag,
+            since,
+            sort,
+            offset,
+            limit,
+        } => {
+            let since_ms = since.map(|secs| {
+                let since = SystemTime::now() - Duration::from_secs(secs);
+                since
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64
+            });
+            let filter = NoteFilter {
+                contains,
+                tag,
+                since_ms,
+            };
+            let result = client.query(filter, sort, offset, limit).await?;
+            print!("{}", render_query(&result, offset));
+        }
+        cli::SubCommand::Bench { count, concurrency } => {
+            client.quit().await?;
+            let report = bench::run(addr, format, count, concurrency).await?;
+            println!("{report}");
+            return Ok(());
+        }
+        cli::SubCommand::Count => println!("{}", client.count().await?),
+        cli::SubCommand::Stats => {
+            let stats = client.stats().await?;
+            println!("Notes: {} ({} bytes)", stats.notes, stats.used_bytes);
+            println!("Created: {}, expired: {}", stats.created, stats.expired);
+            println!("Clients: {}", stats.clients);
+            for (command, count) in stats.commands {
+                println!("- {command}: {count}");
+            }
+        }
+        cli::SubCommand::Config => {
+            print!("{}", render_config(&client.config().await?)?);
+        }
+        cli::SubCommand::Ping { count } => {
+            let rtts = client.ping(count).await?;
+            println!("{}", render_rtts(&rtts));
+        }
+        cli::SubCommand::Diff { before, .. } => {
+            let before = cache::load(&before)?;
+            let after = client.read_notes().await?;
+            print!("{}", diff::NoteDiff::new(&before.notes, &after));
+        }
+        cli::SubCommand::Wait { contains, timeout } => {
+            let timeout = Duration::from_secs(timeout);
+            match client.wait_for_note(&contains, timeout).await? {
+                Some(note) => println!("- {}", note),
+                None => {
+                    client.quit().await?;
+                    return Err(anyhow!(
+                        "timed out after {timeout:?} waiting for a note containing {contains:?}"
+                    ));
+                }
             }
         }
     }
-    client.disconnect().await?;
+    client.quit().await?;
     Ok(())
 }
 
-async fn connect<T: tokio::net::ToSocketAddrs>(addr: T) -> Result<Client> {
+/// Pick the server address from, in order of precedence, `--url`,
+/// `TEMPO_SERVER_URL`, `TEMPO_HOST` and/or `TEMPO_PORT`, and the built-in default.
+/// A lone `TEMPO_HOST` or `TEMPO_PORT` fills in the other half from the default.
+fn server_url(flag: Option<String>, var: impl Fn(&str) -> Option<String>) -> String {
+    if let Some(url) = flag.or_else(|| var("TEMPO_SERVER_URL")) {
+        return url;
+    }
+    let (default_host, default_port) = WS_URL.rsplit_once(':').expect("default has a port");
+    match (var("TEMPO_HOST"), var("TEMPO_PORT")) {
+        (None, None) => WS_URL.to_string(),
+        (host, port) => format!(
+            "{}:{}",
+            host.as_deref().unwrap_or(default_host),
+            port.as_deref().unwrap_or(default_port)
+        ),
+    }
+}
+
+/// Resolve the comma-separated `host:port` list `urls` into every address to
+/// try, in order, each with the host it came from.
+fn resolve(urls: &str) -> Result<Vec<(String, SocketAddr)>> {
+    let mut targets = Vec::new();
+    for url in urls.split(',').map(str::trim).filter(|url| !url.is_empty()) {
+        let host = url.rsplit_once(':').map_or(url, |(host, _)| host);
+        let addrs = url
+            .to_socket_addrs()
+            .map_err(|e| anyhow!("cannot resolve {url}: {e}"))?;
+        targets.extend(addrs.map(|addr| (host.to_string(), addr)));
+    }
+    if targets.is_empty() {
+        return Err(anyhow!("no server address in {urls:?}"));
+    }
+    Ok(targets)
+}
+
+/// The `--cache-file` given, or the default cache location.
+fn cache_path(flag: Option<PathBuf>) -> Result<PathBuf> {
+    flag.map_or_else(|| cache::default_path(|name| env::var(name).ok()), Ok)
+}
+
+/// Format notes for `list`: a header and one `sep`-prefixed note per line with
+/// its id and age, or NUL-terminated bodies alone when `print0` is set.
+fn render_notes(notes: &[NoteSummary], sep: &str, print0: bool) -> String {
+    if print0 {
+        return notes
+            .iter()
+            .map(|note| format!("{}\0", note.body))
+            .collect();
+    }
+    if notes.is_empty() {
+        return "No notes.\n".to_string();
+    }
+    let lines = notes.iter().map(|note| {
+        format!(
+            "{sep}[#{}, {}s ago] {}\n",
+            note.id, note.age_secs, note.body
+        )
+    });
+    std::iter::once("Notes:\n".to_string())
+        .chain(lines)
+        .collect()
+}
+
+/// Summarize ping round-trip times as `min/avg/max`.
+fn render_rtts(rtts: &[Duration]) -> String {
+    let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) else {
+        return "No pings sent.".to_string();
+    };
+    let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+    format!(
+        "{} pings, rtt min/avg/max = {min:?}/{avg:?}/{max:?}",
+        rtts.len()
+    )
+}
+
+/// Format the notes found by `get`, one `#id: body` line each, then any missing ids.
+fn render_batch(batch: &NoteBatch) -> String {
+    let mut rendered: String = batch
+        .found
+        .iter()
+        .map(|(id, body)| format!("#{id}: {body}\n"))
+        .collect();
+    if !batch.missing.is_empty() {
+        let missing = batch
+            .missing
+            .iter()
+            .map(|id| format!("#{id}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        rendered += &format!("Not found: {missing}\n");
+    }
+    rendered
+}
+
+/// One `#id: body (expires in ...)` line per note, then which matches were shown.
+fn render_query(result: &QueryResult, offset: u32) -> String {
+    let mut rendered: String = result
+        .notes
+        .iter()
+        .map(|note| {
+            let expires_in = Duration::from_millis(note.expires_in_ms);
+            format!("#{}: {} (expires in {expires_in:?})\n", note.id, note.body)
+        })
+        .collect();
+    rendered += &match result.notes.len() as u64 {
+        0 => format!("No notes shown of {} matching\n", result.total),
+        shown => {
+            let first = u64::from(offset) + 1;
+            format!("Notes {first}-{} of {}\n", first + shown - 1, result.total)
+        }
+    };
+    rendered
+}
+
+fn render_description(description: &NoteDescription) -> String {
+    let tags = description
+        .tags
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(", ");
+    let owner = description
+        .owner
+        .map_or("-".to_string(), |owner| format!("client {owner}"));
+    let metadata = description
+        .metadata
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "Note #{}\n  body:       {}\n  created:    {} ms since epoch\n  expires in: {:?}\n  tags:       {}\n  owner:      {}\n  metadata:   {}\n  hash:       {}\n",
+        description.id,
+        description.body,
+        description.created_at_ms,
+        Duration::from_millis(description.expires_in_ms),
+        tags,
+        owner,
+        metadata,
+        description.hash,
+    )
+}
+
+/// Format the server's settings for `config`, one `name: value` line each.
+fn render_config(config: &EffectiveConfig) -> Result<String> {
+    let serde_json::Value::Object(settings) = serde_json::to_value(config)? else {
+        unreachable!("the config serializes to an object");
+    };
+    let mut rendered = String::new();
+    for (name, value) in settings {
+        let value = match value {
+            serde_json::Value::Null => "none".to_string(),
+            serde_json::Value::String(s) => s,
+            serde_json::Value::Array(items) if items.is_empty() => "none".to_string(),
+            serde_json::Value::Array(items) => items
+                .iter()
+                .map(|item| {
+                    item.as_str()
+                        .map_or_else(|| item.to_string(), str::to_owned)
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+            value => value.to_string(),
+        };
+        rendered.push_str(&format!("{name}: {value}\n"));
+    }
+    Ok(rendered)
+}
+
+async fn connect<T: tokio::net::ToSocketAddrs>(addr: T, format: WireFormat) -> Result<Client> {
     let socket = tokio::time::timeout(Duration::from_secs(30), TcpStream::connect(addr)).await??;
-    let connection = Connection::new(socket);
+    let connection = Connection::with_format(Box::new(socket) as Box<dyn Stream>, format);
+    Client::new(connection).await
+}
+
+/// Connect to each of `targets` in turn, returning the first client that
+/// connects and its address, or every failure if none does.
+async fn failover<'a, F>(
+    targets: &'a [(String, SocketAddr)],
+    mut connect: impl FnMut(&'a str, SocketAddr) -> F,
+) -> Result<(SocketAddr, Client)>
+where
+    F: Future<Output = Result<Client>>,
+{
+    let mut errors = Vec::new();
+    for (host, addr) in targets {
+        tracing::info!(%addr, "connecting");
+        match connect(host, *addr).await {
+            Ok(client) => return Ok((*addr, client)),
+            Err(e) => {
+                tracing::warn!(%addr, "failed to connect: {e}");
+                errors.push(format!("{addr}: {e}"));
+            }
+        }
+    }
+    Err(anyhow!("failed to connect to {}", errors.join("; ")))
+}
+
+/// How often to try connecting again after a failed attempt, e.g. while the
+/// server restarts: first after `delay`, then twice as long each time.
+#[derive(Debug, Clone, Copy)]
+struct Retry {
+    retries: u32,
+    delay: Duration,
+}
+
+impl Retry {
+    async fn run<T, F: Future<Output = Result<T>>>(
+        self,
+        mut attempt: impl FnMut() -> F,
+    ) -> Result<T> {
+        let mut delay = self.delay;
+        for retry in 1..=self.retries {
+            match attempt().await {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    tracing::warn!(retry, ?delay, "failed to connect, retrying: {e}");
+                    tokio::time::sleep(delay).await;
+                    delay = delay.saturating_mul(2);
+                }
+            }
+        }
+        attempt().await
+    }
+}
+
+/// Like [`connect`], over TLS to a server whose certificate is for `host`.
+async fn connect_tls<T: tokio::net::ToSocketAddrs>(
+    addr: T,
+    format: WireFormat,
+    connector: &TlsConnector,
+    host: &str,
+) -> Result<Client> {
+    let name = ServerName::try_from(host.to_owned())?;
+    let socket = tokio::time::timeout(Duration::from_secs(30), async {
+        let socket = TcpStream::connect(addr).await?;
+        connector.connect(name, socket).await
+    })
+    .await??;
+    let connection = Connection::with_format(Box::new(socket) as Box<dyn Stream>, format);
     Client::new(connection).await
 }
 
 #[derive(Debug)]
 pub struct Client {
-    connection: Connection,
+    connection: Connection<Box<dyn Stream>>,
     id: u64,
 }
 
 impl Client {
-    async fn new(mut connection: Connection) -> Result<Self> {
-        let start = Instant::now();
-        let timeout = start + Duration::from_secs(30);
-        let command = tokio::time::timeout_at(timeout, async {
-            println!("Waiting for id...");
-            let Frame(command) = loop {
-                if let Some(frame) = connection.read_frame().await.expect("connection closed") {
-                    break frame;
-                }
-                let time_left = timeout - Instant::now();
-                if time_left <= Duration::from_secs(5) {
-                    println!("waiting for id ({time_left:.0?} left)...");
-                }
-                tokio::time::sleep(Duration::from_secs(1)).await;
-            };
-            command
+    async fn new(mut connection: Connection<Box<dyn Stream>>) -> Result<Self> {
+        let timeout = Instant::now() + Duration::from_secs(30);
+        let Frame(command) = tokio::time::timeout_at(timeout, async {
+            tracing::debug!("waiting for id");
+            connection
+                .read_frame()
+                .await?
+                .ok_or(anyhow!("connection closed before an id was received"))
         })
         .await
-        .expect("connection timeout: no id received");
+        .map_err(|_| anyhow!("connection timeout: no id received"))??;
         match command {
             Command::Id(id) => {
-                println!("Connected, id: {}", id);
+                // Anything already queued behind the id would otherwise
+                // surface as a confusing reply to our first request.
+                if let Some(Frame(extra)) = connection.parse_frame()? {
+                    return Err(anyhow!(
+                        "unexpected {extra} frame during handshake after id {id}"
+                    ));
+                }
+                tracing::info!(id, "connected");
                 Ok(Self { connection, id })
             }
             c => Err(anyhow!(
@@ -82,29 +540,321 @@ impl Client {
             )),
         }
     }
-    async fn create_note(&mut self, body: &str) -> Result<()> {
-        let body = body.trim().to_string() + "\r\n";
+    /// Read the reply to the last request, reporting any expiry warnings
+    /// the server pushed in the meantime and answering its keepalive pings.
+    /// An error frame is returned as a [`common::Error::Rejected`].
+    async fn read_reply(&mut self) -> Result<Command> {
+        loop {
+            let Frame(command) = self
+                .connection
+                .read_frame()
+                .await?
+                .ok_or(anyhow!("connection closed early"))?;
+            tracing::debug!(?command, "read frame");
+            match command {
+                Command::Error(message) => return Err(common::Error::Rejected(message).into()),
+                Command::Expiring(id) => eprintln!("Note #{id} expires soon"),
+                Command::Closing(reason) => {
+                    return Err(anyhow!("server closed the connection: {reason}"))
+                }
+                Command::Ping(nonce) => {
+                    self.connection
+                        .write_frame(&Command::Pong(nonce).into())
+                        .await?
+                }
+                command => return Ok(command),
+            }
+        }
+    }
+
+    async fn negotiate(&mut self, compression: Compression) -> Result<()> {
+        let hello = Command::Hello {
+            version: PROTOCOL_VERSION,
+            compression,
+        };
+        self.connection.write_frame(&hello.into()).await?;
+        let command = self.read_reply().await?;
+        match command {
+            Command::Hello { compression, .. } => {
+                self.connection.set_compression(compression);
+                Ok(())
+            }
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn create_note(&mut self, body: &str) -> Result<NoteID> {
+        let body = body.trim().to_string();
         self.connection
             .write_frame(&Command::Create(body).into())
             .await?;
+        self.read_created().await
+    }
 
-        Ok(())
+    async fn create_note_with_metadata(
+        &mut self,
+        body: &str,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<NoteID> {
+        let body = body.trim().to_string();
+        self.connection
+            .write_frame(&Command::CreateWithMetadata { body, metadata }.into())
+            .await?;
+        self.read_created().await
+    }
+
+    async fn create_note_with_ttl(&mut self, body: &str, ttl: Duration) -> Result<NoteID> {
+        let body = body.trim().to_string();
+        self.connection
+            .write_frame(&Command::CreateWithTtl(ttl, body).into())
+            .await?;
+        self.read_created().await
+    }
+
+    async fn create_from_template(
+        &mut self,
+        name: String,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<NoteID> {
+        let vars = vars.into_iter().collect();
+        self.connection
+            .write_frame(&Command::CreateFromTemplate { name, vars }.into())
+            .await?;
+        self.read_created().await
     }
 
-    async fn read_notes(&mut self) -> Result<Vec<String>> {
+    /// Read the id the server gave the note just created.
+    async fn read_created(&mut self) -> Result<NoteID> {
+        match self.read_reply().await? {
+            Command::Created(id) => Ok(id),
+            Command::Create(body) => Err(anyhow!(
+                "server echoed {body:?} instead of an id; it may be running with --echo-creates or --dry-run"
+            )),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn read_notes(&mut self) -> Result<Vec<NoteSummary>> {
         self.connection.write_frame(&Command::Read.into()).await?;
-        let Frame(command) = self
-            .connection
-            .read_frame()
-            .await?
-            .expect("connection closed early");
+        let command = self.read_reply().await?;
         match command {
             Command::List(notes) => Ok(notes),
             c => Err(anyhow!("unexpected command type: {}", c.to_string())),
         }
     }
 
-    async fn _quit(&mut self) -> Result<()> {
+    /// Fetch up to `limit` notes after the first `offset`, and whether more follow.
+    async fn list_page(&mut self, offset: u64, limit: u64) -> Result<(Vec<NoteSummary>, bool)> {
+        self.connection
+            .write_frame(&Command::ListPage { offset, limit }.into())
+            .await?;
+        match self.read_reply().await? {
+            Command::ListPageResult { notes, more } => Ok((notes, more)),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn search(&mut self, text: &str) -> Result<Vec<NoteSummary>> {
+        self.connection
+            .write_frame(&Command::Search(text.to_string()).into())
+            .await?;
+        match self.read_reply().await? {
+            Command::List(notes) => Ok(notes),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn swap_notes(&mut self, a: NoteID, b: NoteID) -> Result<()> {
+        self.connection
+            .write_frame(&Command::Swap(a, b).into())
+            .await?;
+        let command = self.read_reply().await?;
+        match command {
+            Command::Swap(..) => Ok(()),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn delete_note(&mut self, id: NoteID) -> Result<()> {
+        self.connection
+            .write_frame(&Command::Delete(id).into())
+            .await?;
+        match self.read_reply().await? {
+            Command::Delete(_) => Ok(()),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn transfer_note(&mut self, id: NoteID, to: ClientID) -> Result<()> {
+        self.connection
+            .write_frame(&Command::Transfer(id, to).into())
+            .await?;
+        match self.read_reply().await? {
+            Command::Transfer(..) => Ok(()),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn authenticate(&mut self, token: &str) -> Result<Role> {
+        self.connection
+            .write_frame(&Command::Auth(token.to_string()).into())
+            .await?;
+        match self.read_reply().await? {
+            Command::Authenticated(role) => Ok(role),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn append_note(&mut self, id: NoteID, text: &str) -> Result<u64> {
+        self.connection
+            .write_frame(&Command::Append(id, text.to_string()).into())
+            .await?;
+        match self.read_reply().await? {
+            Command::Appended(_, len) => Ok(len),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn increment(&mut self, id: NoteID, delta: i64) -> Result<i64> {
+        self.connection
+            .write_frame(&Command::Increment(id, delta).into())
+            .await?;
+        match self.read_reply().await? {
+            Command::Counter(_, value) => Ok(value),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    /// Fetch one note's body, or `None` if the server has no such note.
+    async fn get_note(&mut self, id: NoteID) -> Result<Option<String>> {
+        self.connection
+            .write_frame(&Command::Get(id).into())
+            .await?;
+        match self.read_reply().await {
+            Ok(Command::Note(_, body)) => Ok(Some(body)),
+            // The server words a missing note this way; other errors are real failures.
+            Err(e)
+                if matches!(
+                    e.downcast_ref(),
+                    Some(common::Error::Rejected(message)) if *message == format!("note {id} not found")
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+            Ok(c) => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn get_many(&mut self, ids: Vec<NoteID>) -> Result<NoteBatch> {
+        self.connection
+            .write_frame(&Command::GetMany(ids).into())
+            .await?;
+        match self.read_reply().await? {
+            Command::Batch(batch) => Ok(batch),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn query(
+        &mut self,
+        filter: NoteFilter,
+        sort: QuerySort,
+        offset: u32,
+        limit: u32,
+    ) -> Result<QueryResult> {
+        let query = Command::Query {
+            filter,
+            sort,
+            offset,
+            limit,
+        };
+        self.connection.write_frame(&query.into()).await?;
+        match self.read_reply().await? {
+            Command::QueryResult(result) => Ok(result),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn describe(&mut self, id: NoteID) -> Result<NoteDescription> {
+        self.connection
+            .write_frame(&Command::Describe(id).into())
+            .await?;
+        let command = self.read_reply().await?;
+        match command {
+            Command::Description(description) => Ok(description),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    /// Send `count` pings at once and time each until its pong comes back.
+    async fn ping(&mut self, count: u64) -> Result<Vec<Duration>> {
+        let mut sent = HashMap::new();
+        for nonce in 0..count {
+            self.connection.feed_frame(&Command::Ping(nonce).into())?;
+            sent.insert(nonce, Instant::now());
+        }
+        self.connection.flush().await?;
+        let mut rtts = Vec::new();
+        while !sent.is_empty() {
+            match self.read_reply().await? {
+                Command::Pong(nonce) => {
+                    let sent_at = sent
+                        .remove(&nonce)
+                        .ok_or(anyhow!("pong for unknown nonce {nonce}"))?;
+                    rtts.push(sent_at.elapsed());
+                }
+                c => return Err(anyhow!("unexpected command type: {}", c.to_string())),
+            }
+        }
+        Ok(rtts)
+    }
+
+    async fn stats(&mut self) -> Result<Stats> {
+        self.connection.write_frame(&Command::Stats.into()).await?;
+        let command = self.read_reply().await?;
+        match command {
+            Command::StatsResult(stats) => Ok(stats),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn count(&mut self) -> Result<u64> {
+        self.connection.write_frame(&Command::Count.into()).await?;
+        match self.read_reply().await? {
+            Command::CountResult(count) => Ok(count),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    async fn config(&mut self) -> Result<EffectiveConfig> {
+        self.connection.write_frame(&Command::Config.into()).await?;
+        let command = self.read_reply().await?;
+        match command {
+            Command::ConfigResult(config) => Ok(config),
+            c => Err(anyhow!("unexpected command type: {}", c.to_string())),
+        }
+    }
+
+    /// Poll the notes until one contains `needle`, or return `None` once `timeout` elapses.
+    async fn wait_for_note(&mut self, needle: &str, timeout: Duration) -> Result<Option<String>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let notes = self.read_notes().await?;
+            if let Some(note) = notes.into_iter().find(|note| note.body.contains(needle)) {
+                return Ok(Some(note.body));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(WAIT_POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    /// End the session; the server closes the connection after answering
+    /// what was sent before.
+    async fn quit(&mut self) -> Result<()> {
         self.connection.write_frame(&Command::Quit.into()).await?;
         Ok(())
     }
@@ -116,3 +866,582 @@ impl Client {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::{auth::AuthTokens, NotesServer, ServerConfig};
+    use tokio::net::TcpListener;
+
+    async fn spawn_server() -> Result<SocketAddr> {
+        spawn_server_with(ServerConfig::default()).await
+    }
+
+    async fn spawn_server_with(config: ServerConfig) -> Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(listener, NotesServer::with_config(config)));
+        Ok(addr)
+    }
+
+    /// Hand every connection accepted on `listener` to `notes_server`.
+    async fn serve(listener: TcpListener, mut notes_server: NotesServer) {
+        while let Ok((socket, _)) = listener.accept().await {
+            notes_server
+                .handle_connection(socket)
+                .await
+                .expect("failed to handle connection");
+        }
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn server_url_precedence() {
+        let all = [
+            ("TEMPO_SERVER_URL", "env:1"),
+            ("TEMPO_HOST", "host"),
+            ("TEMPO_PORT", "2"),
+        ];
+        assert_eq!(server_url(Some("flag:0".into()), vars(&all)), "flag:0");
+        assert_eq!(server_url(None, vars(&all)), "env:1");
+        assert_eq!(server_url(None, vars(&all[1..])), "host:2");
+        assert_eq!(server_url(None, vars(&[])), WS_URL);
+    }
+
+    #[test]
+    fn server_url_fills_missing_host_or_port_from_default() {
+        let (host, port) = WS_URL.rsplit_once(':').unwrap();
+        assert_eq!(
+            server_url(None, vars(&[("TEMPO_HOST", "example.com")])),
+            format!("example.com:{port}")
+        );
+        assert_eq!(
+            server_url(None, vars(&[("TEMPO_PORT", "9000")])),
+            format!("{host}:9000")
+        );
+    }
+
+    #[test]
+    fn render_notes_separators() {
+        let notes = vec![
+            NoteSummary {
+                id: 3,
+                age_secs: 12,
+                body: "- dashed".to_string(),
+            },
+            NoteSummary {
+                id: 7,
+                age_secs: 0,
+                body: "multi\nline".to_string(),
+            },
+        ];
+        assert_eq!(
+            render_notes(&notes, "- ", false),
+            "Notes:\n- [#3, 12s ago] - dashed\n- [#7, 0s ago] multi\nline\n"
+        );
+        assert_eq!(
+            render_notes(&notes, "* ", false),
+            "Notes:\n* [#3, 12s ago] - dashed\n* [#7, 0s ago] multi\nline\n"
+        );
+        assert_eq!(render_notes(&notes, "- ", true), "- dashed\0multi\nline\0");
+    }
+
+    #[tokio::test]
+    async fn duplicate_id_during_handshake_is_rejected() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let mut connection = Connection::new(listener.accept().await?.0);
+            connection.feed_frame(&Command::Id(1).into())?;
+            connection.feed_frame(&Command::Id(2).into())?;
+            connection.flush().await?;
+            Ok::<_, color_eyre::Report>(connection)
+        });
+        let Err(err) = connect(addr, WireFormat::Binary).await else {
+            panic!("handshake should fail");
+        };
+        assert!(err.to_string().contains("during handshake"), "{err}");
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_on_empty_server_says_no_notes() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        let notes = client.read_notes().await?;
+        assert_eq!(render_notes(&notes, "- ", false), "No notes.\n");
+        assert_eq!(render_notes(&notes, "- ", true), "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_reports_each_note_id() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        for body in ["one", "two", "three"] {
+            client.create_note(body).await?;
+        }
+        client.delete_note(1).await?;
+        client.create_note("four").await?;
+
+        let notes = client.read_notes().await?;
+        let listed: Vec<_> = notes
+            .iter()
+            .map(|note| (note.id, note.body.as_str()))
+            .collect();
+        assert_eq!(listed, [(0, "one"), (2, "three"), (3, "four")]);
+        assert!(notes.iter().all(|note| note.age_secs < 60));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_fetches_present_and_reports_absent_ids() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        client.create_note("buy milk").await?;
+        client.create_note("buy bread").await?;
+        let batch = client.get_many(vec![1, 5, 0]).await?;
+        assert_eq!(batch.missing, vec![5]);
+        assert_eq!(
+            render_batch(&batch),
+            "#0: buy milk\n#1: buy bread\nNot found: #5\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_note_returns_the_body_or_none() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        let id = client.create_note("buy milk").await?;
+        assert_eq!(client.get_note(id).await?.as_deref(), Some("buy milk"));
+        assert_eq!(client.get_note(id + 1).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_pages_through_matching_notes() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        for body in ["buy milk", "call mum", "buy bread", "buy eggs"] {
+            client.create_note(body).await?;
+        }
+        let filter = NoteFilter {
+            contains: Some("BUY".to_string()),
+            ..Default::default()
+        };
+        let result = client
+            .query(filter.clone(), QuerySort::Created, 1, 1)
+            .await?;
+        assert_eq!(result.total, 3);
+        assert_eq!(result.notes.len(), 1);
+        assert_eq!(result.notes[0].body, "buy bread");
+        assert!(render_query(&result, 1).starts_with("#2: buy bread (expires in "));
+        assert!(render_query(&result, 1).ends_with("Notes 2-2 of 3\n"));
+
+        let past_the_end = client.query(filter, QuerySort::Created, 5, 1).await?;
+        assert_eq!(
+            render_query(&past_the_end, 5),
+            "No notes shown of 3 matching\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn config_lists_server_settings() -> Result<()> {
+        let mut tokens = AuthTokens::default();
+        tokens.insert("ops", Role::Admin);
+        let addr = spawn_server_with(ServerConfig {
+            auth_tokens: Some(tokens),
+            ..Default::default()
+        })
+        .await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        assert_eq!(client.authenticate("ops").await?, Role::Admin);
+        let rendered = render_config(&client.config().await?)?;
+        assert!(rendered.contains("\nmax_bytes: none\n"), "{rendered}");
+        assert!(rendered.contains("\nmax_in_flight: 32\n"), "{rendered}");
+        assert!(rendered.contains("\ntemplates: none\n"), "{rendered}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_list_is_an_error() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let mut connection = Connection::new(listener.accept().await?.0);
+            connection.write_frame(&Command::Id(1).into()).await?;
+            connection.read_frame().await?;
+            let notes = vec![
+                NoteSummary {
+                    body: "x".repeat(1024),
+                    ..Default::default()
+                };
+                64
+            ];
+            connection.write_frame(&Command::List(notes).into()).await
+        });
+
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        client.connection.set_max_frame_size(Some(4096));
+        let err = client.read_notes().await.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(common::Error::FrameTooLarge { limit: 4096 })
+            ),
+            "{err}"
+        );
+        // The server may see the client hang up before the list is written.
+        let _ = server.await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn diff_sorts_notes_into_added_removed_and_changed() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        for body in ["kept", "dropped", "edited"] {
+            client.create_note(body).await?;
+        }
+        let path = std::env::temp_dir().join(format!("tempo-diff-{}.json", std::process::id()));
+        cache::save(&path, &client.read_notes().await?)?;
+        client.delete_note(1).await?;
+        client.append_note(2, "again").await?;
+        client.create_note("added").await?;
+
+        let before = cache::load(&path);
+        std::fs::remove_file(&path)?;
+        let diff = diff::NoteDiff::new(&before?.notes, &client.read_notes().await?);
+        let ids = |notes: &[NoteSummary]| notes.iter().map(|note| note.id).collect::<Vec<_>>();
+        assert_eq!(ids(&diff.added), [3]);
+        assert_eq!(ids(&diff.removed), [1]);
+        let [(old, new)] = &diff.changed[..] else {
+            panic!("expected one changed note, got {:?}", diff.changed);
+        };
+        assert_eq!((old.id, old.body.as_str()), (2, "edited"));
+        assert_eq!(new.body, "edited\nagain");
+        assert_eq!(
+            diff.to_string(),
+            "+ [#3] added\n- [#1] dropped\n~ [#2] edited -> edited\nagain\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_matches_bodies_ignoring_case() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        for body in ["Buy MILK", "call mum", "milk the cow"] {
+            client.create_note(body).await?;
+        }
+        let ids = |notes: Vec<NoteSummary>| notes.iter().map(|note| note.id).collect::<Vec<_>>();
+        assert_eq!(ids(client.search("milk").await?), [0, 2]);
+        assert_eq!(ids(client.search("MUM").await?), [1]);
+        assert_eq!(ids(client.search("").await?), [0, 1, 2]);
+        assert!(client.search("bread").await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notes_round_trip_over_self_signed_tls() -> Result<()> {
+        let signed = rcgen::generate_simple_self_signed(["localhost".to_string()])?;
+        let dir = std::env::temp_dir();
+        let cert = dir.join(format!("tempo-tls-{}.crt", std::process::id()));
+        let key = dir.join(format!("tempo-tls-{}.key", std::process::id()));
+        std::fs::write(&cert, signed.cert.pem())?;
+        std::fs::write(&key, signed.signing_key.serialize_pem())?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let mut notes_server = NotesServer::default();
+        notes_server.serve_tls(server::tls::acceptor(&cert, &key)?);
+        tokio::spawn(serve(listener, notes_server));
+
+        let connector = tls::connector(Some(&cert))?;
+        let mut client = connect_tls(addr, WireFormat::Binary, &connector, "localhost").await?;
+        client.create_note("over tls").await?;
+        let notes = client.read_notes().await?;
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].body, "over tls");
+        // The web roots don't vouch for a self-signed certificate.
+        let untrusted = tls::connector(None)?;
+        assert!(
+            connect_tls(addr, WireFormat::Binary, &untrusted, "localhost")
+                .await
+                .is_err()
+        );
+        std::fs::remove_file(cert)?;
+        std::fs::remove_file(key)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connecting_retries_until_the_server_sends_an_id() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            // Hang up on the first client before it gets an id, as a server going down would.
+            drop(listener.accept().await);
+            serve(listener, NotesServer::default()).await;
+        });
+
+        let retry = Retry {
+            retries: 2,
+            delay: Duration::from_millis(10),
+        };
+        let mut attempts = 0;
+        let mut client = retry
+            .run(|| {
+                attempts += 1;
+                connect(addr, WireFormat::Binary)
+            })
+            .await?;
+        assert_eq!(attempts, 2);
+        assert_eq!(client.create_note("second time lucky").await?, 0);
+
+        let once = Retry {
+            retries: 0,
+            delay: Duration::ZERO,
+        };
+        let closed = TcpListener::bind("127.0.0.1:0").await?;
+        let closed_addr = closed.local_addr()?;
+        drop(closed);
+        assert!(once
+            .run(|| connect(closed_addr, WireFormat::Binary))
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_fails_over_to_the_next_address() -> Result<()> {
+        let closed = TcpListener::bind("127.0.0.1:0").await?;
+        let dead = closed.local_addr()?;
+        drop(closed);
+        let live = spawn_server().await?;
+
+        let targets = resolve(&format!("{dead}, {live}"))?;
+        assert_eq!(targets.len(), 2);
+        let (addr, mut client) =
+            failover(&targets, |_, addr| connect(addr, WireFormat::Binary)).await?;
+        assert_eq!(addr, live);
+        assert_eq!(client.create_note("second address").await?, 0);
+
+        let targets = resolve(&format!("{dead},{dead}"))?;
+        let err = failover(&targets, |_, addr| connect(addr, WireFormat::Binary))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string().matches(&dead.to_string()).count(), 2);
+        assert!(resolve(" , ").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn paging_visits_every_note_once() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        for i in 0..250 {
+            client.create_note(&format!("note {i}")).await?;
+        }
+        let mut ids = Vec::new();
+        let mut pages = 0;
+        loop {
+            let (notes, more) = client.list_page(ids.len() as u64, 100).await?;
+            assert!(notes.len() <= 100);
+            ids.extend(notes.iter().map(|note| note.id));
+            pages += 1;
+            if !more {
+                break;
+            }
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(ids, (0..250).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_follows_creates_and_expiry() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        assert_eq!(client.count().await?, 0);
+        client.create_note("stays").await?;
+        client
+            .create_note_with_ttl("goes", Duration::from_secs(1))
+            .await?;
+        assert_eq!(client.count().await?, 2);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(client.count().await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notes_expire_after_their_own_ttl() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        client
+            .create_note_with_ttl("short", Duration::from_secs(1))
+            .await?;
+        client
+            .create_note_with_ttl("long", Duration::from_secs(10))
+            .await?;
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let notes = client.read_notes().await?;
+        let bodies: Vec<_> = notes.iter().map(|note| note.body.as_str()).collect();
+        assert_eq!(bodies, ["long"]);
+        let err = client
+            .create_note_with_ttl("never", Duration::ZERO)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("ttl must be positive"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_returns_new_ids() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        assert_eq!(client.create_note("buy milk").await?, 0);
+        let metadata = BTreeMap::from([("source".to_string(), "email".to_string())]);
+        assert_eq!(
+            client
+                .create_note_with_metadata("call back", metadata)
+                .await?,
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cached_list_is_read_back_offline() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        let path = std::env::temp_dir().join(format!("tempo-cache-{}.json", std::process::id()));
+
+        client.create_note("buy milk").await?;
+        cache::save(&path, &client.read_notes().await?)?;
+        client.create_note("buy eggs").await?;
+        // Each online list replaces the cache rather than adding to it.
+        cache::save(&path, &client.read_notes().await?)?;
+        client.disconnect().await?;
+
+        let cached = cache::load(&path)?;
+        std::fs::remove_file(&path)?;
+        let bodies: Vec<_> = cached.notes.iter().map(|note| note.body.as_str()).collect();
+        assert_eq!(bodies, ["buy milk", "buy eggs"]);
+        assert!(cached.age() < Duration::from_secs(60));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn append_grows_the_note() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        let id = client.create_note("log").await?;
+        assert_eq!(client.append_note(id, "first entry").await?, 15);
+        assert_eq!(
+            client.get_note(id).await?.as_deref(),
+            Some("log\nfirst entry")
+        );
+        assert!(client.append_note(id + 1, "lost").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn updates_to_missing_notes_are_errors() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        let rejected = |result: Result<_>| match result {
+            Err(e) => {
+                matches!(e.downcast_ref(), Some(common::Error::Rejected(message)) if message.contains("not found"))
+            }
+            Ok(_) => false,
+        };
+        let updates = async {
+            assert!(rejected(client.swap_notes(0, 1).await.map(|_| 0)));
+            assert!(rejected(client.increment(3, 1).await));
+            assert!(rejected(client.append_note(3, "more").await.map(|_| 0)));
+            // The connection survives the errors.
+            client.create_note("buy milk").await
+        };
+        let id = tokio::time::timeout(Duration::from_secs(5), updates).await??;
+        assert_eq!(id, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_removes_note_once() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        let id = client.create_note("buy milk").await?;
+        client.delete_note(id).await?;
+        assert!(client.read_notes().await?.is_empty());
+        let err = client.delete_note(0).await.unwrap_err();
+        assert!(err.to_string().contains("not found"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ping_matches_every_pong() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        let rtts = client.ping(5).await?;
+        assert_eq!(rtts.len(), 5);
+        assert!(render_rtts(&rtts).starts_with("5 pings, rtt min/avg/max = "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bench_reports_throughput() -> Result<()> {
+        let addr = spawn_server().await?;
+        let report = bench::run(addr, WireFormat::Binary, 20, 3).await?;
+        assert_eq!(report.creates, 20);
+        assert!(report.throughput() > 0.0);
+        assert!(report.percentile(0.5) <= report.percentile(0.99));
+
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        assert_eq!(client.read_notes().await?.len(), 20);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_finds_note_created_later() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let mut writer = connect(addr, WireFormat::Binary)
+                .await
+                .expect("failed to connect");
+            writer
+                .create_note("buy milk")
+                .await
+                .expect("failed to create");
+        });
+        let note = client.wait_for_note("milk", Duration::from_secs(5)).await?;
+        assert_eq!(note.as_deref(), Some("buy milk"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_times_out() -> Result<()> {
+        let addr = spawn_server().await?;
+        let mut client = connect(addr, WireFormat::Binary).await?;
+        let start = Instant::now();
+        let note = client
+            .wait_for_note("never", Duration::from_millis(500))
+            .await?;
+        assert_eq!(note, None);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        Ok(())
+    }
+}
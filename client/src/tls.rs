@@ -0,0 +1,32 @@
+use color_eyre::eyre::{anyhow, Result};
+use std::{path::Path, sync::Arc};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
+
+/// A connector trusting the PEM certificates at `ca`, e.g. a server's
+/// self-signed one, or the usual web roots without it.
+pub fn connector(ca: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in CertificateDer::pem_file_iter(ca)
+                .map_err(|e| anyhow!("failed to read certificates from {}: {e}", ca.display()))?
+            {
+                roots.add(cert?)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
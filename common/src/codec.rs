@@ -0,0 +1,613 @@
+use bytes::{Buf, BytesMut};
+use color_eyre::{eyre::Result, Report};
+use std::io::Cursor;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{protocol::*, Error, FrameParseError, NoteID};
+
+/// A frame read by [`FrameCodec`], or the [`Error::MalformedFrame`] it was
+/// skipped with.
+pub type Decoded = std::result::Result<Frame, Error>;
+
+fn malformed(e: Report) -> Error {
+    Error::MalformedFrame(e.to_string())
+}
+
+/// Splits a byte stream into [`Frame`]s and encodes them back, for use with
+/// `tokio_util::codec::Framed` and friends. Frames that can't be parsed are
+/// skipped and decoded as errors, so one bad frame doesn't end the stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameCodec {
+    pub format: WireFormat,
+    /// Applied to every frame in both directions; switched once `Command::Hello` negotiates it.
+    pub compression: Compression,
+    /// Largest frame accepted when decoding, as sent on the wire. A peer
+    /// going over it is an error rather than an ever-growing read buffer.
+    pub max_frame_size: Option<usize>,
+}
+
+impl FrameCodec {
+    pub fn new(format: WireFormat) -> Self {
+        Self {
+            format,
+            compression: Compression::None,
+            max_frame_size: None,
+        }
+    }
+
+    /// Fail once a frame of `len` bytes, complete or not, is over the limit.
+    fn check_size(&self, len: usize) -> Result<()> {
+        match self.max_frame_size {
+            Some(limit) if len > limit => Err(Error::FrameTooLarge { limit }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn decode_plain(&self, src: &mut BytesMut) -> Result<Option<Decoded>> {
+        if self.format == WireFormat::Json {
+            let Some(end) = src.iter().position(|&b| b == b'\n') else {
+                self.check_size(src.len())?;
+                return Ok(None);
+            };
+            self.check_size(end + 1)?;
+            let line = src.split_to(end + 1);
+            return Ok(Some(Frame::from_json(&line[..end]).map_err(malformed)));
+        }
+        let mut buf = Cursor::new(&src[..]);
+
+        match Frame::check(&mut buf) {
+            Ok(_) => {
+                let len = buf.position() as usize;
+                self.check_size(len)?;
+                buf.set_position(0);
+                let frame = Frame::parse(&mut buf).map_err(malformed);
+                src.advance(len);
+                Ok(Some(frame))
+            }
+            // Everything buffered belongs to the unfinished frame.
+            Err(FrameParseError::Incomplete) => {
+                self.check_size(src.len())?;
+                Ok(None)
+            }
+            // Skip to the next byte that could start a frame.
+            Err(e @ FrameParseError::Invalid(_)) => {
+                let skipped = src
+                    .iter()
+                    .position(|&byte| Command::try_from(byte).is_ok())
+                    .unwrap_or(src.len());
+                src.advance(skipped);
+                Ok(Some(Err(Error::MalformedFrame(format!(
+                    "{e}, skipped {skipped} bytes"
+                )))))
+            }
+        }
+    }
+
+    /// Compressed frames are a 4-byte big-endian block length followed by
+    /// an lz4 block holding exactly one encoded frame. The block starts with
+    /// the little-endian size it decompresses to, which is held to the limit
+    /// too before that much is allocated.
+    fn decode_compressed(&self, src: &mut BytesMut) -> Result<Option<Decoded>> {
+        let Some(len) = src.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(len.try_into()?) as usize;
+        self.check_size(4 + len)?;
+        if src.len() < 4 + len {
+            return Ok(None);
+        }
+        // Shorter blocks fail to decompress below.
+        if let Some(size) = src.get(4..8).filter(|_| len >= 4) {
+            self.check_size(u32::from_le_bytes(size.try_into()?) as usize)?;
+        }
+        src.advance(4);
+        let block = src.split_to(len);
+        let frame = lz4_flex::decompress_size_prepended(&block)
+            .map_err(Report::from)
+            .and_then(|bytes| match self.format {
+                WireFormat::Binary | WireFormat::LengthPrefixed => {
+                    Frame::parse(&mut Cursor::new(&bytes[..]))
+                }
+                WireFormat::Json => Frame::from_json(bytes.strip_suffix(b"\n").unwrap_or(&bytes)),
+            });
+        Ok(Some(frame.map_err(malformed)))
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Decoded;
+    type Error = Report;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Decoded>> {
+        match self.compression {
+            Compression::None => self.decode_plain(src),
+            Compression::Lz4 => self.decode_compressed(src),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Decoded>> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(Error::UnexpectedEof {
+                buffered: src.len(),
+            }
+            .into()),
+        }
+    }
+}
+
+impl Encoder<&Frame> for FrameCodec {
+    type Error = Report;
+
+    fn encode(&mut self, frame: &Frame, dst: &mut BytesMut) -> Result<()> {
+        let bytes = match self.format {
+            WireFormat::Binary => encode_frame(frame),
+            WireFormat::Json => frame.to_json()?,
+            WireFormat::LengthPrefixed => encode_length_prefixed_frame(frame),
+        };
+        match self.compression {
+            Compression::None => dst.extend_from_slice(&bytes),
+            Compression::Lz4 => {
+                let block = lz4_flex::compress_prepend_size(&bytes);
+                let len = u32::try_from(block.len())?.to_be_bytes();
+                dst.extend_from_slice(&len);
+                dst.extend_from_slice(&block);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = Report;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<()> {
+        self.encode(&frame, dst)
+    }
+}
+
+/// Encode `frame` for [`WireFormat::LengthPrefixed`]: note bodies are sized
+/// rather than `\r\n`-terminated, everything else is as in [`encode_frame`].
+fn encode_length_prefixed_frame(frame: &Frame) -> Vec<u8> {
+    let sized = |bytes: &[u8]| {
+        let len = u32::try_from(bytes.len()).expect("note bodies are under 4 GiB");
+        [&len.to_be_bytes()[..], bytes].concat()
+    };
+    // The argument line `encode_frame` writes, sized instead of `\r\n`-terminated.
+    let sized_line = |byte: u8| {
+        let line = encode_frame(frame);
+        [&[byte][..], &sized(&line[1..line.len() - 2])].concat()
+    };
+    match frame.0 {
+        Command::Create(ref body) => {
+            [&[LENGTH_PREFIXED_CREATE_BYTE][..], &sized(body.as_bytes())].concat()
+        }
+        Command::List(_) => sized_line(LENGTH_PREFIXED_LIST_BYTE),
+        Command::ListPageResult { .. } => sized_line(LENGTH_PREFIXED_LIST_PAGE_RESULT_BYTE),
+        Command::Note(..) => sized_line(LENGTH_PREFIXED_NOTE_BYTE),
+        Command::Notify { .. } => sized_line(LENGTH_PREFIXED_NOTIFY_BYTE),
+        Command::Page { .. } => sized_line(LENGTH_PREFIXED_PAGE_BYTE),
+        Command::CompareAndUpdate {
+            id,
+            ref expected_body,
+            ref new_body,
+        } => [
+            &[LENGTH_PREFIXED_COMPARE_AND_UPDATE_BYTE][..],
+            &id.to_be_bytes(),
+            &sized(expected_body.as_bytes()),
+            &sized(new_body.as_bytes()),
+        ]
+        .concat(),
+        _ => encode_frame(frame),
+    }
+}
+
+pub(crate) fn encode_frame(frame: &Frame) -> Vec<u8> {
+    match frame.0 {
+        Command::Create(ref body) => [&[CREATE_BYTE], body.as_bytes(), b"\r\n"].concat(),
+        // Each note as its id, age and body, all length-prefixed.
+        Command::List(ref notes) => {
+            let frame_arg = format!("{}\r\n", encode_strings(summary_fields(notes)));
+            [&[LIST_BYTE], frame_arg.as_bytes()].concat()
+        }
+        Command::ListPage { offset, limit } => {
+            let body = format!("{offset} {limit}");
+            [&[LIST_PAGE_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::ListPageResult { ref notes, more } => {
+            let more = if more { "1" } else { "0" }.to_string();
+            let fields = std::iter::once(more).chain(summary_fields(notes));
+            [
+                &[LIST_PAGE_RESULT_BYTE],
+                encode_strings(fields).as_bytes(),
+                b"\r\n",
+            ]
+            .concat()
+        }
+        Command::Read => vec![READ_BYTE],
+        Command::Quit => vec![QUIT_BYTE],
+        Command::Disconnect(id) => {
+            let body = id.to_string();
+            [&[DISCONNECT_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Id(id) => {
+            let body = id.to_string();
+            [&[ID_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Hello {
+            version,
+            compression,
+        } => {
+            let body = format!("{version} {compression}");
+            [&[HELLO_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Swap(a, b) => {
+            let body = format!("{a} {b}");
+            [&[SWAP_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Error(ref message) => [&[ERROR_BYTE], message.as_bytes(), b"\r\n"].concat(),
+        Command::CreateFromTemplate { ref name, ref vars } => {
+            let strings = std::iter::once(name).chain(vars.iter().flat_map(|(k, v)| [k, v]));
+            let body = encode_strings(strings);
+            [&[CREATE_FROM_TEMPLATE_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::CreateWithMetadata {
+            ref body,
+            ref metadata,
+        } => {
+            let strings = std::iter::once(body).chain(metadata.iter().flat_map(|(k, v)| [k, v]));
+            let body = encode_strings(strings);
+            [&[CREATE_WITH_METADATA_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Stats => vec![STATS_BYTE],
+        Command::Unsubscribe => vec![UNSUBSCRIBE_BYTE],
+        // serde_json escapes control characters, so JSON bodies never contain `\r\n`.
+        Command::StatsResult(ref stats) => {
+            let body = serde_json::to_vec(stats).expect("stats always serialize");
+            [&[STATS_RESULT_BYTE], &body[..], b"\r\n"].concat()
+        }
+        Command::Config => vec![CONFIG_BYTE],
+        Command::Count => vec![COUNT_BYTE],
+        Command::CountResult(count) => {
+            let body = count.to_string();
+            [&[COUNT_RESULT_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::ConfigResult(ref config) => {
+            let body = serde_json::to_vec(config).expect("config always serializes");
+            [&[CONFIG_RESULT_BYTE], &body[..], b"\r\n"].concat()
+        }
+        Command::Describe(id) => {
+            let body = id.to_string();
+            [&[DESCRIBE_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::CompareAndUpdate {
+            id,
+            ref expected_body,
+            ref new_body,
+        } => {
+            let body = format!("{id} {}", encode_strings([expected_body, new_body]));
+            [&[COMPARE_AND_UPDATE_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::CompareAndUpdateResult { id, updated } => {
+            let body = format!("{id} {updated}");
+            [&[COMPARE_AND_UPDATE_RESULT_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Created(id) => {
+            let body = id.to_string();
+            [&[CREATED_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Delete(id) => {
+            let body = id.to_string();
+            [&[DELETE_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Get(id) => {
+            let body = id.to_string();
+            [&[GET_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Note(id, ref body) => {
+            let body = format!("{id} {body}");
+            [&[NOTE_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Query {
+            ref filter,
+            sort,
+            offset,
+            limit,
+        } => {
+            let filter = serde_json::to_string(filter).expect("filters always serialize");
+            let body = format!("{sort} {offset} {limit} {filter}");
+            [&[QUERY_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::QueryResult(ref result) => {
+            let body = serde_json::to_vec(result).expect("query results always serialize");
+            [&[QUERY_RESULT_BYTE], &body[..], b"\r\n"].concat()
+        }
+        Command::Append(id, ref text) => {
+            let body = format!("{id} {text}");
+            [&[APPEND_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Appended(id, len) => {
+            let body = format!("{id} {len}");
+            [&[APPENDED_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::CreateWithTtl(ttl, ref body) => {
+            let body = format!("{} {body}", ttl.as_millis());
+            [&[CREATE_WITH_TTL_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Search(ref text) => [&[SEARCH_BYTE], text.as_bytes(), b"\r\n"].concat(),
+        Command::Transfer(id, target) => {
+            let body = format!("{id} {target}");
+            [&[TRANSFER_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Auth(ref token) => [&[AUTH_BYTE], token.as_bytes(), b"\r\n"].concat(),
+        Command::Authenticated(role) => {
+            let body = role.to_string();
+            [&[AUTHENTICATED_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Expiring(id) => {
+            let body = id.to_string();
+            [&[EXPIRING_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::GetByHash(ref hash) => [&[GET_BY_HASH_BYTE], hash.as_bytes(), b"\r\n"].concat(),
+        Command::GetMany(ref ids) => {
+            let body = encode_strings(ids.iter().map(NoteID::to_string));
+            [&[GET_MANY_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Batch(ref batch) => {
+            let body = serde_json::to_vec(batch).expect("batches always serialize");
+            [&[BATCH_BYTE], &body[..], b"\r\n"].concat()
+        }
+        Command::Closing(reason) => {
+            let body = reason.to_string();
+            [&[CLOSING_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Ping(nonce) => {
+            let body = nonce.to_string();
+            [&[PING_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        // Empty filters stand in for `None`.
+        Command::Subscribe {
+            ref contains,
+            ref tag,
+        } => {
+            let filters = [contains, tag].map(|filter| filter.as_deref().unwrap_or_default());
+            let body = encode_strings(filters);
+            [&[SUBSCRIBE_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Notify { seq, id, ref body } => {
+            let body = format!("{seq} {id} {body}");
+            [&[NOTIFY_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Increment(id, delta) => {
+            let body = format!("{id} {delta}");
+            [&[INCREMENT_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Counter(id, value) => {
+            let body = format!("{id} {value}");
+            [&[COUNTER_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Pong(nonce) => {
+            let body = nonce.to_string();
+            [&[PONG_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        // Empty cursors stand in for `None`; the server never issues one.
+        Command::ReadPage { ref cursor, limit } => {
+            let body = format!("{limit} {}", cursor.as_deref().unwrap_or_default());
+            [&[READ_PAGE_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Page {
+            ref notes,
+            ref cursor,
+        } => {
+            let cursor = cursor.as_deref().unwrap_or_default();
+            let body =
+                encode_strings(std::iter::once(cursor).chain(notes.iter().map(String::as_str)));
+            [&[PAGE_BYTE], body.as_bytes(), b"\r\n"].concat()
+        }
+        Command::Description(ref description) => {
+            let body = serde_json::to_vec(description).expect("descriptions always serialize");
+            [&[DESCRIPTION_BYTE], &body[..], b"\r\n"].concat()
+        }
+    }
+}
+
+/// The strings a [`Command::List`] sends for `notes`: an id, age and body per note.
+fn summary_fields(notes: &[NoteSummary]) -> impl Iterator<Item = String> + '_ {
+    notes.iter().flat_map(|note| {
+        [
+            note.id.to_string(),
+            note.age_secs.to_string(),
+            note.body.clone(),
+        ]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::tests::all_commands;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::duplex;
+    use tokio_util::codec::Framed;
+
+    #[tokio::test]
+    async fn framed_round_trip_every_command() -> Result<()> {
+        for format in [
+            WireFormat::Binary,
+            WireFormat::Json,
+            WireFormat::LengthPrefixed,
+        ] {
+            for compression in [Compression::None, Compression::Lz4] {
+                let codec = FrameCodec {
+                    format,
+                    compression,
+                    max_frame_size: None,
+                };
+                let (a, b) = duplex(64 * 1024);
+                let mut sender = Framed::new(a, codec);
+                let mut receiver = Framed::new(b, codec);
+                for command in all_commands() {
+                    let frame = Frame(command);
+                    sender.send(frame.clone()).await?;
+                    let received = receiver.next().await.expect("stream ended")??;
+                    assert_eq!(received, frame, "{format:?}/{compression}");
+                }
+                drop(sender);
+                assert!(receiver.next().await.is_none());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn length_prefixed_bodies_may_hold_line_endings_and_nul() -> Result<()> {
+        let bodies = ["line one\r\nline two", "nul\0inside", "\r\n", ""];
+        let mut commands: Vec<_> = bodies
+            .iter()
+            .map(|body| Command::Create(body.to_string()))
+            .collect();
+        commands.push(Command::CompareAndUpdate {
+            id: 3,
+            expected_body: "old\r\n".to_string(),
+            new_body: "new\0\r\n".to_string(),
+        });
+        let notes = vec![NoteSummary {
+            id: 4,
+            age_secs: 1,
+            body: "listed\r\nnote\0".to_string(),
+        }];
+        commands.extend([
+            Command::List(notes.clone()),
+            Command::ListPageResult { notes, more: true },
+            Command::Note(4, "read\r\nback".to_string()),
+            Command::Notify {
+                seq: 2,
+                id: 4,
+                body: "pushed\r\n".to_string(),
+            },
+            Command::Page {
+                notes: vec!["paged\r\n".to_string()],
+                cursor: Some("4".to_string()),
+            },
+        ]);
+
+        let mut sender = FrameCodec::new(WireFormat::LengthPrefixed);
+        let mut src = BytesMut::new();
+        for command in &commands {
+            sender.encode(&Frame(command.clone()), &mut src)?;
+        }
+        // A plain binary peer reads them too.
+        let mut receiver = FrameCodec::new(WireFormat::Binary);
+        for command in commands {
+            assert_eq!(receiver.decode(&mut src)?, Some(Ok(Frame(command))));
+        }
+        assert!(src.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn length_prefixed_frame_waits_for_its_whole_body() -> Result<()> {
+        let mut codec = FrameCodec::new(WireFormat::LengthPrefixed);
+        let mut encoded = BytesMut::new();
+        codec.encode(&Frame(Command::Create("a\r\nb".to_string())), &mut encoded)?;
+        let mut src = BytesMut::from(&encoded[..encoded.len() - 1]);
+        assert_eq!(codec.decode(&mut src)?, None);
+        src.extend_from_slice(&encoded[encoded.len() - 1..]);
+        assert_eq!(
+            codec.decode(&mut src)?,
+            Some(Ok(Frame(Command::Create("a\r\nb".to_string()))))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn frames_over_the_size_limit_are_rejected() -> Result<()> {
+        // Random enough that lz4 can't shrink it under the limit.
+        let mut seed = 1u32;
+        let noise = (0..200)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                char::from(b'a' + (seed >> 16) as u8 % 26)
+            })
+            .collect();
+        let oversized = Frame(Command::List(vec![NoteSummary {
+            body: noise,
+            ..Default::default()
+        }]));
+        for format in [WireFormat::Binary, WireFormat::Json] {
+            for compression in [Compression::None, Compression::Lz4] {
+                let mut codec = FrameCodec {
+                    format,
+                    compression,
+                    max_frame_size: None,
+                };
+                let mut encoded = BytesMut::new();
+                codec.encode(&Frame(Command::Read), &mut encoded)?;
+                codec.encode(&oversized, &mut encoded)?;
+
+                codec.max_frame_size = Some(64);
+                assert_eq!(codec.decode(&mut encoded)?, Some(Ok(Frame(Command::Read))));
+                // Before the frame is complete, too.
+                let mut partial = BytesMut::from(&encoded[..encoded.len() - 2]);
+                for src in [&mut partial, &mut encoded] {
+                    let err = codec.decode(src).unwrap_err();
+                    assert!(
+                        matches!(err.downcast_ref(), Some(Error::FrameTooLarge { limit: 64 })),
+                        "{format:?}/{compression}: {err}"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_frames_declaring_a_huge_size_are_rejected() {
+        let mut codec = FrameCodec {
+            format: WireFormat::Binary,
+            compression: Compression::Lz4,
+            max_frame_size: Some(64),
+        };
+        // A tiny block claiming to decompress to 1 GiB.
+        let block = [&(1u32 << 30).to_le_bytes()[..], b"\x10$"].concat();
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&(block.len() as u32).to_be_bytes());
+        src.extend_from_slice(&block);
+        let err = codec.decode(&mut src).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(Error::FrameTooLarge { limit: 64 })),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn garbage_is_skipped_up_to_the_next_frame() -> Result<()> {
+        let mut codec = FrameCodec::default();
+        let mut src = BytesMut::from(&b"\x00\xffxyz$`seven\r\n$"[..]);
+        let Some(Err(Error::MalformedFrame(message))) = codec.decode(&mut src)? else {
+            panic!("expected a malformed frame");
+        };
+        assert!(message.contains("skipped 5 bytes"), "{message}");
+        assert_eq!(codec.decode(&mut src)?, Some(Ok(Frame(Command::Read))));
+        // A frame that checks out but doesn't parse is consumed whole.
+        assert!(matches!(
+            codec.decode(&mut src)?,
+            Some(Err(Error::MalformedFrame(_)))
+        ));
+        assert_eq!(codec.decode(&mut src)?, Some(Ok(Frame(Command::Read))));
+        assert!(src.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn decode_eof_with_partial_frame_is_unexpected_eof() {
+        let mut codec = FrameCodec::default();
+        let mut src = BytesMut::from(&b"+half"[..]);
+        let err = codec.decode_eof(&mut src).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::UnexpectedEof { buffered: 5 })
+        ));
+    }
+}
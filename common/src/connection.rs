@@ -0,0 +1,360 @@
+use color_eyre::eyre::Result;
+use futures::{SinkExt, StreamExt};
+use std::{fmt, io};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    net::TcpStream,
+};
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+use crate::{codec::FrameCodec, protocol::*, Error};
+
+/// A byte stream a [`Connection`] can run over, so plaintext and TLS sockets
+/// can sit behind one type as `Connection<Box<dyn Stream>>`.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + fmt::Debug> Stream for S {}
+
+/// A framed, bidirectional connection to a peer.
+#[derive(Debug)]
+pub struct Connection<S = TcpStream> {
+    reader: ConnectionReader<ReadHalf<S>>,
+    writer: ConnectionWriter<WriteHalf<S>>,
+}
+
+impl<S: AsyncRead + AsyncWrite> Connection<S> {
+    pub fn new(stream: S) -> Self {
+        Self::with_format(stream, WireFormat::Binary)
+    }
+
+    pub fn with_format(stream: S, format: WireFormat) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        Self {
+            reader: ConnectionReader::new(reader, format),
+            writer: ConnectionWriter::new(writer, format),
+        }
+    }
+
+    /// Split into halves that can be driven from separate tasks, e.g. to push
+    /// frames to the peer while waiting for its next command.
+    pub fn split(
+        self,
+    ) -> (
+        ConnectionReader<ReadHalf<S>>,
+        ConnectionWriter<WriteHalf<S>>,
+    ) {
+        (self.reader, self.writer)
+    }
+
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        self.reader.read_frame().await
+    }
+
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.writer.write_frame(frame).await
+    }
+
+    pub fn feed_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.writer.feed_frame(frame)
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        self.writer.flush().await
+    }
+
+    pub fn parse_frame(&mut self) -> Result<Option<Frame>> {
+        self.reader.parse_frame()
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.reader.frames.decoder().compression
+    }
+
+    /// Switch the codec used for every subsequent frame in both directions.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.reader.set_compression(compression);
+        self.writer.set_compression(compression);
+    }
+
+    /// Fail reads of frames from the peer larger than `max` bytes.
+    pub fn set_max_frame_size(&mut self, max: Option<usize>) {
+        self.reader.set_max_frame_size(max);
+    }
+}
+
+/// Read half of a [`Connection`]; owns the receive buffer.
+#[derive(Debug)]
+pub struct ConnectionReader<R> {
+    frames: FramedRead<R, FrameCodec>,
+}
+
+impl<R: AsyncRead + Unpin> ConnectionReader<R> {
+    fn new(stream: R, format: WireFormat) -> Self {
+        Self {
+            frames: FramedRead::new(stream, FrameCodec::new(format)),
+        }
+    }
+
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.frames.decoder_mut().compression = compression;
+    }
+
+    pub fn set_max_frame_size(&mut self, max: Option<usize>) {
+        self.frames.decoder_mut().max_frame_size = max;
+    }
+
+    /// Read the next frame, or `None` once the peer closes the connection.
+    /// A malformed frame is an [`Error::MalformedFrame`], after which reading
+    /// can carry on; other errors end the connection.
+    pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        match self.frames.next().await.transpose() {
+            Err(e)
+                if e.downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::ConnectionReset) =>
+            {
+                Err(Error::ConnectionReset.into())
+            }
+            result => Ok(result?.transpose()?),
+        }
+    }
+
+    /// Take a frame that has already been received, without reading more.
+    pub fn parse_frame(&mut self) -> Result<Option<Frame>> {
+        let mut codec = *self.frames.decoder();
+        Ok(codec.decode(self.frames.read_buffer_mut())?.transpose()?)
+    }
+}
+
+/// Write half of a [`Connection`]; owns the send buffer.
+///
+/// Frames queued with [`feed_frame`](Self::feed_frame) only reach the peer
+/// once [`flush`](Self::flush)ed. Dropping the writer with unflushed bytes
+/// loses them, which debug builds catch with an assertion unless a flush
+/// already failed.
+#[derive(Debug)]
+pub struct ConnectionWriter<W> {
+    frames: FramedWrite<W, FrameCodec>,
+    /// Set once a flush fails, after which unsent bytes are expected.
+    failed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> ConnectionWriter<W> {
+    fn new(stream: W, format: WireFormat) -> Self {
+        Self {
+            frames: FramedWrite::new(stream, FrameCodec::new(format)),
+            failed: false,
+        }
+    }
+
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.frames.encoder_mut().compression = compression;
+    }
+
+    /// Queue a frame and send everything buffered so far.
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.feed_frame(frame)?;
+        self.flush().await
+    }
+
+    /// Queue a frame without sending it, so several frames can go out in one write.
+    pub fn feed_frame(&mut self, frame: &Frame) -> Result<()> {
+        let mut codec = *self.frames.encoder();
+        codec.encode(frame, self.frames.write_buffer_mut())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        let flushed = SinkExt::<Frame>::flush(&mut self.frames).await;
+        self.failed |= flushed.is_err();
+        flushed
+    }
+}
+
+impl<W> Drop for ConnectionWriter<W> {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            let unflushed = self.frames.write_buffer().len();
+            debug_assert!(
+                unflushed == 0 || self.failed,
+                "connection dropped with {unflushed} unflushed bytes"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codec::encode_frame, NoteID};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    fn connection_pair() -> (Connection<DuplexStream>, Connection<DuplexStream>) {
+        let (server, client) = duplex(64 * 1024);
+        (Connection::new(server), Connection::new(client))
+    }
+
+    #[tokio::test]
+    async fn read_frame_clean_eof() -> Result<()> {
+        let (mut server, client) = connection_pair();
+        drop(client);
+        assert!(server.read_frame().await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_frame_partial_frame_then_close() -> Result<()> {
+        let (socket, mut raw) = duplex(1024);
+        let mut connection = Connection::new(socket);
+
+        raw.write_all(b"+half a no").await?;
+        drop(raw);
+        let err = connection.read_frame().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::UnexpectedEof { buffered: 10 })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hello_negotiates_lz4() -> Result<()> {
+        let (mut server, mut client) = connection_pair();
+        let hello = Command::Hello {
+            version: PROTOCOL_VERSION,
+            compression: Compression::Lz4,
+        };
+        client.write_frame(&hello.into()).await?;
+        let Some(Frame(Command::Hello { compression, .. })) = server.read_frame().await? else {
+            panic!("expected hello");
+        };
+        assert_eq!(compression, Compression::Lz4);
+        server.set_compression(compression);
+        client.set_compression(compression);
+
+        let body = "compress me ".repeat(100);
+        client
+            .write_frame(&Command::Create(body.clone()).into())
+            .await?;
+        let Some(Frame(Command::Create(received))) = server.read_frame().await? else {
+            panic!("expected create");
+        };
+        assert_eq!(received, body);
+
+        let notes = [body.clone(), "second".to_string()]
+            .into_iter()
+            .enumerate()
+            .map(|(id, body)| NoteSummary {
+                id: id as NoteID,
+                age_secs: 0,
+                body,
+            })
+            .collect::<Vec<_>>();
+        server
+            .write_frame(&Command::List(notes.clone()).into())
+            .await?;
+        let Some(Frame(Command::List(received))) = client.read_frame().await? else {
+            panic!("expected list");
+        };
+        assert_eq!(received, notes);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn json_connection_round_trip() -> Result<()> {
+        let (server, client) = duplex(1024);
+        let mut server = Connection::with_format(server, WireFormat::Json);
+        let mut client = Connection::with_format(client, WireFormat::Json);
+
+        client
+            .write_frame(&Command::Create("line one".to_string()).into())
+            .await?;
+        client.write_frame(&Command::Read.into()).await?;
+        let Some(Frame(Command::Create(body))) = server.read_frame().await? else {
+            panic!("expected create");
+        };
+        assert_eq!(body, "line one");
+        assert!(matches!(
+            server.read_frame().await?,
+            Some(Frame(Command::Read))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lz4_frames_are_compressed_on_the_wire() -> Result<()> {
+        let (socket, mut raw) = duplex(64 * 1024);
+        let mut connection = Connection::new(socket);
+        connection.set_compression(Compression::Lz4);
+
+        let frame = Command::List(vec![NoteSummary {
+            body: "a".repeat(1000),
+            ..Default::default()
+        }])
+        .into();
+        let plain = encode_frame(&frame);
+        connection.write_frame(&frame).await?;
+        drop(connection);
+
+        let mut wire = Vec::new();
+        raw.read_to_end(&mut wire).await?;
+        assert!(wire.len() < plain.len());
+        assert_eq!(lz4_flex::decompress_size_prepended(&wire[4..])?, plain);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn split_halves_work_concurrently() -> Result<()> {
+        let (mut peer, connection) = connection_pair();
+        let (mut reader, mut writer) = connection.split();
+
+        let pusher = tokio::spawn(async move {
+            for i in 0..3 {
+                let frame = Command::Create(format!("pushed {i}")).into();
+                writer.write_frame(&frame).await?;
+            }
+            Ok::<_, color_eyre::Report>(writer)
+        });
+        for i in 0..3 {
+            peer.write_frame(&Command::Swap(i, i + 1).into()).await?;
+        }
+        for i in 0..3 {
+            let Some(Frame(Command::Swap(a, _))) = reader.read_frame().await? else {
+                panic!("expected swap");
+            };
+            assert_eq!(a, i);
+        }
+        for i in 0..3 {
+            let Some(Frame(Command::Create(body))) = peer.read_frame().await? else {
+                panic!("expected create");
+            };
+            assert_eq!(body, format!("pushed {i}"));
+        }
+        pusher.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fed_frames_are_sent_on_flush() -> Result<()> {
+        let (mut peer, mut connection) = connection_pair();
+        connection.feed_frame(&Command::Read.into())?;
+        connection.feed_frame(&Command::Quit.into())?;
+        connection.flush().await?;
+        assert!(matches!(
+            peer.read_frame().await?,
+            Some(Frame(Command::Read))
+        ));
+        assert!(matches!(
+            peer.read_frame().await?,
+            Some(Frame(Command::Quit))
+        ));
+        Ok(())
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    #[should_panic(expected = "unflushed bytes")]
+    async fn dropping_unflushed_connection_asserts() {
+        let (_peer, mut connection) = connection_pair();
+        connection.feed_frame(&Command::Read.into()).unwrap();
+        drop(connection);
+    }
+}
@@ -1,138 +1,276 @@
-use bytes::{Buf, BytesMut};
-use color_eyre::eyre::{anyhow, Result};
-use protocol::*;
-use std::io::Cursor;
-use thiserror::Error;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    time::{Duration, Instant},
+use bytes::Buf;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Cursor,
+    time::SystemTime,
 };
+use thiserror::Error;
+use tokio::time::Duration;
+pub mod codec;
+pub mod connection;
 pub mod protocol;
 
+pub use codec::FrameCodec;
+pub use connection::{Connection, ConnectionReader, ConnectionWriter, Stream};
+
 pub type NoteID = u64;
 pub type ClientID = u64;
-#[derive(Debug, Clone)]
+/// A stored note. Times are wall-clock, so a serialized note keeps its age
+/// across restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Note {
     id: NoteID,
     body: String,
-    pub created_at: Instant,
+    /// Hex-encoded blake3 digest of `body`, kept in step by [`set_body`](Self::set_body).
+    hash: String,
+    tags: BTreeSet<String>,
+    /// Free-form key-value pairs such as `priority=3`, set when the note is created.
+    metadata: BTreeMap<String, String>,
+    /// Client that created the note, if it came in over a connection.
+    owner: Option<ClientID>,
+    /// How long the note lives, if not the server's note timeout.
+    #[serde(default)]
+    ttl: Option<Duration>,
+    pub created_at: SystemTime,
+    /// When the note's lifetime last began: its creation or latest [`touch`](Self::touch).
+    refreshed_at: SystemTime,
+    /// When the note was last read, for servers that expire idle notes.
+    last_accessed: SystemTime,
+    /// Bumped by every [`touch`](Self::touch) and [`mark_accessed`](Self::mark_accessed),
+    /// so stale expiry schedules can be told apart.
+    generation: u64,
 }
 impl Note {
     pub fn new(id: NoteID, body: String) -> Self {
+        let created_at = SystemTime::now();
         Self {
             id,
+            hash: content_hash(&body),
             body,
-            created_at: Instant::now(),
+            tags: BTreeSet::new(),
+            metadata: BTreeMap::new(),
+            owner: None,
+            ttl: None,
+            created_at,
+            refreshed_at: created_at,
+            last_accessed: created_at,
+            generation: 0,
         }
     }
+    /// Start a [`NoteBuilder`] for a note with id 0, an empty body and no tags,
+    /// created now.
+    pub fn builder() -> NoteBuilder {
+        NoteBuilder::default()
+    }
+    pub fn with_tags<T: Into<String>>(mut self, tags: impl IntoIterator<Item = T>) -> Self {
+        self.tags.extend(tags.into_iter().map(Into::into));
+        self
+    }
+    pub fn with_metadata(mut self, metadata: BTreeMap<String, String>) -> Self {
+        self.metadata.extend(metadata);
+        self
+    }
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+    pub fn with_owner(mut self, owner: Option<ClientID>) -> Self {
+        self.owner = owner;
+        self
+    }
+    pub fn owner(&self) -> Option<ClientID> {
+        self.owner
+    }
+    pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
     pub fn id(&self) -> NoteID {
         self.id
     }
+    /// Restart the note's lifetime from now. Its creation time stays as it was.
+    pub fn touch(&mut self) -> u64 {
+        self.refreshed_at = SystemTime::now();
+        self.last_accessed = self.refreshed_at;
+        self.generation += 1;
+        self.generation
+    }
+    /// Record a read of the note, restarting its idle time from now.
+    pub fn mark_accessed(&mut self) -> u64 {
+        self.last_accessed = SystemTime::now();
+        self.generation += 1;
+        self.generation
+    }
+    pub fn refreshed_at(&self) -> SystemTime {
+        self.refreshed_at
+    }
+    pub fn last_accessed(&self) -> SystemTime {
+        self.last_accessed
+    }
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    /// Time since the note was created, or zero if the clock has since been
+    /// set back before that.
     pub fn elapsed(&self) -> Duration {
-        self.created_at.elapsed()
+        self.created_at.elapsed().unwrap_or_default()
     }
     pub fn body(&self) -> &str {
         &self.body
     }
-}
-
-pub const NOTE_TIMEOUT: Duration = Duration::from_secs(60);
-pub const DEFAULT_PORT: &str = "7536";
-pub const DEFAULT_ADDRESS: &str = "127.0.0.1";
-pub const WS_URL: &str = "127.0.0.1:7536";
-
-#[derive(Debug)]
-pub struct Connection {
-    stream: TcpStream,
-    buffer: BytesMut,
-}
-
-impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
-        Self {
-            stream,
-            buffer: BytesMut::with_capacity(1024),
-        }
+    pub fn hash(&self) -> &str {
+        &self.hash
     }
-
-    pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
-        loop {
-            if let Some(frame) = self.parse_frame()? {
-                return Ok(Some(frame));
+    /// Replace the body, returning the previous one.
+    pub fn set_body(&mut self, body: String) -> String {
+        self.hash = content_hash(&body);
+        std::mem::replace(&mut self.body, body)
+    }
+    pub fn tags(&self) -> &BTreeSet<String> {
+        &self.tags
+    }
+    /// Check the note against every predicate set on `query`.
+    pub fn matches(&self, query: &NoteQuery) -> bool {
+        if let Some(ref needle) = query.contains {
+            if !self.body.to_lowercase().contains(&needle.to_lowercase()) {
+                return false;
             }
-            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
-            if 0 == bytes_read {
-                if self.buffer.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err(anyhow!("connection reset by peer"));
-                };
+        }
+        if let Some(ref tag) = query.tag {
+            if !self.tags.contains(tag) {
+                return false;
             }
         }
+        let age = self.elapsed();
+        if query.max_age.is_some_and(|max_age| age > max_age) {
+            return false;
+        }
+        if query.min_age.is_some_and(|min_age| age < min_age) {
+            return false;
+        }
+        true
     }
+}
 
-    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
-        match frame.0 {
-            Command::Create(ref body) => {
-                let command = &[CREATE_BYTE];
-                let body = body.as_bytes();
-                self.stream.write_all(&[command, body].concat()).await?
-            }
-            Command::List(ref notes) => {
-                let msg = notes.iter().fold(String::new(), |f, note| {
-                    f + note.len().to_string().as_str() + "#" + note
-                });
-                let frame_arg = format!("{msg}\r\n");
-                let body = frame_arg.as_bytes();
-                let command = &[LIST_BYTE];
-                self.stream.write_all(&[command, body].concat()).await?
-            }
-            Command::Read => self.stream.write_all(&[READ_BYTE]).await?,
-            Command::Quit => self.stream.write_all(&[QUIT_BYTE]).await?,
-            Command::Disconnect(id) => {
-                let command = &[DISCONNECT_BYTE];
-                let body = id.to_string();
-                let body = body.as_bytes();
-                let sep = b"\r\n";
-                self.stream
-                    .write_all(&[command, body, sep].concat())
-                    .await?
-            }
-            Command::Id(id) => {
-                let command = &[ID_BYTE];
-                let body = id.to_string();
-                let body = body.as_bytes();
-                let sep = b"\r\n";
-                self.stream
-                    .write_all(&[command, body, sep].concat())
-                    .await?
-            }
-        }
-        Ok(())
+impl Default for Note {
+    fn default() -> Self {
+        Note::builder().build()
     }
+}
 
-    pub fn parse_frame(&mut self) -> Result<Option<Frame>> {
-        let mut buf = Cursor::new(&self.buffer[..]);
+/// Fluent construction of a [`Note`], mainly for fixtures and imports.
+#[derive(Debug, Clone, Default)]
+pub struct NoteBuilder {
+    id: NoteID,
+    body: String,
+    tags: BTreeSet<String>,
+    metadata: BTreeMap<String, String>,
+    owner: Option<ClientID>,
+    ttl: Option<Duration>,
+    created_at: Option<SystemTime>,
+    refreshed_at: Option<SystemTime>,
+    last_accessed: Option<SystemTime>,
+    generation: u64,
+}
 
-        match Frame::check(&mut buf) {
-            Ok(_) => {
-                let len = buf.position() as usize;
-                buf.set_position(0);
-                let frame = Frame::parse(&mut buf)?;
-                self.buffer.advance(len);
-                Ok(Some(frame))
-            }
-            Err(FrameParseError::Incomplete) => Ok(None),
-            Err(e) => Err(e.into()),
+impl NoteBuilder {
+    pub fn id(mut self, id: NoteID) -> Self {
+        self.id = id;
+        self
+    }
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.body = body.into();
+        self
+    }
+    pub fn tag(mut self, tag: impl Into<String>) -> Self {
+        self.tags.insert(tag.into());
+        self
+    }
+    pub fn meta(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+    pub fn owner(mut self, owner: ClientID) -> Self {
+        self.owner = Some(owner);
+        self
+    }
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+    pub fn created_at(mut self, created_at: SystemTime) -> Self {
+        self.created_at = Some(created_at);
+        self
+    }
+    /// Defaults to the creation time.
+    pub fn refreshed_at(mut self, refreshed_at: SystemTime) -> Self {
+        self.refreshed_at = Some(refreshed_at);
+        self
+    }
+    /// Defaults to the creation time.
+    pub fn last_accessed(mut self, last_accessed: SystemTime) -> Self {
+        self.last_accessed = Some(last_accessed);
+        self
+    }
+    /// Carry over the [`Note::generation`] of a note read back from storage.
+    pub fn generation(mut self, generation: u64) -> Self {
+        self.generation = generation;
+        self
+    }
+    pub fn build(self) -> Note {
+        let created_at = self.created_at.unwrap_or_else(SystemTime::now);
+        Note {
+            id: self.id,
+            hash: content_hash(&self.body),
+            body: self.body,
+            tags: self.tags,
+            metadata: self.metadata,
+            owner: self.owner,
+            ttl: self.ttl,
+            created_at,
+            refreshed_at: self.refreshed_at.unwrap_or(created_at),
+            last_accessed: self.last_accessed.unwrap_or(created_at),
+            generation: self.generation,
         }
     }
 }
 
+/// Hex-encoded blake3 digest of a note body, as returned by [`Note::hash`].
+pub fn content_hash(body: &str) -> String {
+    blake3::hash(body.as_bytes()).to_hex().to_string()
+}
+
+/// Filter for notes. Predicates left as `None` match every note.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NoteQuery {
+    /// Case-insensitive substring of the body.
+    pub contains: Option<String>,
+    /// Exact tag the note must carry.
+    pub tag: Option<String>,
+    /// Only notes created at most this long ago.
+    pub max_age: Option<Duration>,
+    /// Only notes created at least this long ago.
+    pub min_age: Option<Duration>,
+}
+
+pub const NOTE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest note body, in bytes, a server accepts unless configured otherwise.
+pub const MAX_NOTE_SIZE: usize = 64 * 1024;
+pub const DEFAULT_PORT: &str = "7536";
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1";
+pub const WS_URL: &str = "127.0.0.1:7536";
+
 /// Find a line
 fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], FrameParseError> {
     // Scan the bytes directly
     let start = src.position() as usize;
+    // A line needs at least its `\r\n`; this also keeps `end` from underflowing.
+    if src.get_ref().len().saturating_sub(start) < 2 {
+        return Err(FrameParseError::Incomplete);
+    }
     // Scan to the second to last byte
     let end = src.get_ref().len() - 1;
 
@@ -157,6 +295,50 @@ fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, FrameParseError> {
     Ok(src.get_u8())
 }
 
+fn get_u64(src: &mut Cursor<&[u8]>) -> Result<u64, FrameParseError> {
+    if src.remaining() < 8 {
+        return Err(FrameParseError::Incomplete);
+    }
+
+    Ok(src.get_u64())
+}
+
+/// Read a 4-byte big-endian length and that many bytes after it.
+fn get_sized<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], FrameParseError> {
+    if src.remaining() < 4 {
+        return Err(FrameParseError::Incomplete);
+    }
+    let len = src.get_u32() as usize;
+    if src.remaining() < len {
+        return Err(FrameParseError::Incomplete);
+    }
+    let start = src.position() as usize;
+    src.advance(len);
+    Ok(&src.get_ref()[start..start + len])
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The peer closed its end cleanly while a frame was only partially received.
+    #[error("connection closed mid-frame with {buffered} bytes buffered")]
+    UnexpectedEof { buffered: usize },
+    #[error("connection reset by peer")]
+    ConnectionReset,
+    /// The server a handler belongs to has been closed.
+    #[error("server is closed")]
+    ServerClosed,
+    /// The peer sent a frame bigger than the connection accepts.
+    #[error("frame exceeds the {limit} byte limit")]
+    FrameTooLarge { limit: usize },
+    /// The peer sent a frame that could not be parsed. It was skipped, so
+    /// later frames can still be read.
+    #[error("malformed frame: {0}")]
+    MalformedFrame(String),
+    /// The server answered a request with a [`Command::Error`](protocol::Command::Error).
+    #[error("{0}")]
+    Rejected(String),
+}
+
 #[derive(Error, Debug)]
 pub enum FrameParseError {
     #[error("incomplete frame")]
@@ -164,3 +346,174 @@ pub enum FrameParseError {
     #[error("invalid frame start byte: {0:?}")]
     Invalid(u8),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_line_needs_at_least_a_line_ending() {
+        for buf in [&b""[..], b"\r", b"+"] {
+            let mut src = Cursor::new(buf);
+            assert!(matches!(
+                get_line(&mut src),
+                Err(FrameParseError::Incomplete)
+            ));
+        }
+        let mut src = Cursor::new(&b"+\r\n"[..]);
+        src.set_position(1);
+        assert_eq!(get_line(&mut src).unwrap(), b"");
+        assert!(matches!(
+            get_line(&mut src),
+            Err(FrameParseError::Incomplete)
+        ));
+    }
+
+    fn aged_note(body: &str, age: Duration) -> Note {
+        Note::builder()
+            .body(body)
+            .created_at(SystemTime::now() - age)
+            .build()
+    }
+
+    #[test]
+    fn builder_defaults_and_fields() {
+        let before = SystemTime::now();
+        let note = Note::default();
+        assert_eq!(note.id(), 0);
+        assert_eq!(note.body(), "");
+        assert!(note.tags().is_empty());
+        assert_eq!(note.owner(), None);
+        assert!(note.created_at >= before && note.created_at <= SystemTime::now());
+
+        let created_at = SystemTime::now() - Duration::from_secs(5);
+        let note = Note::builder()
+            .id(3)
+            .body("buy milk")
+            .tag("errand")
+            .meta("source", "email")
+            .owner(7)
+            .created_at(created_at)
+            .build();
+        assert_eq!(note.id(), 3);
+        assert_eq!(note.body(), "buy milk");
+        assert!(note.tags().contains("errand"));
+        assert_eq!(note.metadata()["source"], "email");
+        assert_eq!(note.owner(), Some(7));
+        assert_eq!(note.created_at, created_at);
+    }
+
+    #[test]
+    fn elapsed_only_grows() {
+        let note = Note::new(0, "body".to_string());
+        let mut previous = note.elapsed();
+        for _ in 0..100 {
+            let elapsed = note.elapsed();
+            assert!(elapsed >= previous);
+            previous = elapsed;
+        }
+    }
+
+    #[test]
+    fn serialized_note_keeps_its_age() {
+        let note = aged_note("body", Duration::from_secs(60)).with_tags(["work"]);
+        let restored: Note = serde_json::from_str(&serde_json::to_string(&note).unwrap()).unwrap();
+        assert_eq!(restored.created_at, note.created_at);
+        assert!(restored.elapsed() >= Duration::from_secs(60));
+        assert_eq!(restored.body(), "body");
+        assert_eq!(restored.hash(), note.hash());
+        assert!(restored.tags().contains("work"));
+    }
+
+    #[test]
+    fn touch_keeps_the_creation_time() {
+        let mut note = aged_note("body", Duration::from_secs(60));
+        let created_at = note.created_at;
+        let generation = note.touch();
+        assert_eq!(note.generation(), generation);
+        assert_eq!(note.created_at, created_at);
+        assert!(note.refreshed_at() > created_at);
+        assert_eq!(note.last_accessed(), note.refreshed_at());
+        assert!(note.elapsed() >= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn hash_follows_body() {
+        let mut note = Note::new(0, "buy milk".to_string());
+        assert_eq!(note.hash(), Note::new(1, "buy milk".to_string()).hash());
+        assert_eq!(note.hash().len(), 64);
+        note.set_body("buy bread".to_string());
+        assert_eq!(note.hash(), content_hash("buy bread"));
+        assert_ne!(note.hash(), content_hash("buy milk"));
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        let note = Note::new(0, "anything".to_string());
+        assert!(note.matches(&NoteQuery::default()));
+    }
+
+    #[test]
+    fn query_contains_is_case_insensitive() {
+        let note = Note::new(0, "Buy Milk".to_string());
+        let query = |s: &str| NoteQuery {
+            contains: Some(s.to_string()),
+            ..Default::default()
+        };
+        assert!(note.matches(&query("milk")));
+        assert!(note.matches(&query("BUY")));
+        assert!(note.matches(&query("")));
+        assert!(!note.matches(&query("bread")));
+    }
+
+    #[test]
+    fn query_tag() {
+        let note = Note::new(0, "body".to_string()).with_tags(["work", "urgent"]);
+        let query = |s: &str| NoteQuery {
+            tag: Some(s.to_string()),
+            ..Default::default()
+        };
+        assert!(note.matches(&query("work")));
+        assert!(!note.matches(&query("home")));
+        assert!(!note.matches(&query("Work")));
+    }
+
+    #[test]
+    fn query_age() {
+        let note = aged_note("body", Duration::from_secs(10));
+        let max_age = |secs| NoteQuery {
+            max_age: Some(Duration::from_secs(secs)),
+            ..Default::default()
+        };
+        let min_age = |secs| NoteQuery {
+            min_age: Some(Duration::from_secs(secs)),
+            ..Default::default()
+        };
+        assert!(note.matches(&max_age(20)));
+        assert!(!note.matches(&max_age(5)));
+        assert!(note.matches(&min_age(5)));
+        assert!(!note.matches(&min_age(20)));
+    }
+
+    #[test]
+    fn query_combined_predicates() {
+        let note = aged_note("Deploy the server", Duration::from_secs(10)).with_tags(["ops"]);
+        let query = NoteQuery {
+            contains: Some("deploy".to_string()),
+            tag: Some("ops".to_string()),
+            max_age: Some(Duration::from_secs(30)),
+            min_age: Some(Duration::from_secs(5)),
+        };
+        assert!(note.matches(&query));
+        let wrong_tag = NoteQuery {
+            tag: Some("dev".to_string()),
+            ..query.clone()
+        };
+        assert!(!note.matches(&wrong_tag));
+        let too_young = NoteQuery {
+            min_age: Some(Duration::from_secs(60)),
+            ..query
+        };
+        assert!(!note.matches(&too_young));
+    }
+}
@@ -1,7 +1,17 @@
 use color_eyre::eyre::{anyhow, Result};
-use std::io::Cursor;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::Cursor,
+    str::FromStr,
+    time::{Duration, UNIX_EPOCH},
+};
+use thiserror::Error;
 
-use crate::{get_line, get_u8, ClientID, FrameParseError};
+use crate::{
+    get_line, get_sized, get_u64, get_u8, ClientID, FrameParseError, Note, NoteID, NoteQuery,
+};
 
 pub const CREATE_BYTE: u8 = b'+';
 pub const CREATE_COMMAND: &str = "CREATE";
@@ -15,13 +25,563 @@ pub const DISCONNECT_BYTE: u8 = b'!';
 pub const DISCONNECT_COMMAND: &str = "DISCONNECT";
 pub const ID_BYTE: u8 = b'#';
 pub const ID_COMMAND: &str = "ID";
+pub const HELLO_BYTE: u8 = b'@';
+pub const HELLO_COMMAND: &str = "HELLO";
+pub const SWAP_BYTE: u8 = b'~';
+pub const SWAP_COMMAND: &str = "SWAP";
+pub const ERROR_BYTE: u8 = b'?';
+pub const ERROR_COMMAND: &str = "ERROR";
+pub const CREATE_FROM_TEMPLATE_BYTE: u8 = b'*';
+pub const CREATE_FROM_TEMPLATE_COMMAND: &str = "CREATE_FROM_TEMPLATE";
+pub const STATS_BYTE: u8 = b'=';
+pub const STATS_COMMAND: &str = "STATS";
+pub const STATS_RESULT_BYTE: u8 = b'^';
+pub const STATS_RESULT_COMMAND: &str = "STATS_RESULT";
+pub const DESCRIBE_BYTE: u8 = b'&';
+pub const DESCRIBE_COMMAND: &str = "DESCRIBE";
+pub const DESCRIPTION_BYTE: u8 = b'|';
+pub const DESCRIPTION_COMMAND: &str = "DESCRIPTION";
+pub const READ_PAGE_BYTE: u8 = b'<';
+pub const READ_PAGE_COMMAND: &str = "READ_PAGE";
+pub const PAGE_BYTE: u8 = b'>';
+pub const PAGE_COMMAND: &str = "PAGE";
+pub const EXPIRING_BYTE: u8 = b'.';
+pub const EXPIRING_COMMAND: &str = "EXPIRING";
+pub const CREATE_WITH_METADATA_BYTE: u8 = b':';
+pub const CREATE_WITH_METADATA_COMMAND: &str = "CREATE_WITH_METADATA";
+pub const PING_BYTE: u8 = b'(';
+pub const PING_COMMAND: &str = "PING";
+pub const PONG_BYTE: u8 = b')';
+pub const PONG_COMMAND: &str = "PONG";
+pub const SUBSCRIBE_BYTE: u8 = b'/';
+pub const SUBSCRIBE_COMMAND: &str = "SUBSCRIBE";
+pub const UNSUBSCRIBE_BYTE: u8 = b'U';
+pub const UNSUBSCRIBE_COMMAND: &str = "UNSUBSCRIBE";
+pub const CONFIG_BYTE: u8 = b'C';
+pub const CONFIG_COMMAND: &str = "CONFIG";
+pub const CONFIG_RESULT_BYTE: u8 = b'c';
+pub const CONFIG_RESULT_COMMAND: &str = "CONFIG_RESULT";
+pub const NOTIFY_BYTE: u8 = b'\\';
+pub const NOTIFY_COMMAND: &str = "NOTIFY";
+pub const INCREMENT_BYTE: u8 = b'[';
+pub const INCREMENT_COMMAND: &str = "INCREMENT";
+pub const COUNTER_BYTE: u8 = b']';
+pub const COUNTER_COMMAND: &str = "COUNTER";
+pub const GET_BY_HASH_BYTE: u8 = b'{';
+pub const GET_BY_HASH_COMMAND: &str = "GET_BY_HASH";
+pub const GET_MANY_BYTE: u8 = b'}';
+pub const GET_MANY_COMMAND: &str = "GET_MANY";
+pub const BATCH_BYTE: u8 = b';';
+pub const BATCH_COMMAND: &str = "BATCH";
+pub const CLOSING_BYTE: u8 = b'_';
+pub const CLOSING_COMMAND: &str = "CLOSING";
+pub const DELETE_BYTE: u8 = b'`';
+pub const DELETE_COMMAND: &str = "DELETE";
+pub const COMPARE_AND_UPDATE_BYTE: u8 = b'\'';
+pub const COMPARE_AND_UPDATE_COMMAND: &str = "COMPARE_AND_UPDATE";
+pub const COMPARE_AND_UPDATE_RESULT_BYTE: u8 = b'"';
+pub const COMPARE_AND_UPDATE_RESULT_COMMAND: &str = "COMPARE_AND_UPDATE_RESULT";
+pub const CREATED_BYTE: u8 = b',';
+pub const CREATED_COMMAND: &str = "CREATED";
+pub const GET_BYTE: u8 = b'G';
+pub const GET_COMMAND: &str = "GET";
+pub const NOTE_BYTE: u8 = b'N';
+pub const NOTE_COMMAND: &str = "NOTE";
+pub const QUERY_BYTE: u8 = b'Q';
+pub const QUERY_COMMAND: &str = "QUERY";
+pub const QUERY_RESULT_BYTE: u8 = b'q';
+pub const QUERY_RESULT_COMMAND: &str = "QUERY_RESULT";
+pub const APPEND_BYTE: u8 = b'A';
+pub const APPEND_COMMAND: &str = "APPEND";
+pub const APPENDED_BYTE: u8 = b'a';
+pub const APPENDED_COMMAND: &str = "APPENDED";
+pub const AUTH_BYTE: u8 = b'T';
+pub const AUTH_COMMAND: &str = "AUTH";
+pub const AUTHENTICATED_BYTE: u8 = b't';
+pub const AUTHENTICATED_COMMAND: &str = "AUTHENTICATED";
+pub const CREATE_WITH_TTL_BYTE: u8 = b'E';
+pub const CREATE_WITH_TTL_COMMAND: &str = "CREATE_WITH_TTL";
+pub const TRANSFER_BYTE: u8 = b'X';
+pub const TRANSFER_COMMAND: &str = "TRANSFER";
+pub const SEARCH_BYTE: u8 = b'S';
+pub const SEARCH_COMMAND: &str = "SEARCH";
+pub const COUNT_BYTE: u8 = b'K';
+pub const COUNT_COMMAND: &str = "COUNT";
+pub const COUNT_RESULT_BYTE: u8 = b'k';
+pub const COUNT_RESULT_COMMAND: &str = "COUNT_RESULT";
+pub const LIST_PAGE_BYTE: u8 = b'P';
+pub const LIST_PAGE_COMMAND: &str = "LIST_PAGE";
+pub const LIST_PAGE_RESULT_BYTE: u8 = b'p';
+pub const LIST_PAGE_RESULT_COMMAND: &str = "LIST_PAGE_RESULT";
+/// [`Command::Create`] and [`Command::CompareAndUpdate`] as sent in
+/// [`WireFormat::LengthPrefixed`]; they decode to the same commands.
+pub const LENGTH_PREFIXED_CREATE_BYTE: u8 = b'L';
+pub const LENGTH_PREFIXED_COMPARE_AND_UPDATE_BYTE: u8 = b'M';
+/// Replies carrying note bodies as sent in [`WireFormat::LengthPrefixed`]:
+/// their usual argument line, preceded by its byte length instead of ending
+/// in `\r\n`. They decode to the same commands.
+pub const LENGTH_PREFIXED_LIST_BYTE: u8 = b'B';
+pub const LENGTH_PREFIXED_LIST_PAGE_RESULT_BYTE: u8 = b'D';
+pub const LENGTH_PREFIXED_NOTE_BYTE: u8 = b'F';
+pub const LENGTH_PREFIXED_NOTIFY_BYTE: u8 = b'H';
+pub const LENGTH_PREFIXED_PAGE_BYTE: u8 = b'I';
 
-#[derive(Debug)]
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How frames are laid out on the wire.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    /// A command byte followed by a `\r\n`-terminated argument line.
+    #[default]
+    Binary,
+    /// One JSON object per `\n`-terminated line, e.g. `{"cmd":"create","body":"..."}`.
+    Json,
+    /// Like `Binary`, but note bodies sent with `Create` and `CompareAndUpdate`,
+    /// and the `List`, `ListPageResult`, `Note`, `Notify` and `Page` replies
+    /// carrying them, are a 4-byte big-endian length followed by raw UTF-8, so
+    /// they may hold `\r\n`. Every binary peer reads these frames, so only the
+    /// sender needs it.
+    LengthPrefixed,
+}
+
+/// Codec applied to every frame once negotiated with `Command::Hello`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => f.write_str("none"),
+            Compression::Lz4 => f.write_str("lz4"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("unknown compression codec: {0}")]
+pub struct UnknownCompression(String);
+
+impl FromStr for Compression {
+    type Err = UnknownCompression;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            other => Err(UnknownCompression(other.to_string())),
+        }
+    }
+}
+
+/// Why the server ended a connection, sent to the client in a final [`Command::Closing`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The client sent `Disconnect`.
+    ClientQuit,
+    /// The client closed its end of the connection.
+    ClientClosed,
+    /// The client sent nothing within the handshake timeout.
+    IdleTimeout,
+    /// The client did not answer a keepalive ping in time.
+    KeepaliveTimeout,
+    /// Reading or answering a frame failed.
+    Error,
+    /// The server is shutting down.
+    ServerShutdown,
+    /// An admin disconnected the client.
+    Kicked,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::ClientQuit => f.write_str("client_quit"),
+            CloseReason::ClientClosed => f.write_str("client_closed"),
+            CloseReason::IdleTimeout => f.write_str("idle_timeout"),
+            CloseReason::KeepaliveTimeout => f.write_str("keepalive_timeout"),
+            CloseReason::Error => f.write_str("error"),
+            CloseReason::ServerShutdown => f.write_str("server_shutdown"),
+            CloseReason::Kicked => f.write_str("kicked"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("unknown close reason: {0}")]
+pub struct UnknownCloseReason(String);
+
+impl FromStr for CloseReason {
+    type Err = UnknownCloseReason;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client_quit" => Ok(CloseReason::ClientQuit),
+            "client_closed" => Ok(CloseReason::ClientClosed),
+            "idle_timeout" => Ok(CloseReason::IdleTimeout),
+            "keepalive_timeout" => Ok(CloseReason::KeepaliveTimeout),
+            "error" => Ok(CloseReason::Error),
+            "server_shutdown" => Ok(CloseReason::ServerShutdown),
+            "kicked" => Ok(CloseReason::Kicked),
+            other => Err(UnknownCloseReason(other.to_string())),
+        }
+    }
+}
+
+/// Server-wide counters returned in reply to [`Command::Stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    pub notes: u64,
+    pub used_bytes: u64,
+    /// How many commands of each type were handled since start, keyed by command name.
+    pub commands: BTreeMap<String, u64>,
+    /// Notes created since start, including those since expired or deleted.
+    #[serde(default)]
+    pub created: u64,
+    /// Notes that expired since start.
+    #[serde(default)]
+    pub expired: u64,
+    /// Clients connected now.
+    #[serde(default)]
+    pub clients: u64,
+}
+
+/// The settings a server is running with, returned in reply to [`Command::Config`].
+/// Only settings that are safe to show any client belong here. Durations are
+/// in milliseconds and `None` means the limit or feature is off.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EffectiveConfig {
+    pub note_timeout_ms: u64,
+    pub max_bytes: Option<u64>,
+    pub evict_oldest: bool,
+    pub max_note_len: Option<u64>,
+    pub json_protocol: bool,
+    pub length_prefixed: bool,
+    /// Names of the templates available to [`Command::CreateFromTemplate`].
+    pub templates: Vec<String>,
+    pub max_in_flight: u64,
+    pub expiry_warning_ms: Option<u64>,
+    pub handshake_timeout_ms: Option<u64>,
+    pub echo_creates: bool,
+    pub dry_run: bool,
+    pub dedupe: bool,
+    pub max_frames_per_sec: Option<u32>,
+    pub create_rate_warning: Option<u64>,
+    pub create_rate_window_ms: u64,
+    pub keepalive_interval_ms: Option<u64>,
+    pub keepalive_timeout_ms: u64,
+    pub idle_expiry: bool,
+    pub idle_expiry_counts_lists: bool,
+    /// The content policy's pattern, and whether matching bodies are rejected
+    /// rather than required.
+    pub content_regex: Option<String>,
+    pub content_regex_forbid: bool,
+}
+
+/// Everything the server knows about one note, returned in reply to [`Command::Describe`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteDescription {
+    pub id: NoteID,
+    pub body: String,
+    /// Creation time, in milliseconds since the Unix epoch.
+    pub created_at_ms: u64,
+    /// Milliseconds until the server removes the note.
+    pub expires_in_ms: u64,
+    pub tags: BTreeSet<String>,
+    pub owner: Option<ClientID>,
+    pub metadata: BTreeMap<String, String>,
+    /// Content hash of `body`; see [`Command::GetByHash`].
+    pub hash: String,
+}
+
+/// The notes found for a [`Command::GetMany`], returned in a [`Command::Batch`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteBatch {
+    /// Bodies of the requested notes that exist, by id.
+    pub found: BTreeMap<NoteID, String>,
+    /// Requested ids with no note, in request order.
+    pub missing: Vec<NoteID>,
+}
+
+/// One note as listed in a [`Command::List`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteSummary {
+    pub id: NoteID,
+    /// Whole seconds since the note was created, as of the listing.
+    pub age_secs: u64,
+    pub body: String,
+}
+
+impl From<&Note> for NoteSummary {
+    fn from(note: &Note) -> Self {
+        NoteSummary {
+            id: note.id(),
+            age_secs: note.elapsed().as_secs(),
+            body: note.body().to_owned(),
+        }
+    }
+}
+
+/// Which notes a [`Command::Query`] selects. Fields left as `None` match every note.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteFilter {
+    /// Case-insensitive substring of the body.
+    pub contains: Option<String>,
+    /// Exact tag the note must carry.
+    pub tag: Option<String>,
+    /// Only notes created at or after this time, in milliseconds since the Unix epoch.
+    pub since_ms: Option<u64>,
+}
+
+impl NoteFilter {
+    pub fn matches(&self, note: &Note) -> bool {
+        let query = NoteQuery {
+            contains: self.contains.clone(),
+            tag: self.tag.clone(),
+            ..Default::default()
+        };
+        let since = self
+            .since_ms
+            .map(|ms| UNIX_EPOCH + Duration::from_millis(ms));
+        note.matches(&query) && since.is_none_or(|since| note.created_at >= since)
+    }
+}
+
+/// Order of the notes in a [`Command::QueryResult`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuerySort {
+    #[default]
+    Id,
+    /// Oldest first.
+    Created,
+    /// Soonest to expire first.
+    Ttl,
+}
+
+impl fmt::Display for QuerySort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuerySort::Id => f.write_str("id"),
+            QuerySort::Created => f.write_str("created"),
+            QuerySort::Ttl => f.write_str("ttl"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("unknown sort order: {0}")]
+pub struct UnknownQuerySort(String);
+
+impl FromStr for QuerySort {
+    type Err = UnknownQuerySort;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(QuerySort::Id),
+            "created" => Ok(QuerySort::Created),
+            "ttl" => Ok(QuerySort::Ttl),
+            other => Err(UnknownQuerySort(other.to_string())),
+        }
+    }
+}
+
+/// What a client that sent [`Command::Auth`] may do. Each role may also do
+/// everything the roles before it may.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Read notes and server information.
+    Reader,
+    /// Also create, change and delete notes.
+    Writer,
+    /// Also disconnect other clients.
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Reader => f.write_str("reader"),
+            Role::Writer => f.write_str("writer"),
+            Role::Admin => f.write_str("admin"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("unknown role: {0}")]
+pub struct UnknownRole(String);
+
+impl FromStr for Role {
+    type Err = UnknownRole;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reader" => Ok(Role::Reader),
+            "writer" => Ok(Role::Writer),
+            "admin" => Ok(Role::Admin),
+            other => Err(UnknownRole(other.to_string())),
+        }
+    }
+}
+
+/// One page of the notes matching a [`Command::Query`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryResult {
+    /// The matches from the query's offset on, at most its limit of them.
+    pub notes: Vec<NoteDescription>,
+    /// How many notes matched in all.
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", content = "body", rename_all = "snake_case")]
 pub enum Command {
     Create(String),
-    List(Vec<String>),
+    List(Vec<NoteSummary>),
     Id(ClientID),
     Disconnect(ClientID),
+    Hello {
+        version: u32,
+        compression: Compression,
+    },
+    /// Exchange the bodies of two notes. The server echoes it back on success.
+    Swap(NoteID, NoteID),
+    /// A request could not be served.
+    Error(String),
+    /// Create a note from a named server-side template, filling its `{placeholder}`s from `vars`.
+    CreateFromTemplate {
+        name: String,
+        vars: BTreeMap<String, String>,
+    },
+    Stats,
+    StatsResult(Stats),
+    /// Ask for the server's [`EffectiveConfig`], answered with a [`Command::ConfigResult`].
+    Config,
+    ConfigResult(EffectiveConfig),
+    /// Ask for a [`Command::Description`] of one note.
+    Describe(NoteID),
+    Description(NoteDescription),
+    /// Read up to `limit` notes, resuming after `cursor` when given. Answered with [`Command::Page`].
+    ReadPage {
+        cursor: Option<String>,
+        limit: u32,
+    },
+    /// One page of note bodies. `cursor` resumes after the last of them and is
+    /// `None` once there are no more notes.
+    Page {
+        notes: Vec<String>,
+        cursor: Option<String>,
+    },
+    /// Pushed to a note's owner shortly before the note expires.
+    Expiring(NoteID),
+    /// Like [`Command::Create`], attaching `metadata` such as `source=email` to the note.
+    CreateWithMetadata {
+        body: String,
+        metadata: BTreeMap<String, String>,
+    },
+    /// Answered with a [`Command::Pong`] carrying the same opaque nonce, so
+    /// pipelined pings can be matched to their replies. The server also sends
+    /// them to idle clients as keepalives.
+    Ping(u64),
+    Pong(u64),
+    /// Ask to be sent a [`Command::Notify`] for every note created from now on
+    /// that matches both filters; `None` matches everything. Echoed back once active.
+    Subscribe {
+        contains: Option<String>,
+        tag: Option<String>,
+    },
+    /// Stop the pushes started by [`Command::Subscribe`], keeping the connection
+    /// open. Echoed back, also when the client was not subscribed.
+    Unsubscribe,
+    /// Pushed to subscribers when a matching note is created. `seq` increases
+    /// with every create on the server, so pushes arrive in strictly increasing
+    /// order; a jump means notes were skipped by the filter or dropped because
+    /// the subscriber fell behind.
+    Notify {
+        seq: u64,
+        id: NoteID,
+        body: String,
+    },
+    /// Add a delta to a note whose body is an integer. Answered with a [`Command::Counter`].
+    Increment(NoteID, i64),
+    /// A counter note's value after an [`Command::Increment`].
+    Counter(NoteID, i64),
+    /// Ask for a [`Command::Description`] of the note whose body has this content hash.
+    GetByHash(String),
+    /// Fetch several notes in one round trip. Answered with a [`Command::Batch`].
+    GetMany(Vec<NoteID>),
+    Batch(NoteBatch),
+    /// The id given to the note stored for a create.
+    Created(NoteID),
+    /// Fetch one note's body. Answered with a [`Command::Note`], or an error
+    /// if there is no such note.
+    Get(NoteID),
+    Note(NoteID, String),
+    /// Select the notes matching `filter`, ordered by `sort`, and return up to
+    /// `limit` of them starting `offset` matches in. Answered with a
+    /// [`Command::QueryResult`].
+    Query {
+        filter: NoteFilter,
+        sort: QuerySort,
+        offset: u32,
+        limit: u32,
+    },
+    QueryResult(QueryResult),
+    /// Add a line to the end of a note's body. Answered with a
+    /// [`Command::Appended`], or an error if there is no such note or the
+    /// body would grow too long.
+    Append(NoteID, String),
+    /// A note's body length in bytes after an [`Command::Append`].
+    Appended(NoteID, u64),
+    /// Present a token to the server. Answered with a [`Command::Authenticated`],
+    /// or an error if the server doesn't know the token.
+    Auth(String),
+    /// The role the client was given by its [`Command::Auth`].
+    Authenticated(Role),
+    /// Like [`Command::Create`], with the note expiring this long after it is
+    /// created instead of after the server's note timeout. Sent in whole milliseconds.
+    CreateWithTtl(Duration, String),
+    /// Make another client the owner of a note. Only the owner, or an admin,
+    /// may hand a note off; the server echoes the command back on success.
+    Transfer(NoteID, ClientID),
+    /// Like [`Command::Read`], only listing the notes whose body contains the
+    /// text, ignoring case. Answered with a [`Command::List`].
+    Search(String),
+    /// Ask how many notes the server holds, without their bodies. Answered
+    /// with a [`Command::CountResult`].
+    Count,
+    /// The number of notes, in reply to a [`Command::Count`].
+    CountResult(u64),
+    /// Like [`Command::Read`], listing at most `limit` notes in id order after
+    /// skipping the first `offset`. Answered with a [`Command::ListPageResult`].
+    ListPage {
+        offset: u64,
+        limit: u64,
+    },
+    /// One page of notes, and whether more follow it.
+    ListPageResult {
+        notes: Vec<NoteSummary>,
+        more: bool,
+    },
+    /// Remove a note before it expires. The server echoes it back on success.
+    Delete(NoteID),
+    /// Replace a note's body with `new_body` only if it still equals
+    /// `expected_body`. Answered with a [`Command::CompareAndUpdateResult`].
+    CompareAndUpdate {
+        id: NoteID,
+        expected_body: String,
+        new_body: String,
+    },
+    /// Whether a [`Command::CompareAndUpdate`] replaced the body.
+    CompareAndUpdateResult {
+        id: NoteID,
+        updated: bool,
+    },
+    /// The last frame the server sends before closing the connection.
+    Closing(CloseReason),
     Read,
     Quit,
 }
@@ -35,22 +595,116 @@ impl Command {
             Command::Quit => QUIT_BYTE,
             Command::Disconnect(_) => DISCONNECT_BYTE,
             Command::Id(_) => ID_BYTE,
+            Command::Hello { .. } => HELLO_BYTE,
+            Command::Swap(..) => SWAP_BYTE,
+            Command::Error(_) => ERROR_BYTE,
+            Command::CreateFromTemplate { .. } => CREATE_FROM_TEMPLATE_BYTE,
+            Command::Stats => STATS_BYTE,
+            Command::StatsResult(_) => STATS_RESULT_BYTE,
+            Command::Config => CONFIG_BYTE,
+            Command::ConfigResult(_) => CONFIG_RESULT_BYTE,
+            Command::Describe(_) => DESCRIBE_BYTE,
+            Command::Description(_) => DESCRIPTION_BYTE,
+            Command::ReadPage { .. } => READ_PAGE_BYTE,
+            Command::Page { .. } => PAGE_BYTE,
+            Command::Expiring(_) => EXPIRING_BYTE,
+            Command::CreateWithMetadata { .. } => CREATE_WITH_METADATA_BYTE,
+            Command::Ping(_) => PING_BYTE,
+            Command::Pong(_) => PONG_BYTE,
+            Command::Subscribe { .. } => SUBSCRIBE_BYTE,
+            Command::Unsubscribe => UNSUBSCRIBE_BYTE,
+            Command::Notify { .. } => NOTIFY_BYTE,
+            Command::Increment(..) => INCREMENT_BYTE,
+            Command::Counter(..) => COUNTER_BYTE,
+            Command::GetByHash(_) => GET_BY_HASH_BYTE,
+            Command::GetMany(_) => GET_MANY_BYTE,
+            Command::Batch(_) => BATCH_BYTE,
+            Command::Closing(_) => CLOSING_BYTE,
+            Command::Delete(_) => DELETE_BYTE,
+            Command::Created(_) => CREATED_BYTE,
+            Command::Get(_) => GET_BYTE,
+            Command::Note(..) => NOTE_BYTE,
+            Command::Query { .. } => QUERY_BYTE,
+            Command::QueryResult(_) => QUERY_RESULT_BYTE,
+            Command::Append(..) => APPEND_BYTE,
+            Command::Appended(..) => APPENDED_BYTE,
+            Command::Auth(_) => AUTH_BYTE,
+            Command::Authenticated(_) => AUTHENTICATED_BYTE,
+            Command::CreateWithTtl(..) => CREATE_WITH_TTL_BYTE,
+            Command::Transfer(..) => TRANSFER_BYTE,
+            Command::Search(_) => SEARCH_BYTE,
+            Command::Count => COUNT_BYTE,
+            Command::CountResult(_) => COUNT_RESULT_BYTE,
+            Command::ListPage { .. } => LIST_PAGE_BYTE,
+            Command::ListPageResult { .. } => LIST_PAGE_RESULT_BYTE,
+            Command::CompareAndUpdate { .. } => COMPARE_AND_UPDATE_BYTE,
+            Command::CompareAndUpdateResult { .. } => COMPARE_AND_UPDATE_RESULT_BYTE,
         }
     }
 }
 
-impl ToString for Command {
-    fn to_string(&self) -> String {
+impl Command {
+    pub fn name(&self) -> &'static str {
         match self {
-            Command::Create(_) => CREATE_COMMAND.to_string(),
-            Command::List(_) => LIST_COMMAND.to_string(),
-            Command::Read => READ_COMMAND.to_string(),
-            Command::Quit => QUIT_COMMAND.to_string(),
-            Command::Disconnect(_) => DISCONNECT_COMMAND.to_string(),
-            Command::Id(_) => ID_COMMAND.to_string(),
+            Command::Create(_) => CREATE_COMMAND,
+            Command::List(_) => LIST_COMMAND,
+            Command::Read => READ_COMMAND,
+            Command::Quit => QUIT_COMMAND,
+            Command::Disconnect(_) => DISCONNECT_COMMAND,
+            Command::Id(_) => ID_COMMAND,
+            Command::Hello { .. } => HELLO_COMMAND,
+            Command::Swap(..) => SWAP_COMMAND,
+            Command::Error(_) => ERROR_COMMAND,
+            Command::CreateFromTemplate { .. } => CREATE_FROM_TEMPLATE_COMMAND,
+            Command::Stats => STATS_COMMAND,
+            Command::StatsResult(_) => STATS_RESULT_COMMAND,
+            Command::Config => CONFIG_COMMAND,
+            Command::ConfigResult(_) => CONFIG_RESULT_COMMAND,
+            Command::Describe(_) => DESCRIBE_COMMAND,
+            Command::Description(_) => DESCRIPTION_COMMAND,
+            Command::ReadPage { .. } => READ_PAGE_COMMAND,
+            Command::Page { .. } => PAGE_COMMAND,
+            Command::Expiring(_) => EXPIRING_COMMAND,
+            Command::CreateWithMetadata { .. } => CREATE_WITH_METADATA_COMMAND,
+            Command::Ping(_) => PING_COMMAND,
+            Command::Pong(_) => PONG_COMMAND,
+            Command::Subscribe { .. } => SUBSCRIBE_COMMAND,
+            Command::Unsubscribe => UNSUBSCRIBE_COMMAND,
+            Command::Notify { .. } => NOTIFY_COMMAND,
+            Command::Increment(..) => INCREMENT_COMMAND,
+            Command::Counter(..) => COUNTER_COMMAND,
+            Command::GetByHash(_) => GET_BY_HASH_COMMAND,
+            Command::GetMany(_) => GET_MANY_COMMAND,
+            Command::Batch(_) => BATCH_COMMAND,
+            Command::Closing(_) => CLOSING_COMMAND,
+            Command::Delete(_) => DELETE_COMMAND,
+            Command::Created(_) => CREATED_COMMAND,
+            Command::Get(_) => GET_COMMAND,
+            Command::Note(..) => NOTE_COMMAND,
+            Command::Query { .. } => QUERY_COMMAND,
+            Command::QueryResult(_) => QUERY_RESULT_COMMAND,
+            Command::Append(..) => APPEND_COMMAND,
+            Command::Appended(..) => APPENDED_COMMAND,
+            Command::Auth(_) => AUTH_COMMAND,
+            Command::Authenticated(_) => AUTHENTICATED_COMMAND,
+            Command::CreateWithTtl(..) => CREATE_WITH_TTL_COMMAND,
+            Command::Transfer(..) => TRANSFER_COMMAND,
+            Command::Search(_) => SEARCH_COMMAND,
+            Command::Count => COUNT_COMMAND,
+            Command::CountResult(_) => COUNT_RESULT_COMMAND,
+            Command::ListPage { .. } => LIST_PAGE_COMMAND,
+            Command::ListPageResult { .. } => LIST_PAGE_RESULT_COMMAND,
+            Command::CompareAndUpdate { .. } => COMPARE_AND_UPDATE_COMMAND,
+            Command::CompareAndUpdateResult { .. } => COMPARE_AND_UPDATE_RESULT_COMMAND,
         }
     }
 }
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
 impl From<Command> for u8 {
     fn from(command: Command) -> Self {
         command.byte()
@@ -71,23 +725,122 @@ impl From<Frame> for Command {
         frame.0
     }
 }
-impl From<u8> for Command {
-    fn from(byte: u8) -> Self {
-        match byte {
-            CREATE_BYTE => Command::Create(String::new()),
-            LIST_BYTE => Command::List(Vec::new()),
+impl TryFrom<u8> for Command {
+    type Error = FrameParseError;
+
+    /// The command a frame starting with `byte` holds, with placeholder fields.
+    fn try_from(byte: u8) -> Result<Self, FrameParseError> {
+        Ok(match byte {
+            CREATE_BYTE | LENGTH_PREFIXED_CREATE_BYTE => Command::Create(String::new()),
+            LIST_BYTE | LENGTH_PREFIXED_LIST_BYTE => Command::List(Vec::new()),
             READ_BYTE => Command::Read,
             QUIT_BYTE => Command::Quit,
             DISCONNECT_BYTE => Command::Disconnect(0),
             ID_BYTE => Command::Id(0),
-            _ => panic!("invalid command"),
-        }
+            HELLO_BYTE => Command::Hello {
+                version: PROTOCOL_VERSION,
+                compression: Compression::None,
+            },
+            SWAP_BYTE => Command::Swap(0, 0),
+            ERROR_BYTE => Command::Error(String::new()),
+            CREATE_FROM_TEMPLATE_BYTE => Command::CreateFromTemplate {
+                name: String::new(),
+                vars: BTreeMap::new(),
+            },
+            STATS_BYTE => Command::Stats,
+            STATS_RESULT_BYTE => Command::StatsResult(Stats::default()),
+            CONFIG_BYTE => Command::Config,
+            CONFIG_RESULT_BYTE => Command::ConfigResult(EffectiveConfig::default()),
+            DESCRIBE_BYTE => Command::Describe(0),
+            DESCRIPTION_BYTE => Command::Description(NoteDescription::default()),
+            READ_PAGE_BYTE => Command::ReadPage {
+                cursor: None,
+                limit: 0,
+            },
+            PAGE_BYTE | LENGTH_PREFIXED_PAGE_BYTE => Command::Page {
+                notes: Vec::new(),
+                cursor: None,
+            },
+            EXPIRING_BYTE => Command::Expiring(0),
+            CREATE_WITH_METADATA_BYTE => Command::CreateWithMetadata {
+                body: String::new(),
+                metadata: BTreeMap::new(),
+            },
+            PING_BYTE => Command::Ping(0),
+            PONG_BYTE => Command::Pong(0),
+            SUBSCRIBE_BYTE => Command::Subscribe {
+                contains: None,
+                tag: None,
+            },
+            UNSUBSCRIBE_BYTE => Command::Unsubscribe,
+            NOTIFY_BYTE | LENGTH_PREFIXED_NOTIFY_BYTE => Command::Notify {
+                seq: 0,
+                id: 0,
+                body: String::new(),
+            },
+            INCREMENT_BYTE => Command::Increment(0, 0),
+            COUNTER_BYTE => Command::Counter(0, 0),
+            GET_BY_HASH_BYTE => Command::GetByHash(String::new()),
+            GET_MANY_BYTE => Command::GetMany(Vec::new()),
+            BATCH_BYTE => Command::Batch(NoteBatch::default()),
+            CLOSING_BYTE => Command::Closing(CloseReason::Error),
+            DELETE_BYTE => Command::Delete(0),
+            CREATED_BYTE => Command::Created(0),
+            GET_BYTE => Command::Get(0),
+            NOTE_BYTE | LENGTH_PREFIXED_NOTE_BYTE => Command::Note(0, String::new()),
+            QUERY_BYTE => Command::Query {
+                filter: NoteFilter::default(),
+                sort: QuerySort::default(),
+                offset: 0,
+                limit: 0,
+            },
+            QUERY_RESULT_BYTE => Command::QueryResult(QueryResult::default()),
+            APPEND_BYTE => Command::Append(0, String::new()),
+            APPENDED_BYTE => Command::Appended(0, 0),
+            AUTH_BYTE => Command::Auth(String::new()),
+            AUTHENTICATED_BYTE => Command::Authenticated(Role::Reader),
+            CREATE_WITH_TTL_BYTE => Command::CreateWithTtl(Duration::ZERO, String::new()),
+            TRANSFER_BYTE => Command::Transfer(0, 0),
+            SEARCH_BYTE => Command::Search(String::new()),
+            COUNT_BYTE => Command::Count,
+            COUNT_RESULT_BYTE => Command::CountResult(0),
+            LIST_PAGE_BYTE => Command::ListPage {
+                offset: 0,
+                limit: 0,
+            },
+            LIST_PAGE_RESULT_BYTE | LENGTH_PREFIXED_LIST_PAGE_RESULT_BYTE => {
+                Command::ListPageResult {
+                    notes: Vec::new(),
+                    more: false,
+                }
+            }
+            COMPARE_AND_UPDATE_BYTE | LENGTH_PREFIXED_COMPARE_AND_UPDATE_BYTE => {
+                Command::CompareAndUpdate {
+                    id: 0,
+                    expected_body: String::new(),
+                    new_body: String::new(),
+                }
+            }
+            COMPARE_AND_UPDATE_RESULT_BYTE => Command::CompareAndUpdateResult {
+                id: 0,
+                updated: false,
+            },
+            other => return Err(FrameParseError::Invalid(other)),
+        })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Frame(pub Command);
 impl Frame {
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        let mut json = serde_json::to_vec(&self.0)?;
+        json.push(b'\n');
+        Ok(json)
+    }
+    pub fn from_json(line: &[u8]) -> Result<Frame> {
+        Ok(Frame(serde_json::from_slice(line)?))
+    }
     pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), FrameParseError> {
         match get_u8(src)? {
             CREATE_BYTE => {
@@ -100,11 +853,70 @@ impl Frame {
             }
             READ_BYTE => Ok(()),
             QUIT_BYTE => Ok(()),
+            STATS_BYTE => Ok(()),
+            UNSUBSCRIBE_BYTE => Ok(()),
+            CONFIG_BYTE => Ok(()),
+            COUNT_BYTE => Ok(()),
+            LENGTH_PREFIXED_CREATE_BYTE
+            | LENGTH_PREFIXED_LIST_BYTE
+            | LENGTH_PREFIXED_LIST_PAGE_RESULT_BYTE
+            | LENGTH_PREFIXED_NOTE_BYTE
+            | LENGTH_PREFIXED_NOTIFY_BYTE
+            | LENGTH_PREFIXED_PAGE_BYTE => {
+                get_sized(src)?;
+                Ok(())
+            }
+            LENGTH_PREFIXED_COMPARE_AND_UPDATE_BYTE => {
+                get_u64(src)?;
+                get_sized(src)?;
+                get_sized(src)?;
+                Ok(())
+            }
             DISCONNECT_BYTE => {
                 get_line(src)?;
                 Ok(())
             }
-            ID_BYTE => {
+            ID_BYTE | COUNT_RESULT_BYTE | LIST_PAGE_BYTE | LIST_PAGE_RESULT_BYTE => {
+                get_line(src)?;
+                Ok(())
+            }
+            HELLO_BYTE
+            | SWAP_BYTE
+            | ERROR_BYTE
+            | CREATE_FROM_TEMPLATE_BYTE
+            | STATS_RESULT_BYTE
+            | CONFIG_RESULT_BYTE
+            | DESCRIBE_BYTE
+            | DESCRIPTION_BYTE
+            | READ_PAGE_BYTE
+            | PAGE_BYTE
+            | EXPIRING_BYTE
+            | CREATE_WITH_METADATA_BYTE
+            | PING_BYTE
+            | PONG_BYTE
+            | SUBSCRIBE_BYTE
+            | NOTIFY_BYTE
+            | INCREMENT_BYTE
+            | COUNTER_BYTE
+            | GET_BY_HASH_BYTE
+            | GET_MANY_BYTE
+            | BATCH_BYTE
+            | CLOSING_BYTE
+            | DELETE_BYTE
+            | CREATED_BYTE
+            | GET_BYTE
+            | NOTE_BYTE
+            | QUERY_BYTE
+            | QUERY_RESULT_BYTE
+            | APPEND_BYTE
+            | APPENDED_BYTE
+            | AUTH_BYTE
+            | AUTHENTICATED_BYTE
+            | CREATE_WITH_TTL_BYTE
+            | TRANSFER_BYTE
+            | SEARCH_BYTE
+            | COMPARE_AND_UPDATE_BYTE
+            | COMPARE_AND_UPDATE_RESULT_BYTE => {
                 get_line(src)?;
                 Ok(())
             }
@@ -117,29 +929,33 @@ impl Frame {
                 let line = get_line(src)?.to_vec();
                 Ok(Command::Create(String::from_utf8(line)?).into())
             }
-            LIST_BYTE => {
-                let line = get_line(src)?.to_vec();
-                let encoded_notes = String::from_utf8(line)?;
-                let mut notes = Vec::new();
-                let mut chars = encoded_notes.chars();
-                let mut len = String::new();
-                while let Some(ch) = chars.next() {
-                    match ch {
-                        '#' => {
-                            let note_size = len.parse::<usize>()?;
-                            let mut note = String::new();
-                            for _ in 0..note_size {
-                                let c = chars.next().ok_or(anyhow!("invalid frame"))?;
-                                note.push(c);
-                            }
-                            notes.push(note);
-                            len.clear();
-                        }
-                        c if c.is_ascii_digit() => len.push(c),
-                        _ => return Err(anyhow!("invalid frame")),
-                    }
+            byte @ (LIST_BYTE | LENGTH_PREFIXED_LIST_BYTE) => {
+                let line = get_argument(byte, src)?.to_vec();
+                let fields = decode_strings(&String::from_utf8(line)?)?;
+                Ok(Command::List(decode_summaries(&fields)?).into())
+            }
+            LIST_PAGE_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (offset, limit) = line
+                    .split_once(' ')
+                    .ok_or(anyhow!("invalid list page frame"))?;
+                Ok(Command::ListPage {
+                    offset: offset.parse()?,
+                    limit: limit.parse()?,
                 }
-                Ok(Command::List(notes).into())
+                .into())
+            }
+            byte @ (LIST_PAGE_RESULT_BYTE | LENGTH_PREFIXED_LIST_PAGE_RESULT_BYTE) => {
+                let line = get_argument(byte, src)?.to_vec();
+                let fields = decode_strings(&String::from_utf8(line)?)?;
+                let (more, notes) = fields
+                    .split_first()
+                    .ok_or(anyhow!("missing list page flag"))?;
+                Ok(Command::ListPageResult {
+                    notes: decode_summaries(notes)?,
+                    more: more == "1",
+                }
+                .into())
             }
             READ_BYTE => Ok(Command::Read.into()),
             QUIT_BYTE => Ok(Command::Quit.into()),
@@ -155,7 +971,669 @@ impl Frame {
                 let id = id.parse::<u64>()?;
                 Ok(Command::Id(id).into())
             }
+            COUNT_RESULT_BYTE => {
+                let count = get_line(src)?;
+                let count = String::from_utf8(count.to_vec())?;
+                let count = count.parse::<u64>()?;
+                Ok(Command::CountResult(count).into())
+            }
+            HELLO_BYTE => {
+                let line = get_line(src)?;
+                let line = String::from_utf8(line.to_vec())?;
+                let (version, compression) =
+                    line.split_once(' ').ok_or(anyhow!("invalid hello frame"))?;
+                Ok(Command::Hello {
+                    version: version.parse()?,
+                    compression: compression.parse()?,
+                }
+                .into())
+            }
+            SWAP_BYTE => {
+                let line = get_line(src)?;
+                let line = String::from_utf8(line.to_vec())?;
+                let (a, b) = line.split_once(' ').ok_or(anyhow!("invalid swap frame"))?;
+                Ok(Command::Swap(a.parse()?, b.parse()?).into())
+            }
+            ERROR_BYTE => {
+                let message = get_line(src)?;
+                Ok(Command::Error(String::from_utf8(message.to_vec())?).into())
+            }
+            CREATE_FROM_TEMPLATE_BYTE => {
+                let line = get_line(src)?.to_vec();
+                let mut strings = decode_strings(&String::from_utf8(line)?)?.into_iter();
+                let name = strings.next().ok_or(anyhow!("missing template name"))?;
+                let mut vars = BTreeMap::new();
+                while let Some(key) = strings.next() {
+                    let value = strings.next().ok_or(anyhow!("missing value for {key}"))?;
+                    vars.insert(key, value);
+                }
+                Ok(Command::CreateFromTemplate { name, vars }.into())
+            }
+            CREATE_WITH_METADATA_BYTE => {
+                let line = get_line(src)?.to_vec();
+                let mut strings = decode_strings(&String::from_utf8(line)?)?.into_iter();
+                let body = strings.next().ok_or(anyhow!("missing note body"))?;
+                let mut metadata = BTreeMap::new();
+                while let Some(key) = strings.next() {
+                    let value = strings.next().ok_or(anyhow!("missing value for {key}"))?;
+                    metadata.insert(key, value);
+                }
+                Ok(Command::CreateWithMetadata { body, metadata }.into())
+            }
+            STATS_BYTE => Ok(Command::Stats.into()),
+            UNSUBSCRIBE_BYTE => Ok(Command::Unsubscribe.into()),
+            STATS_RESULT_BYTE => {
+                let line = get_line(src)?;
+                Ok(Command::StatsResult(serde_json::from_slice(line)?).into())
+            }
+            CONFIG_BYTE => Ok(Command::Config.into()),
+            COUNT_BYTE => Ok(Command::Count.into()),
+            LENGTH_PREFIXED_CREATE_BYTE => {
+                let body = String::from_utf8(get_sized(src)?.to_vec())?;
+                Ok(Command::Create(body).into())
+            }
+            LENGTH_PREFIXED_COMPARE_AND_UPDATE_BYTE => {
+                let id = get_u64(src)?;
+                let expected_body = String::from_utf8(get_sized(src)?.to_vec())?;
+                let new_body = String::from_utf8(get_sized(src)?.to_vec())?;
+                Ok(Command::CompareAndUpdate {
+                    id,
+                    expected_body,
+                    new_body,
+                }
+                .into())
+            }
+            CONFIG_RESULT_BYTE => {
+                let line = get_line(src)?;
+                Ok(Command::ConfigResult(serde_json::from_slice(line)?).into())
+            }
+            DESCRIBE_BYTE => {
+                let id = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Describe(id.parse()?).into())
+            }
+            EXPIRING_BYTE => {
+                let id = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Expiring(id.parse()?).into())
+            }
+            DELETE_BYTE => {
+                let id = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Delete(id.parse()?).into())
+            }
+            CREATED_BYTE => {
+                let id = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Created(id.parse()?).into())
+            }
+            GET_BYTE => {
+                let id = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Get(id.parse()?).into())
+            }
+            byte @ (NOTE_BYTE | LENGTH_PREFIXED_NOTE_BYTE) => {
+                let line = String::from_utf8(get_argument(byte, src)?.to_vec())?;
+                let (id, body) = line.split_once(' ').ok_or(anyhow!("invalid note frame"))?;
+                Ok(Command::Note(id.parse()?, body.to_owned()).into())
+            }
+            QUERY_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let mut fields = line.splitn(4, ' ');
+                let (Some(sort), Some(offset), Some(limit), Some(filter)) =
+                    (fields.next(), fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(anyhow!("invalid query frame"));
+                };
+                Ok(Command::Query {
+                    filter: serde_json::from_str(filter)?,
+                    sort: sort.parse()?,
+                    offset: offset.parse()?,
+                    limit: limit.parse()?,
+                }
+                .into())
+            }
+            QUERY_RESULT_BYTE => {
+                let line = get_line(src)?;
+                Ok(Command::QueryResult(serde_json::from_slice(line)?).into())
+            }
+            APPEND_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (id, text) = line
+                    .split_once(' ')
+                    .ok_or(anyhow!("invalid append frame"))?;
+                Ok(Command::Append(id.parse()?, text.to_owned()).into())
+            }
+            CREATE_WITH_TTL_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (ttl_ms, body) = line
+                    .split_once(' ')
+                    .ok_or(anyhow!("invalid create with ttl frame"))?;
+                let ttl = Duration::from_millis(ttl_ms.parse()?);
+                Ok(Command::CreateWithTtl(ttl, body.to_owned()).into())
+            }
+            SEARCH_BYTE => {
+                let line = get_line(src)?.to_vec();
+                Ok(Command::Search(String::from_utf8(line)?).into())
+            }
+            TRANSFER_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (id, target) = line
+                    .split_once(' ')
+                    .ok_or(anyhow!("invalid transfer frame"))?;
+                Ok(Command::Transfer(id.parse()?, target.parse()?).into())
+            }
+            APPENDED_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (id, len) = line
+                    .split_once(' ')
+                    .ok_or(anyhow!("invalid appended frame"))?;
+                Ok(Command::Appended(id.parse()?, len.parse()?).into())
+            }
+            AUTH_BYTE => {
+                let token = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Auth(token).into())
+            }
+            AUTHENTICATED_BYTE => {
+                let role = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Authenticated(role.parse()?).into())
+            }
+            GET_BY_HASH_BYTE => {
+                let line = get_line(src)?.to_vec();
+                Ok(Command::GetByHash(String::from_utf8(line)?).into())
+            }
+            GET_MANY_BYTE => {
+                let line = get_line(src)?.to_vec();
+                let ids = decode_strings(&String::from_utf8(line)?)?
+                    .iter()
+                    .map(|id| id.parse())
+                    .collect::<Result<_, _>>()?;
+                Ok(Command::GetMany(ids).into())
+            }
+            BATCH_BYTE => {
+                let line = get_line(src)?;
+                Ok(Command::Batch(serde_json::from_slice(line)?).into())
+            }
+            CLOSING_BYTE => {
+                let reason = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Closing(reason.parse()?).into())
+            }
+            PING_BYTE => {
+                let nonce = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Ping(nonce.parse()?).into())
+            }
+            PONG_BYTE => {
+                let nonce = String::from_utf8(get_line(src)?.to_vec())?;
+                Ok(Command::Pong(nonce.parse()?).into())
+            }
+            INCREMENT_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (id, delta) = line
+                    .split_once(' ')
+                    .ok_or(anyhow!("invalid increment frame"))?;
+                Ok(Command::Increment(id.parse()?, delta.parse()?).into())
+            }
+            COUNTER_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (id, value) = line
+                    .split_once(' ')
+                    .ok_or(anyhow!("invalid counter frame"))?;
+                Ok(Command::Counter(id.parse()?, value.parse()?).into())
+            }
+            SUBSCRIBE_BYTE => {
+                let line = get_line(src)?.to_vec();
+                let mut strings = decode_strings(&String::from_utf8(line)?)?.into_iter();
+                let mut filter = || strings.next().filter(|filter| !filter.is_empty());
+                Ok(Command::Subscribe {
+                    contains: filter(),
+                    tag: filter(),
+                }
+                .into())
+            }
+            COMPARE_AND_UPDATE_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (id, bodies) = line
+                    .split_once(' ')
+                    .ok_or(anyhow!("invalid compare-and-update frame"))?;
+                let [expected_body, new_body] = <[String; 2]>::try_from(decode_strings(bodies)?)
+                    .map_err(|_| anyhow!("compare-and-update needs two bodies"))?;
+                Ok(Command::CompareAndUpdate {
+                    id: id.parse()?,
+                    expected_body,
+                    new_body,
+                }
+                .into())
+            }
+            COMPARE_AND_UPDATE_RESULT_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (id, updated) = line
+                    .split_once(' ')
+                    .ok_or(anyhow!("invalid compare-and-update result frame"))?;
+                Ok(Command::CompareAndUpdateResult {
+                    id: id.parse()?,
+                    updated: updated.parse()?,
+                }
+                .into())
+            }
+            byte @ (NOTIFY_BYTE | LENGTH_PREFIXED_NOTIFY_BYTE) => {
+                let line = String::from_utf8(get_argument(byte, src)?.to_vec())?;
+                let mut fields = line.splitn(3, ' ');
+                let (Some(seq), Some(id), Some(body)) =
+                    (fields.next(), fields.next(), fields.next())
+                else {
+                    return Err(anyhow!("invalid notify frame"));
+                };
+                Ok(Command::Notify {
+                    seq: seq.parse()?,
+                    id: id.parse()?,
+                    body: body.to_string(),
+                }
+                .into())
+            }
+            DESCRIPTION_BYTE => {
+                let line = get_line(src)?;
+                Ok(Command::Description(serde_json::from_slice(line)?).into())
+            }
+            READ_PAGE_BYTE => {
+                let line = String::from_utf8(get_line(src)?.to_vec())?;
+                let (limit, cursor) = line.split_once(' ').unwrap_or((&line, ""));
+                Ok(Command::ReadPage {
+                    cursor: Some(cursor.to_string()).filter(|cursor| !cursor.is_empty()),
+                    limit: limit.parse()?,
+                }
+                .into())
+            }
+            byte @ (PAGE_BYTE | LENGTH_PREFIXED_PAGE_BYTE) => {
+                let line = get_argument(byte, src)?.to_vec();
+                let mut strings = decode_strings(&String::from_utf8(line)?)?.into_iter();
+                let cursor = strings.next().ok_or(anyhow!("missing page cursor"))?;
+                Ok(Command::Page {
+                    cursor: Some(cursor).filter(|cursor| !cursor.is_empty()),
+                    notes: strings.collect(),
+                }
+                .into())
+            }
             other => Err(FrameParseError::Invalid(other).into()),
         }
     }
 }
+
+/// The argument of a frame starting with `byte`: sized for the
+/// length-prefixed replies, otherwise a `\r\n`-terminated line.
+fn get_argument<'a>(byte: u8, src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], FrameParseError> {
+    match byte {
+        LENGTH_PREFIXED_LIST_BYTE
+        | LENGTH_PREFIXED_LIST_PAGE_RESULT_BYTE
+        | LENGTH_PREFIXED_NOTE_BYTE
+        | LENGTH_PREFIXED_NOTIFY_BYTE
+        | LENGTH_PREFIXED_PAGE_BYTE => get_sized(src),
+        _ => get_line(src),
+    }
+}
+
+/// Notes as sent in a [`Command::List`]: an id, age and body per note.
+pub(crate) fn decode_summaries(fields: &[String]) -> Result<Vec<NoteSummary>> {
+    if !fields.len().is_multiple_of(3) {
+        return Err(anyhow!("list entries need an id, age and body"));
+    }
+    fields
+        .chunks_exact(3)
+        .map(|note| {
+            Ok(NoteSummary {
+                id: note[0].parse()?,
+                age_secs: note[1].parse()?,
+                body: note[2].clone(),
+            })
+        })
+        .collect()
+}
+
+/// Encode strings as `<len>#<string>` runs, so they may contain any character but `\r\n`.
+pub(crate) fn encode_strings<S: AsRef<str>>(strings: impl IntoIterator<Item = S>) -> String {
+    strings.into_iter().fold(String::new(), |f, s| {
+        let s = s.as_ref();
+        f + s.len().to_string().as_str() + "#" + s
+    })
+}
+
+/// Split strings joined by [`encode_strings`]. Lengths count bytes, so a
+/// length that runs past the end or into the middle of a character is an
+/// error, as is anything left over after the last string.
+pub(crate) fn decode_strings(encoded: &str) -> Result<Vec<String>> {
+    let mut strings = Vec::new();
+    let mut rest = encoded;
+    while !rest.is_empty() {
+        let (len, tail) = rest
+            .split_once('#')
+            .filter(|(len, _)| !len.is_empty() && len.bytes().all(|b| b.is_ascii_digit()))
+            .ok_or(anyhow!("invalid frame: expected a length"))?;
+        let len = len.parse::<usize>()?;
+        let string = tail
+            .get(..len)
+            .ok_or(anyhow!("invalid frame: string shorter than its length"))?;
+        strings.push(string.to_owned());
+        rest = &tail[len..];
+    }
+    Ok(strings)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn all_commands() -> Vec<Command> {
+        vec![
+            Command::Create("buy milk".to_string()),
+            Command::List(vec![
+                NoteSummary {
+                    id: 1,
+                    age_secs: 12,
+                    body: "one".to_string(),
+                },
+                NoteSummary {
+                    id: 4,
+                    age_secs: 0,
+                    body: "two \"quoted\" 3#x".to_string(),
+                },
+                NoteSummary {
+                    id: 9,
+                    age_secs: 3600,
+                    body: String::new(),
+                },
+            ]),
+            Command::Id(7),
+            Command::Disconnect(7),
+            Command::Hello {
+                version: PROTOCOL_VERSION,
+                compression: Compression::Lz4,
+            },
+            Command::Swap(1, 2),
+            Command::Error("note 3 not found".to_string()),
+            Command::CreateFromTemplate {
+                name: "standup".to_string(),
+                vars: BTreeMap::from([("who".to_string(), "alice".to_string())]),
+            },
+            Command::Stats,
+            Command::StatsResult(Stats {
+                notes: 2,
+                used_bytes: 10,
+                commands: BTreeMap::from([(CREATE_COMMAND.to_string(), 2)]),
+                created: 3,
+                expired: 1,
+                clients: 2,
+            }),
+            Command::Config,
+            Command::ConfigResult(EffectiveConfig {
+                note_timeout_ms: 60_000,
+                max_bytes: Some(4096),
+                templates: vec!["standup".to_string()],
+                content_regex: Some("^[A-Z]+-\\d+".to_string()),
+                ..Default::default()
+            }),
+            Command::ReadPage {
+                cursor: Some("0000000000000003".to_string()),
+                limit: 10,
+            },
+            Command::ReadPage {
+                cursor: None,
+                limit: 10,
+            },
+            Command::Page {
+                notes: vec!["one".to_string()],
+                cursor: Some("0000000000000001".to_string()),
+            },
+            Command::Page {
+                notes: Vec::new(),
+                cursor: None,
+            },
+            Command::Expiring(3),
+            Command::Delete(3),
+            Command::Created(3),
+            Command::Get(3),
+            Command::Note(3, "buy milk".to_string()),
+            Command::Note(4, String::new()),
+            Command::Query {
+                filter: NoteFilter::default(),
+                sort: QuerySort::Id,
+                offset: 0,
+                limit: 10,
+            },
+            Command::Query {
+                filter: NoteFilter {
+                    contains: Some("milk and bread".to_string()),
+                    tag: Some("errand".to_string()),
+                    since_ms: Some(1_700_000_000_000),
+                },
+                sort: QuerySort::Ttl,
+                offset: 20,
+                limit: 10,
+            },
+            Command::QueryResult(QueryResult {
+                notes: vec![NoteDescription {
+                    id: 3,
+                    body: "buy milk".to_string(),
+                    ..Default::default()
+                }],
+                total: 7,
+            }),
+            Command::Append(3, "and bread".to_string()),
+            Command::Append(3, String::new()),
+            Command::Appended(3, 18),
+            Command::Auth("s3cret token".to_string()),
+            Command::Authenticated(Role::Reader),
+            Command::Authenticated(Role::Admin),
+            Command::CreateWithTtl(Duration::from_millis(1500), "short lived".to_string()),
+            Command::Transfer(3, 8),
+            Command::Search("Milk".to_string()),
+            Command::Search(String::new()),
+            Command::Count,
+            Command::CountResult(0),
+            Command::CountResult(42),
+            Command::ListPage {
+                offset: 100,
+                limit: 50,
+            },
+            Command::ListPageResult {
+                notes: vec![NoteSummary {
+                    id: 100,
+                    age_secs: 2,
+                    body: "two \"quoted\" 3#x".to_string(),
+                }],
+                more: true,
+            },
+            Command::ListPageResult {
+                notes: Vec::new(),
+                more: false,
+            },
+            Command::CompareAndUpdate {
+                id: 3,
+                expected_body: "buy milk".to_string(),
+                new_body: "buy oat milk".to_string(),
+            },
+            Command::CompareAndUpdate {
+                id: 3,
+                expected_body: String::new(),
+                new_body: "two words".to_string(),
+            },
+            Command::CompareAndUpdateResult {
+                id: 3,
+                updated: true,
+            },
+            Command::Ping(u64::MAX),
+            Command::Subscribe {
+                contains: Some("milk".to_string()),
+                tag: None,
+            },
+            Command::Subscribe {
+                contains: None,
+                tag: Some("errand".to_string()),
+            },
+            Command::Unsubscribe,
+            Command::Notify {
+                seq: 7,
+                id: 3,
+                body: "buy milk".to_string(),
+            },
+            Command::Increment(3, -2),
+            Command::Counter(3, i64::MIN),
+            Command::Pong(42),
+            Command::Describe(3),
+            Command::Description(NoteDescription {
+                id: 3,
+                body: "buy milk".to_string(),
+                created_at_ms: 1_700_000_000_000,
+                expires_in_ms: 500,
+                tags: BTreeSet::from(["errand".to_string()]),
+                owner: Some(7),
+                metadata: BTreeMap::from([("source".to_string(), "email".to_string())]),
+                hash: crate::content_hash("buy milk"),
+            }),
+            Command::GetByHash(crate::content_hash("buy milk")),
+            Command::GetMany(vec![3, 0, 42]),
+            Command::GetMany(Vec::new()),
+            Command::Closing(CloseReason::IdleTimeout),
+            Command::Closing(CloseReason::ClientQuit),
+            Command::Closing(CloseReason::ServerShutdown),
+            Command::Closing(CloseReason::Kicked),
+            Command::Batch(NoteBatch {
+                found: BTreeMap::from([(0, "buy milk".to_string()), (3, "call back".to_string())]),
+                missing: vec![42],
+            }),
+            Command::CreateWithMetadata {
+                body: "call back".to_string(),
+                metadata: BTreeMap::from([
+                    ("priority".to_string(), "3".to_string()),
+                    ("source".to_string(), "email".to_string()),
+                ]),
+            },
+            Command::Read,
+            Command::Quit,
+        ]
+    }
+
+    #[test]
+    fn binary_round_trip() -> Result<()> {
+        for command in all_commands() {
+            let bytes = crate::codec::encode_frame(&Frame(command.clone()));
+            let mut src = Cursor::new(&bytes[..]);
+            Frame::check(&mut src)?;
+            assert_eq!(src.position() as usize, bytes.len(), "{command}");
+            src.set_position(0);
+            assert_eq!(Frame::parse(&mut src)?, Frame(command));
+        }
+        Ok(())
+    }
+
+    fn list_round_trip(bodies: &[String]) -> Result<()> {
+        let notes: Vec<_> = bodies
+            .iter()
+            .enumerate()
+            .map(|(id, body)| NoteSummary {
+                id: id as NoteID * 10,
+                age_secs: id as u64,
+                body: body.clone(),
+            })
+            .collect();
+        let bytes = crate::codec::encode_frame(&Frame(Command::List(notes.clone())));
+        let mut src = Cursor::new(&bytes[..]);
+        Frame::check(&mut src)?;
+        assert_eq!(src.position() as usize, bytes.len(), "{bodies:?}");
+        src.set_position(0);
+        assert_eq!(Frame::parse(&mut src)?, Frame(Command::List(notes)));
+        Ok(())
+    }
+
+    #[test]
+    fn list_body_that_looks_like_a_length_round_trips() -> Result<()> {
+        list_round_trip(&["10#notactually".to_string()])
+    }
+
+    #[test]
+    fn list_round_trips_adversarial_bodies() -> Result<()> {
+        let fixed = [
+            "",
+            "#",
+            "##",
+            "0",
+            "0#",
+            "#5",
+            "5#hello",
+            "1234567890",
+            "3#abc2#",
+            "é",
+            "日本語#3",
+            "🦀🦀",
+            "2#é",
+            "multi\nline",
+        ];
+        list_round_trip(&fixed.map(String::from))?;
+        // Random lists over an alphabet of structure characters and multi-byte ones.
+        let alphabet = ['#', '0', '1', '5', '9', 'a', ' ', '\n', 'é', '日', '🦀'];
+        let mut seed = 7u32;
+        let mut next = |bound: usize| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 16) as usize % bound
+        };
+        for _ in 0..500 {
+            let bodies: Vec<String> = (0..next(5))
+                .map(|_| {
+                    (0..next(12))
+                        .map(|_| alphabet[next(alphabet.len())])
+                        .collect()
+                })
+                .collect();
+            list_round_trip(&bodies)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decode_strings_rejects_bad_lengths() {
+        assert_eq!(decode_strings("2#é1#x").unwrap(), ["é", "x"]);
+        for encoded in ["5#abc", "3#abc7", "#abc", "1#é", "a#b", "-1#"] {
+            assert!(decode_strings(encoded).is_err(), "{encoded}");
+        }
+    }
+
+    #[test]
+    fn json_round_trip() -> Result<()> {
+        for command in all_commands() {
+            let json = Frame(command.clone()).to_json()?;
+            assert_eq!(json.last(), Some(&b'\n'));
+            let Frame(parsed) = Frame::from_json(&json[..json.len() - 1])?;
+            assert_eq!(parsed, command);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn json_shape() -> Result<()> {
+        let json = Frame(Command::Create("hi".to_string())).to_json()?;
+        assert_eq!(json, b"{\"cmd\":\"create\",\"body\":\"hi\"}\n");
+        let Frame(command) = Frame::from_json(br#"{"cmd":"read"}"#)?;
+        assert!(matches!(command, Command::Read));
+        Ok(())
+    }
+
+    #[test]
+    fn filter_combines_every_field() {
+        let created_at = UNIX_EPOCH + Duration::from_millis(5_000);
+        let note = Note::builder()
+            .body("Buy milk")
+            .tag("errand")
+            .created_at(created_at)
+            .build();
+        assert!(NoteFilter::default().matches(&note));
+        let filter = NoteFilter {
+            contains: Some("milk".to_string()),
+            tag: Some("errand".to_string()),
+            since_ms: Some(5_000),
+        };
+        assert!(filter.matches(&note));
+        let later = NoteFilter {
+            since_ms: Some(5_001),
+            ..filter.clone()
+        };
+        assert!(!later.matches(&note));
+        let other_tag = NoteFilter {
+            tag: Some("work".to_string()),
+            ..filter
+        };
+        assert!(!other_tag.matches(&note));
+    }
+}
@@ -0,0 +1,106 @@
+use color_eyre::eyre::{anyhow, Result};
+use common::{
+    protocol::{Command, Role},
+    ClientID,
+};
+use std::{collections::HashMap, fs, path::Path};
+
+/// Tokens clients authenticate with, each granting a [`Role`].
+#[derive(Debug, Clone, Default)]
+pub struct AuthTokens {
+    roles: HashMap<String, Role>,
+}
+
+impl AuthTokens {
+    /// Load tokens from a file of `token = role` lines; blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn insert(&mut self, token: impl Into<String>, role: Role) {
+        self.roles.insert(token.into(), role);
+    }
+
+    pub fn role(&self, token: &str) -> Option<Role> {
+        self.roles.get(token).copied()
+    }
+}
+
+impl std::str::FromStr for AuthTokens {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tokens = Self::default();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (token, role) = line
+                .split_once('=')
+                .ok_or(anyhow!("line {}: expected `token = role`", number + 1))?;
+            let role = role
+                .trim()
+                .parse()
+                .map_err(|e| anyhow!("line {}: {e}", number + 1))?;
+            tokens.insert(token.trim(), role);
+        }
+        Ok(tokens)
+    }
+}
+
+/// The role `client` needs to send `command`, or `None` if it may be sent
+/// before authenticating. Changing, deleting or transferring a note someone
+/// else owns also needs [`Role::Admin`], which is checked against the note itself.
+pub fn required_role(command: &Command, client: ClientID) -> Option<Role> {
+    match command {
+        Command::Hello { .. }
+        | Command::Auth(_)
+        | Command::Ping(_)
+        | Command::Pong(_)
+        | Command::Quit => None,
+        // Disconnecting another client kicks it.
+        Command::Disconnect(target) if *target != client => Some(Role::Admin),
+        Command::Disconnect(_) => None,
+        Command::Config => Some(Role::Admin),
+        Command::Create(_)
+        | Command::CreateWithMetadata { .. }
+        | Command::CreateWithTtl(..)
+        | Command::CreateFromTemplate { .. }
+        | Command::Swap(..)
+        | Command::Delete(_)
+        | Command::CompareAndUpdate { .. }
+        | Command::Increment(..)
+        | Command::Append(..)
+        | Command::Transfer(..) => Some(Role::Writer),
+        _ => Some(Role::Reader),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_parse_with_roles() -> Result<()> {
+        let tokens: AuthTokens = "# ops\nalice = admin\n\nbot=reader\n".parse()?;
+        assert_eq!(tokens.role("alice"), Some(Role::Admin));
+        assert_eq!(tokens.role("bot"), Some(Role::Reader));
+        assert_eq!(tokens.role("mallory"), None);
+        assert!("bob = root".parse::<AuthTokens>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn kicking_needs_admin() {
+        assert_eq!(required_role(&Command::Disconnect(2), 2), None);
+        assert_eq!(required_role(&Command::Disconnect(3), 2), Some(Role::Admin));
+        assert_eq!(required_role(&Command::Config, 2), Some(Role::Admin));
+        assert_eq!(required_role(&Command::Read, 2), Some(Role::Reader));
+        assert_eq!(
+            required_role(&Command::Create("x".to_string()), 2),
+            Some(Role::Writer)
+        );
+    }
+}
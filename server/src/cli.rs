@@ -1,10 +1,179 @@
 use clap::Parser;
-use common::DEFAULT_PORT;
+use color_eyre::eyre::Result;
+use common::{protocol::WireFormat, DEFAULT_PORT, MAX_NOTE_SIZE};
+use server::{
+    auth::AuthTokens,
+    policy::{ContentPolicy, PolicyMode},
+    settings,
+    templates::Templates,
+    ServerConfig,
+};
+use std::{path::PathBuf, time::Duration};
 
 #[derive(Debug, Parser)]
 pub struct Args {
     #[arg(short, long, default_value = DEFAULT_PORT)]
     pub port: u16,
+    /// Serve on the socket passed by systemd socket activation instead of binding --port
+    #[arg(long)]
+    pub systemd: bool,
+    /// Maximum summed size of all note bodies, in bytes
+    #[arg(long)]
+    pub max_bytes: Option<usize>,
+    /// Evict the oldest notes instead of rejecting creates over --max-bytes
+    #[arg(long, requires = "max_bytes")]
+    pub evict_oldest: bool,
+    /// Longest note body, in bytes, that may be created or appended to
+    #[arg(long, default_value_t = MAX_NOTE_SIZE)]
+    pub max_note_len: usize,
+    /// Speak newline-delimited JSON instead of the binary protocol
+    #[arg(long)]
+    pub json_protocol: bool,
+    /// Send note bodies with a byte length instead of a `\r\n` terminator, so they may hold line endings
+    #[arg(long, conflicts_with = "json_protocol")]
+    pub length_prefixed: bool,
+    /// File of `name = body` note templates, with `{placeholder}`s
+    #[arg(long)]
+    pub templates: Option<PathBuf>,
+    /// File of `key = value` settings applied over these flags, re-read on SIGHUP
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Encrypt connections with TLS, using --cert and --key
+    #[arg(long, requires_all = ["cert", "key"])]
+    pub tls: bool,
+    /// PEM certificate chain to present to clients
+    #[arg(long, requires = "tls")]
+    pub cert: Option<PathBuf>,
+    /// PEM private key for --cert
+    #[arg(long, requires = "tls")]
+    pub key: Option<PathBuf>,
+    /// File the notes are saved to and restored from on startup
+    #[arg(long)]
+    pub persist: Option<PathBuf>,
+    /// SQLite database to keep the notes in instead of memory
+    #[arg(long, conflicts_with = "persist")]
+    pub sqlite: Option<PathBuf>,
+    /// Keep serving from memory if the --persist file can't be written
+    #[arg(long, requires = "persist")]
+    pub persistence_optional: bool,
+    /// Gzip the --persist file, as is done anyway for names ending in .gz
+    #[arg(long, requires = "persist")]
+    pub compress_storage: bool,
+    /// File of newline-delimited note bodies to create before accepting connections
+    #[arg(long)]
+    pub seed: Option<PathBuf>,
+    /// Frames a connection may pipeline before the server stops reading from it
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_in_flight: u64,
+    /// Warn a note's owner this many seconds before the note expires
+    #[arg(long)]
+    pub expiry_warning: Option<u64>,
+    /// Seconds a client may stay silent after connecting before it is disconnected
+    #[arg(long, default_value_t = 30)]
+    pub handshake_timeout: u64,
+    /// Reply to every create with the note body as stored
+    #[arg(long)]
+    pub echo_creates: bool,
+    /// Log commands and answer them without storing, swapping or removing notes
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Store identical bodies once, answering repeat creates with the existing note's id
+    #[arg(long)]
+    pub dedupe: bool,
+    /// Frames per second each connection may send before reading from it is slowed down
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_frame_per_sec: Option<u32>,
+    /// Log a warning when one client creates more than this many notes within --create-rate-window
+    #[arg(long)]
+    pub create_rate_warning: Option<usize>,
+    /// Seconds over which --create-rate-warning counts creates
+    #[arg(long, default_value_t = 10, requires = "create_rate_warning")]
+    pub create_rate_window: u64,
+    /// Ping connections idle for this many seconds
+    #[arg(long)]
+    pub keepalive_interval: Option<u64>,
+    /// Seconds to wait for the pong to a keepalive ping before dropping the connection
+    #[arg(long, default_value_t = 10, requires = "keepalive_interval")]
+    pub keepalive_timeout: u64,
+    /// Expire notes that have not been read for the note timeout, instead of counting from creation
+    #[arg(long)]
+    pub idle_expiry: bool,
+    /// Only count reads of single notes under --idle-expiry, not list reads
+    #[arg(long, requires = "idle_expiry")]
+    pub idle_expiry_ignore_lists: bool,
+    /// Only store notes whose body matches this regex
+    #[arg(long)]
+    pub content_regex: Option<String>,
+    /// Reject notes matching --content-regex instead of those that don't
+    #[arg(long, requires = "content_regex")]
+    pub content_regex_forbid: bool,
+    /// File of `token = role` lines (reader, writer or admin); clients must then
+    /// authenticate with one of the tokens
+    #[arg(long)]
+    pub auth_tokens: Option<PathBuf>,
+    /// Worker threads for the runtime, one per CPU unless given
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub threads: Option<u64>,
+    /// Serve runtime traces to `tokio-console` (TOKIO_CONSOLE_BIND overrides the address)
+    #[arg(long)]
+    pub profile: bool,
+}
+
+impl Args {
+    pub fn config(&self) -> Result<ServerConfig> {
+        let templates = match &self.templates {
+            Some(path) => Templates::load(path)?,
+            None => Templates::default(),
+        };
+        let content_policy = match &self.content_regex {
+            Some(pattern) => Some(ContentPolicy::new(
+                pattern,
+                if self.content_regex_forbid {
+                    PolicyMode::Forbid
+                } else {
+                    PolicyMode::Require
+                },
+            )?),
+            None => None,
+        };
+        let mut config = ServerConfig {
+            max_bytes: self.max_bytes,
+            evict_oldest: self.evict_oldest,
+            max_note_len: Some(self.max_note_len),
+            wire_format: if self.json_protocol {
+                WireFormat::Json
+            } else if self.length_prefixed {
+                WireFormat::LengthPrefixed
+            } else {
+                WireFormat::Binary
+            },
+            templates,
+            max_in_flight: self.max_in_flight as usize,
+            expiry_warning: self.expiry_warning.map(Duration::from_secs),
+            handshake_timeout: Some(Duration::from_secs(self.handshake_timeout)),
+            echo_creates: self.echo_creates,
+            dry_run: self.dry_run,
+            dedupe: self.dedupe,
+            max_frames_per_sec: self.max_frame_per_sec,
+            create_rate_warning: self.create_rate_warning,
+            create_rate_window: Duration::from_secs(self.create_rate_window),
+            keepalive_interval: self.keepalive_interval.map(Duration::from_secs),
+            keepalive_timeout: Duration::from_secs(self.keepalive_timeout),
+            idle_expiry: self.idle_expiry,
+            idle_expiry_counts_lists: !self.idle_expiry_ignore_lists,
+            content_policy,
+            auth_tokens: self
+                .auth_tokens
+                .as_ref()
+                .map(AuthTokens::load)
+                .transpose()?,
+            ..Default::default()
+        };
+        if let Some(path) = &self.config {
+            settings::load(&mut config, path)?;
+        }
+        Ok(config)
+    }
 }
 pub fn parse() -> Args {
     Args::parse()
@@ -0,0 +1,28 @@
+use common::{ClientID, Note, NoteID};
+
+/// Buffered events per subscriber; slower subscribers miss the oldest ones.
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Changes to the note store, delivered to [`NotesServer::subscribe`](crate::NotesServer::subscribe) receivers.
+#[derive(Debug, Clone)]
+pub enum NoteEvent {
+    /// A note was stored, with its sequence number: creates are numbered in
+    /// the order they are broadcast, so a gap means events were missed.
+    Created(Note, u64),
+    /// The note expires within the configured warning period.
+    Expiring(Note),
+    /// Removed by cleanup once its timeout elapsed.
+    Expired(NoteID),
+    /// Removed before expiring, e.g. evicted to stay within the byte budget.
+    Deleted(NoteID),
+    /// The bodies of the two notes were exchanged.
+    Swapped(NoteID, NoteID),
+    /// The note was changed in place, e.g. by an increment or a transfer.
+    Updated(NoteID),
+    /// A client went over [`ServerConfig::create_rate_warning`](crate::ServerConfig::create_rate_warning)
+    /// creates within the window. Sent once each time the rate is crossed.
+    CreateRateExceeded {
+        client: Option<ClientID>,
+        creates: usize,
+    },
+}
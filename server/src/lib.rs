@@ -1,22 +1,331 @@
+use auth::AuthTokens;
 use color_eyre::eyre::{anyhow, Result};
 use common::{
-    protocol::{Command, Frame},
-    ClientID, Connection, Note, NoteID, NOTE_TIMEOUT,
+    protocol::{
+        CloseReason, Command, Compression, EffectiveConfig, Frame, NoteBatch, NoteDescription,
+        NoteFilter, NoteSummary, QueryResult, QuerySort, Role, Stats, WireFormat, PROTOCOL_VERSION,
+    },
+    ClientID, Connection, ConnectionReader, ConnectionWriter, Note, NoteID, NoteQuery,
+    MAX_NOTE_SIZE, NOTE_TIMEOUT,
 };
+use events::{NoteEvent, EVENT_CAPACITY};
+use metrics::{Metrics, MetricsSnapshot};
+use policy::{ContentPolicy, PolicyMode};
+use state::ConnectionState;
 use std::{
-    collections::{BTreeMap, HashMap},
-    sync::mpsc::{self, Receiver, Sender},
-    sync::Arc,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
-use tokio::{net::TcpStream, sync::Mutex as AsyncMutex, task::JoinHandle, time::Duration};
+use store::{MemoryStore, NoteStore, SharedStore};
+use templates::Templates;
+use throttle::TokenBucket;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast,
+        mpsc::{self, UnboundedReceiver as Receiver, UnboundedSender as Sender},
+        watch, Mutex as AsyncMutex,
+    },
+    task::{AbortHandle, JoinHandle, JoinSet},
+    time::{Duration, Instant},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::Instrument;
+
+pub mod auth;
+pub mod events;
+pub mod logging;
+pub mod metrics;
+pub mod persistence;
+pub mod policy;
+pub mod profiling;
+pub mod settings;
+pub mod sqlite;
+pub mod state;
+pub mod store;
+pub mod systemd;
+pub mod templates;
+pub mod throttle;
+pub mod tls;
+
+/// Settings a [`NotesServer`] is constructed with.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub note_timeout: Duration,
+    /// Budget for the summed length of all note bodies.
+    pub max_bytes: Option<usize>,
+    /// Evict the oldest notes instead of rejecting creates that exceed `max_bytes`.
+    pub evict_oldest: bool,
+    /// Longest body, in bytes, a note may be created with or grow to. Also
+    /// bounds the frames clients may send; see [`ServerConfig::max_client_frame`].
+    pub max_note_len: Option<usize>,
+    pub wire_format: WireFormat,
+    /// Bodies available to [`Command::CreateFromTemplate`].
+    pub templates: Templates,
+    /// Frames a connection may have read but not yet handled before the
+    /// server stops reading from it. Must be at least 1.
+    pub max_in_flight: usize,
+    /// How long before expiry to warn a note's owner with [`Command::Expiring`].
+    pub expiry_warning: Option<Duration>,
+    /// How long a client may stay silent after receiving its id before it is disconnected.
+    pub handshake_timeout: Option<Duration>,
+    /// Answer every `Create` with the body as stored, or an error.
+    pub echo_creates: bool,
+    /// Answer commands as usual but leave the stored notes untouched.
+    /// Creates are always echoed, prefixed with [`DRY_RUN_MARKER`].
+    pub dry_run: bool,
+    /// Frames per second each connection may send; reading from faster clients
+    /// is delayed rather than failed, so short bursts still get through.
+    pub max_frames_per_sec: Option<u32>,
+    /// Warn when one client creates more than this many notes within `create_rate_window`.
+    pub create_rate_warning: Option<usize>,
+    pub create_rate_window: Duration,
+    /// Answer a create whose body matches the content hash of a note the
+    /// same client owns with that note's id instead of storing a copy.
+    /// Other clients' notes are never matched, since the creator couldn't
+    /// delete or change them.
+    pub dedupe: bool,
+    /// Ping connections that have sent nothing for this long.
+    pub keepalive_interval: Option<Duration>,
+    /// How long a keepalive ping may go unanswered before the connection is dropped.
+    pub keepalive_timeout: Duration,
+    /// Count `note_timeout` from a note's last read instead of its creation,
+    /// so notes in use stay around and unused ones expire.
+    pub idle_expiry: bool,
+    /// Whether `Read`, `ReadPage` and `ListPage` count as reading every note they return under `idle_expiry`.
+    pub idle_expiry_counts_lists: bool,
+    /// Pattern every created note body is checked against.
+    pub content_policy: Option<ContentPolicy>,
+    /// Tokens clients must [`Command::Auth`] with before anything beyond the
+    /// handshake; the role a token grants limits the commands it may send.
+    /// Without tokens every client is a [`Role::Writer`], and nobody is an admin.
+    pub auth_tokens: Option<AuthTokens>,
+}
+
+impl ServerConfig {
+    /// Largest frame a client may send: room for the two bodies of a
+    /// compare-and-update at `max_note_len`, plus [`FRAME_OVERHEAD`].
+    /// Connections sending more are closed before the frame is buffered whole.
+    pub fn max_client_frame(&self) -> Option<usize> {
+        self.max_note_len
+            .map(|max| max.saturating_mul(2).saturating_add(FRAME_OVERHEAD))
+    }
+
+    /// Fail if a body of `len` bytes is longer than `max_note_len` allows.
+    fn check_note_len(&self, len: usize) -> Result<()> {
+        match self.max_note_len {
+            Some(max) if len > max => Err(anyhow!("note would exceed the {max} byte length limit")),
+            _ => Ok(()),
+        }
+    }
+
+    /// The settings reported to clients asking for [`Command::Config`].
+    pub fn effective(&self) -> EffectiveConfig {
+        let millis = |duration: Duration| duration.as_millis() as u64;
+        EffectiveConfig {
+            note_timeout_ms: millis(self.note_timeout),
+            max_bytes: self.max_bytes.map(|max| max as u64),
+            evict_oldest: self.evict_oldest,
+            max_note_len: self.max_note_len.map(|max| max as u64),
+            json_protocol: self.wire_format == WireFormat::Json,
+            length_prefixed: self.wire_format == WireFormat::LengthPrefixed,
+            templates: self.templates.names(),
+            max_in_flight: self.max_in_flight as u64,
+            expiry_warning_ms: self.expiry_warning.map(millis),
+            handshake_timeout_ms: self.handshake_timeout.map(millis),
+            echo_creates: self.echo_creates,
+            dry_run: self.dry_run,
+            dedupe: self.dedupe,
+            max_frames_per_sec: self.max_frames_per_sec,
+            create_rate_warning: self.create_rate_warning.map(|creates| creates as u64),
+            create_rate_window_ms: millis(self.create_rate_window),
+            keepalive_interval_ms: self.keepalive_interval.map(millis),
+            keepalive_timeout_ms: millis(self.keepalive_timeout),
+            idle_expiry: self.idle_expiry,
+            idle_expiry_counts_lists: self.idle_expiry_counts_lists,
+            content_regex: self
+                .content_policy
+                .as_ref()
+                .map(|policy| policy.pattern().to_owned()),
+            content_regex_forbid: self
+                .content_policy
+                .as_ref()
+                .is_some_and(|policy| policy.mode() == PolicyMode::Forbid),
+        }
+    }
+}
+
+/// Bytes a client frame may hold besides note bodies, for ids, lengths,
+/// metadata and template variables.
+pub const FRAME_OVERHEAD: usize = 4096;
+
+/// Prefix of the bodies echoed back for creates in [`ServerConfig::dry_run`] mode.
+pub const DRY_RUN_MARKER: &str = "[dry-run] ";
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            note_timeout: NOTE_TIMEOUT,
+            max_bytes: None,
+            evict_oldest: false,
+            max_note_len: Some(MAX_NOTE_SIZE),
+            wire_format: WireFormat::Binary,
+            templates: Templates::default(),
+            max_in_flight: 32,
+            expiry_warning: None,
+            handshake_timeout: Some(Duration::from_secs(30)),
+            echo_creates: false,
+            dry_run: false,
+            max_frames_per_sec: None,
+            create_rate_warning: None,
+            create_rate_window: Duration::from_secs(10),
+            dedupe: false,
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(10),
+            idle_expiry: false,
+            idle_expiry_counts_lists: true,
+            content_policy: None,
+            auth_tokens: None,
+        }
+    }
+}
+
+/// A running connection handler.
+#[derive(Debug)]
+struct ClientHandler {
+    task: JoinHandle<Result<()>>,
+    /// Set to have the handler close its connection with [`CloseReason::Kicked`].
+    kick: watch::Sender<bool>,
+}
+
+/// Handlers by the client they serve, until they are reaped.
+type ClientHandlers = Arc<AsyncMutex<HashMap<ClientID, ClientHandler>>>;
 
 pub struct NotesServer {
-    notes: Arc<AsyncMutex<BTreeMap<NoteID, Note>>>,
-    cleanup_sender: Sender<NoteID>,
+    /// Current settings; handlers pick up [`reload`](Self::reload)s through it.
+    config: watch::Sender<Arc<ServerConfig>>,
+    notes: SharedStore,
+    /// Id for the next created note. Only ever grows, so an id is never
+    /// recycled while a stale cleanup message for it may still be queued.
+    next_id: Arc<AtomicU64>,
+    /// Sequence number of the next [`NoteEvent::Created`], taken under the
+    /// notes lock so the numbers follow broadcast order.
+    next_seq: Arc<AtomicU64>,
+    used_bytes: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
+    events: broadcast::Sender<NoteEvent>,
+    cleanup_sender: Sender<(NoteID, u64)>,
     cleanup_handler: JoinHandle<()>,
     disconnect_sender: Sender<ClientID>,
     disconnect_handler: JoinHandle<()>,
-    client_handlers: Arc<AsyncMutex<HashMap<ClientID, JoinHandle<Result<()>>>>>,
+    client_handlers: ClientHandlers,
+    /// Id for the next connection. Unlike the handler count it never shrinks,
+    /// so a reconnecting client can't take over a live client's id.
+    next_client_id: AtomicU64,
+    /// Set by [`close`](Self::close); handlers check it before taking new work.
+    closed: Arc<AtomicBool>,
+    /// Set by [`close`](Self::close) to have the handlers say goodbye to their clients.
+    shutdown: watch::Sender<bool>,
+    /// Set once [`persist_to`](Self::persist_to) is called.
+    persistence: Option<Persistence>,
+    /// Set by [`serve_tls`](Self::serve_tls) to encrypt every later connection.
+    tls: Option<TlsAcceptor>,
+    started_at: Instant,
+}
+
+/// What was left when a [`NotesServer`] shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Notes still stored.
+    pub notes: usize,
+    /// Clients connected when the shutdown began.
+    pub clients: usize,
+    /// Handlers that said goodbye to their client in time.
+    pub finished: usize,
+    /// Handlers cut off for not finishing within the grace period.
+    pub aborted: usize,
+    pub uptime: Duration,
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Shut down after {:.1?} with {} notes and {} clients ({} finished, {} aborted)",
+            self.uptime, self.notes, self.clients, self.finished, self.aborted
+        )
+    }
+}
+
+/// How the handlers running at shutdown ended.
+#[derive(Debug, Default)]
+struct Drained {
+    finished: usize,
+    aborted: usize,
+}
+
+/// Grace period for handlers to finish up when the server shuts down, after
+/// which the stragglers are aborted.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Applies new settings to a running [`NotesServer`]; see [`NotesServer::reloader`].
+#[derive(Debug, Clone)]
+pub struct Reloader {
+    config: watch::Sender<Arc<ServerConfig>>,
+}
+
+impl Reloader {
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.borrow().clone()
+    }
+
+    /// Apply `config` to running handlers and future connections without a restart.
+    /// Settings fixed at startup keep their current value and are returned, after
+    /// being logged, when `config` tried to change them.
+    pub fn reload(&self, mut config: ServerConfig) -> Vec<&'static str> {
+        let current = self.config();
+        let mut ignored = Vec::new();
+        if config.note_timeout != current.note_timeout {
+            config.note_timeout = current.note_timeout;
+            ignored.push("note_timeout");
+        }
+        if config.expiry_warning != current.expiry_warning {
+            config.expiry_warning = current.expiry_warning;
+            ignored.push("expiry_warning");
+        }
+        if config.idle_expiry != current.idle_expiry {
+            config.idle_expiry = current.idle_expiry;
+            ignored.push("idle_expiry");
+        }
+        if config.wire_format != current.wire_format {
+            config.wire_format = current.wire_format;
+            ignored.push("wire_format");
+        }
+        for setting in &ignored {
+            tracing::warn!(
+                setting,
+                "ignoring reloaded setting: it only changes on restart"
+            );
+        }
+        self.config.send_replace(Arc::new(config));
+        ignored
+    }
+}
+
+/// The file a [`NotesServer`] keeps its notes in, and the task rewriting it after changes.
+struct Persistence {
+    path: PathBuf,
+    optional: bool,
+    gzip: bool,
+    task: JoinHandle<()>,
 }
 
 impl Default for NotesServer {
@@ -28,204 +337,1774 @@ impl Default for NotesServer {
 impl NotesServer {
     /// Create a new NotesServer.
     pub fn new(cleanup_timeout_override: Option<Duration>) -> Self {
-        let cleanup_timeout = cleanup_timeout_override.unwrap_or(NOTE_TIMEOUT);
-        let notes = Arc::new(AsyncMutex::new(BTreeMap::new()));
-        let (cleanup_sender, cleanup_receiver) = mpsc::channel::<NoteID>();
-        let cleanup_handler = tokio::spawn({
-            let notes = notes.clone();
-            Self::cleanup(cleanup_receiver, notes, cleanup_timeout)
-        });
+        Self::with_config(ServerConfig {
+            note_timeout: cleanup_timeout_override.unwrap_or(NOTE_TIMEOUT),
+            ..Default::default()
+        })
+    }
+
+    pub fn with_config(config: ServerConfig) -> Self {
+        Self::with_store(config, MemoryStore::default())
+    }
+
+    /// Like [`with_config`](Self::with_config), keeping notes in `store`
+    /// instead of memory.
+    pub fn with_store(config: ServerConfig, store: impl NoteStore + 'static) -> Self {
+        let notes: SharedStore = Arc::new(AsyncMutex::new(Box::new(store)));
+        let used_bytes = Arc::new(AtomicUsize::new(0));
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let metrics = Arc::new(Metrics::default());
+        let (cleanup_sender, cleanup_receiver) = mpsc::unbounded_channel::<(NoteID, u64)>();
+        let cleanup_handler = tokio::spawn(
+            Self::cleanup(
+                cleanup_receiver,
+                Expiry {
+                    notes: notes.clone(),
+                    used_bytes: used_bytes.clone(),
+                    events: events.clone(),
+                    metrics: metrics.clone(),
+                    timeout: config.note_timeout,
+                    warning: config.expiry_warning,
+                    idle: config.idle_expiry,
+                },
+            )
+            .instrument(tracing::info_span!("cleanup")),
+        );
         let client_handlers = Arc::new(AsyncMutex::new(HashMap::new()));
-        let (disconnect_sender, disconnect_receiver) = mpsc::channel::<ClientID>();
+        let (disconnect_sender, disconnect_receiver) = mpsc::unbounded_channel::<ClientID>();
         let disconnect_handler = tokio::spawn({
             let client_handlers = client_handlers.clone();
             Self::handle_disconnects(disconnect_receiver, client_handlers)
+                .instrument(tracing::info_span!("disconnects"))
         });
         Self {
+            config: watch::Sender::new(Arc::new(config)),
             notes,
+            next_id: Arc::new(AtomicU64::new(0)),
+            next_seq: Arc::new(AtomicU64::new(0)),
+            used_bytes,
+            metrics,
+            events,
             cleanup_sender,
             cleanup_handler,
             disconnect_sender,
             disconnect_handler,
             client_handlers,
+            next_client_id: AtomicU64::new(0),
+            closed: Arc::new(AtomicBool::new(false)),
+            shutdown: watch::Sender::new(false),
+            persistence: None,
+            tls: None,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Give every scheduled note a timer of its own, so each expires at its
+    /// deadline however the schedules arrive. A note rescheduled by a touch or
+    /// read replaces its timer rather than adding one.
+    async fn cleanup(mut recv: Receiver<(NoteID, u64)>, expiry: Expiry) {
+        // Dropped with this task when the server closes, aborting pending timers.
+        let mut timers = JoinSet::new();
+        let mut scheduled: HashMap<NoteID, AbortHandle> = HashMap::new();
+        loop {
+            tokio::select! {
+                schedule = recv.recv() => {
+                    let Some((id, generation)) = schedule else {
+                        break;
+                    };
+                    tracing::debug!(note = id, generation, "scheduling expiry");
+                    if let Some(stale) = scheduled.remove(&id) {
+                        stale.abort();
+                    }
+                    let timer = timers.spawn(expiry.clone().run(id, generation).in_current_span());
+                    scheduled.insert(id, timer);
+                }
+                // Reap finished timers so the set doesn't grow with every note ever made.
+                Some(finished) = timers.join_next_with_id(), if !timers.is_empty() => {
+                    match finished {
+                        Ok((task, id)) => {
+                            if scheduled.get(&id).is_some_and(|timer| timer.id() == task) {
+                                scheduled.remove(&id);
+                            }
+                        }
+                        // Replaced timers were dropped from the map when aborted.
+                        Err(e) if e.is_cancelled() => {}
+                        Err(e) => scheduled.retain(|_, timer| timer.id() != e.id()),
+                    }
+                }
+            }
+            expiry.metrics.set_expiry_timers(timers.len());
         }
+        tracing::info!("cleanup task finished");
     }
 
-    async fn cleanup(
-        recv: Receiver<NoteID>,
-        notes: Arc<AsyncMutex<BTreeMap<NoteID, Note>>>,
-        cleanup_timeout: Duration,
+    /// Snapshot the notes to `path` after every change until the server closes.
+    async fn persist(
+        mut events: broadcast::Receiver<NoteEvent>,
+        notes: SharedStore,
+        path: PathBuf,
+        optional: bool,
+        gzip: bool,
     ) {
-        while let Ok(id) = recv.recv() {
-            let note = {
-                notes
-                    .lock()
-                    .await
-                    .get(&id)
-                    .expect("note must exist")
-                    .clone()
-            };
-            println!("[Cleanup] Received note: {:?}", note);
-            while note.elapsed() < cleanup_timeout {
-                let timeout = cleanup_timeout - note.elapsed();
-                println!("Sleeping for {:?}", timeout);
-                tokio::time::sleep(timeout).await;
+        loop {
+            match events.recv().await {
+                Ok(NoteEvent::Expiring(_) | NoteEvent::CreateRateExceeded { .. }) => continue,
+                // Missed events still mean something changed.
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
             }
-            {
-                let mut notes = notes.lock().await;
-                notes.remove(&id);
+            // Skip the snapshot rather than overwrite the file with nothing.
+            let contents = match notes.lock().await.all().await {
+                Ok(notes) => persistence::encode(&notes),
+                Err(e) => {
+                    tracing::error!("failed to read the notes: {e}");
+                    continue;
+                }
+            };
+            if let Err(e) = persistence::save(&path, &contents, gzip).await {
+                if optional {
+                    tracing::warn!("failed to write: {e}. Notes are now kept in memory only!");
+                    break;
+                }
+                tracing::error!("failed to write: {e}");
             }
         }
-        println!("Cleanup thread finished");
     }
 
-    async fn handle_disconnects(
-        recv: Receiver<ClientID>,
-        client_handlers: Arc<AsyncMutex<HashMap<ClientID, JoinHandle<Result<()>>>>>,
-    ) {
-        while let Ok(id) = recv.recv() {
+    async fn handle_disconnects(mut recv: Receiver<ClientID>, client_handlers: ClientHandlers) {
+        while let Some(id) = recv.recv().await {
             {
                 let mut client_handlers = client_handlers.lock().await;
                 client_handlers.remove(&id);
             }
         }
-        println!("Cleanup thread finished");
+        tracing::info!("disconnect task finished");
     }
 
-    pub async fn close(self) -> Result<()> {
-        drop(self.cleanup_sender);
+    /// Stop serving. Connected clients are sent a final
+    /// [`CloseReason::ServerShutdown`], and their handlers get
+    /// [`SHUTDOWN_GRACE`] to finish before being aborted. With
+    /// [`persist_to`](Self::persist_to), the notes are written out once more
+    /// and synced to disk before this returns, so nothing changed since the
+    /// last snapshot is lost.
+    pub async fn close(self) -> Result<ShutdownReport> {
+        // Taken out of the map so reaping and kicks aren't stuck behind the wait.
+        let handlers: Vec<ClientHandler> = std::mem::take(&mut *self.client_handlers.lock().await)
+            .into_values()
+            .filter(|handler| !handler.task.is_finished())
+            .collect();
+        self.shutdown.send_replace(true);
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_GRACE;
+        let mut outcome = Drained::default();
+        for mut handler in handlers {
+            // Handlers report their own errors; only finishing matters here.
+            match tokio::time::timeout_at(deadline, &mut handler.task).await {
+                Ok(_) => outcome.finished += 1,
+                Err(_) => {
+                    handler.task.abort();
+                    outcome.aborted += 1;
+                }
+            }
+        }
+        if outcome.aborted > 0 {
+            tracing::warn!(grace = ?SHUTDOWN_GRACE, "handlers still running, aborting them");
+        }
+        self.finish(outcome).await
+    }
+
+    /// Handlers still serving a client, as opposed to finished ones not yet reaped.
+    #[cfg(test)]
+    async fn connected_clients(&self) -> usize {
         let client_handlers = self.client_handlers.lock().await;
-        for (_, handle) in client_handlers.iter() {
+        client_handlers
+            .values()
+            .filter(|handler| !handler.task.is_finished())
+            .count()
+    }
+
+    /// Shut down after the handlers in `outcome` were drained, reporting what was left.
+    async fn finish(self, mut outcome: Drained) -> Result<ShutdownReport> {
+        self.closed.store(true, Ordering::SeqCst);
+        drop(self.cleanup_sender);
+        {
+            // Anything registered after the handlers were taken gets no grace.
+            let client_handlers = self.client_handlers.lock().await;
+            for handler in client_handlers.values() {
+                if !handler.task.is_finished() {
+                    handler.task.abort();
+                    outcome.aborted += 1;
+                }
+            }
+        }
+        let notes = self.notes.lock().await.count().await?;
+        if let Some(persistence) = self.persistence {
+            // A snapshot cut short is never renamed over the file, and the
+            // final one replaces it anyway.
+            persistence.task.abort();
+            let _ = persistence.task.await;
+            let contents = persistence::encode(&self.notes.lock().await.all().await?);
+            if let Err(e) = persistence::save(&persistence.path, &contents, persistence.gzip).await
+            {
+                if !persistence.optional {
+                    return Err(e.wrap_err(format!(
+                        "cannot write {} on close",
+                        persistence.path.display()
+                    )));
+                }
+                tracing::warn!(
+                    path = %persistence.path.display(),
+                    "failed to write on close: {e}"
+                );
+            }
+        }
+        // Handlers held by embedders keep the channels open, so the background
+        // tasks are stopped rather than waited on; pending expiries die with the server.
+        for (task, handle) in [
+            ("cleanup", self.cleanup_handler),
+            ("disconnect", self.disconnect_handler),
+        ] {
             handle.abort();
-            //todo tell client to disconnect
+            match handle.await {
+                Err(e) if !e.is_cancelled() => {
+                    return Err(anyhow!("failed to join {task} thread"));
+                }
+                _ => {}
+            }
         }
-        self.cleanup_handler
-            .await
-            .map_err(|_| anyhow!("failed to join cleanup thread"))?;
-        self.disconnect_handler
-            .await
-            .map_err(|_| anyhow!("failed to join disconnect thread"))?;
-        Ok(())
+        let report = ShutdownReport {
+            notes,
+            clients: outcome.finished + outcome.aborted,
+            finished: outcome.finished,
+            aborted: outcome.aborted,
+            uptime: self.started_at.elapsed(),
+        };
+        tracing::info!("{report}");
+        Ok(report)
+    }
+
+    /// Speak TLS with every connection handled from now on, instead of plaintext.
+    pub fn serve_tls(&mut self, acceptor: TlsAcceptor) {
+        self.tls = Some(acceptor);
     }
 
     pub async fn handle_connection(&mut self, socket: TcpStream) -> Result<()> {
-        let notes_handler = self.create_handler();
-        let connection = Connection::new(socket);
+        match self.tls.clone() {
+            // The TLS handshake happens on the handler's task, so a slow
+            // client can't hold up accepting the others.
+            Some(acceptor) => {
+                self.spawn_handler(async move { Ok(acceptor.accept(socket).await?) })
+                    .await
+            }
+            None => self.handle_stream(socket).await,
+        }
+    }
+
+    /// Serve a client over any byte stream, e.g. one end of a
+    /// [`tokio::io::duplex`] pipe.
+    pub async fn handle_stream<S>(&mut self, stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        self.spawn_handler(async move { Ok(stream) }).await
+    }
+
+    /// Start a handler serving the stream `stream` resolves to.
+    async fn spawn_handler<S>(
+        &mut self,
+        stream: impl Future<Output = Result<S>> + Send + 'static,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let mut notes_handler = self.create_handler();
+        let (kick, kicked) = watch::channel(false);
+        notes_handler.kicked = kicked;
+        let format = self.config.borrow().wire_format;
         {
             let mut client_handlers = self.client_handlers.lock().await;
-            let id = client_handlers.len() as ClientID;
-            let handle = tokio::spawn(notes_handler.run(connection, id as u64));
-            client_handlers.insert(id, handle);
+            let id = self.next_client_id.fetch_add(1, Ordering::SeqCst);
+            let handler = async move {
+                let connection = Connection::with_format(stream.await?, format);
+                notes_handler.run(connection, id).await
+            }
+            .instrument(tracing::info_span!("connection", client = id));
+            let task = tokio::spawn(handler);
+            client_handlers.insert(id, ClientHandler { task, kick });
         }
         Ok(())
     }
 
     pub fn create_handler(&mut self) -> NotesHandler {
-        NotesHandler::new(
-            self.notes.clone(),
-            self.cleanup_sender.clone(),
-            self.disconnect_sender.clone(),
-        )
+        NotesHandler {
+            config: self.config.subscribe(),
+            notes: self.notes.clone(),
+            next_id: self.next_id.clone(),
+            next_seq: self.next_seq.clone(),
+            used_bytes: self.used_bytes.clone(),
+            metrics: self.metrics.clone(),
+            events: self.events.clone(),
+            cleanup_sender: self.cleanup_sender.clone(),
+            disconnect_sender: self.disconnect_sender.clone(),
+            client_handlers: self.client_handlers.clone(),
+            // Only spawned handlers can be kicked.
+            kicked: watch::channel(false).1,
+            closed: self.closed.clone(),
+            shutdown: self.shutdown.subscribe(),
+            client_id: None,
+            recent_creates: VecDeque::new(),
+            state: ConnectionState::default(),
+            role: None,
+            subscription: None,
+            awaiting_pong: None,
+            inserted: None,
+        }
+    }
+
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.borrow().clone()
+    }
+
+    /// Apply `config` to running handlers and future connections without a restart.
+    /// See [`Reloader::reload`].
+    pub fn reload(&self, config: ServerConfig) -> Vec<&'static str> {
+        self.reloader().reload(config)
+    }
+
+    /// A handle to reload the settings with from another task, e.g. on
+    /// SIGHUP while [`run_until_shutdown`](Self::run_until_shutdown) owns the server.
+    pub fn reloader(&self) -> Reloader {
+        Reloader {
+            config: self.config.clone(),
+        }
+    }
+
+    /// Accept connections on `listener` until `shutdown` completes, then stop
+    /// accepting and [`close`](Self::close).
+    pub async fn run_until_shutdown(
+        mut self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<ShutdownReport> {
+        tokio::pin!(shutdown);
+        loop {
+            let (socket, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                () = &mut shutdown => break,
+            };
+            tracing::info!(%addr, "accepted client");
+            if let Err(e) = self.handle_connection(socket).await {
+                tracing::error!(%addr, "failed to handle connection: {e}");
+            }
+        }
+        drop(listener);
+        tracing::info!("shutting down");
+        self.close().await
+    }
+
+    /// Receive every [`NoteEvent`] from now on, for embedders running the server in-process.
+    pub fn subscribe(&self) -> broadcast::Receiver<NoteEvent> {
+        self.events.subscribe()
+    }
+
+    /// Create a note for every non-empty line of the file at `path`, returning
+    /// how many were created. Seeded notes expire like any other.
+    pub async fn seed_from_file(&mut self, path: impl AsRef<Path>) -> Result<usize> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        let mut handler = self.create_handler();
+        let mut seeded = 0;
+        for body in contents.lines().filter(|line| !line.trim().is_empty()) {
+            handler.create_note(body).await?;
+            seeded += 1;
+        }
+        Ok(seeded)
+    }
+
+    /// Restore the notes saved at `path`, then keep the file up to date with
+    /// every change, returning how many notes were restored. An unwritable
+    /// file is an error unless `optional`, in which case the server warns and
+    /// keeps its notes in memory only. The file is gzipped if `compress` or
+    /// its name ends in `.gz`; compressed files are read back either way.
+    pub async fn persist_to(
+        &mut self,
+        path: impl AsRef<Path>,
+        optional: bool,
+        compress: bool,
+    ) -> Result<usize> {
+        let path = path.as_ref().to_owned();
+        let gzip = compress || persistence::is_gzip_path(&path);
+        let restored = persistence::load(&path).await?;
+        let count = restored.len();
+        let mut handler = self.create_handler();
+        for note in restored {
+            handler.restore(note).await?;
+        }
+        let contents = persistence::encode(&self.notes.lock().await.all().await?);
+        if let Err(e) = persistence::save(&path, &contents, gzip).await {
+            if !optional {
+                return Err(e.wrap_err(format!("cannot write {}", path.display())));
+            }
+            tracing::warn!(
+                path = %path.display(),
+                "cannot write: {e}. Keeping notes in memory only!"
+            );
+            return Ok(count);
+        }
+        let task = tokio::spawn(
+            Self::persist(
+                self.events.subscribe(),
+                self.notes.clone(),
+                path.clone(),
+                optional,
+                gzip,
+            )
+            .instrument(tracing::info_span!("persistence", path = %path.display())),
+        );
+        self.persistence = Some(Persistence {
+            path,
+            optional,
+            gzip,
+            task,
+        });
+        Ok(count)
+    }
+
+    /// Take over the notes already in the store, e.g. a database kept from an
+    /// earlier run: their ids are never reused and they expire like any other.
+    /// Returns how many there are.
+    pub async fn resume_stored(&mut self) -> Result<usize> {
+        let notes = self.notes.lock().await.all().await?;
+        for note in &notes {
+            self.next_id.fetch_max(note.id() + 1, Ordering::SeqCst);
+            self.used_bytes
+                .fetch_add(note.body().len(), Ordering::SeqCst);
+            self.metrics.note_restored();
+            self.cleanup_sender
+                .send((note.id(), note.generation()))
+                .map_err(|_| common::Error::ServerClosed)?;
+        }
+        Ok(notes.len())
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Summed length of all stored note bodies.
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes.load(Ordering::SeqCst)
+    }
+}
+
+/// A multi-threaded runtime to serve on, with `worker_threads` workers or
+/// else one per CPU.
+pub fn runtime(worker_threads: Option<usize>) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = worker_threads {
+        builder.worker_threads(threads);
+    }
+    builder.enable_all().build()
+}
+
+/// The body actually stored for a note sent over `format`: trimmed, with
+/// control characters other than tabs and newlines removed. Bodies framed by
+/// their length in [`WireFormat::LengthPrefixed`] keep every character.
+pub fn sanitize_body(body: &str, format: WireFormat) -> String {
+    body.trim()
+        .chars()
+        .filter(|&c| {
+            format == WireFormat::LengthPrefixed || matches!(c, '\t' | '\n') || !c.is_control()
+        })
+        .collect()
+}
+
+/// Attempts to flush a pushed frame before the subscriber is given up on.
+const PUSH_ATTEMPTS: u32 = 3;
+/// Wait before the first retry of a push, doubled before each further one.
+const PUSH_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Removes notes once they outlive their timeout, one [`run`](Self::run) per schedule.
+#[derive(Clone)]
+struct Expiry {
+    notes: SharedStore,
+    used_bytes: Arc<AtomicUsize>,
+    events: broadcast::Sender<NoteEvent>,
+    metrics: Arc<Metrics>,
+    timeout: Duration,
+    warning: Option<Duration>,
+    idle: bool,
+}
+
+impl Expiry {
+    /// Sleep until the note's deadline and remove it, warning its owner first
+    /// if configured. Gives up once the note is gone or rescheduled. Returns
+    /// `id`, so cleanup knows whose timer finished.
+    async fn run(self, id: NoteID, generation: u64) -> NoteID {
+        // Start of the note's lifetime when its warning went out; a refresh warns again.
+        let mut warned_for = None;
+        loop {
+            // Expiry is checked under the same lock as the removal, so a note
+            // refreshed while we slept survives until its new deadline.
+            let wakeup = {
+                let mut notes = self.notes.lock().await;
+                // The note may already be gone, e.g. evicted to stay within the byte budget.
+                let Some(note) = or_log(notes.get(id).await) else {
+                    break;
+                };
+                // A later touch queued a fresh schedule; that one owns the note now.
+                if note.generation() != generation {
+                    break;
+                }
+                let now = SystemTime::now();
+                let lifetime_start = lifetime_start(&note, self.idle);
+                let expiry = lifetime_start + note.ttl().unwrap_or(self.timeout);
+                if now >= expiry {
+                    if let Some(note) = or_log(notes.remove(id).await) {
+                        self.used_bytes
+                            .fetch_sub(note.body().len(), Ordering::SeqCst);
+                        self.metrics.note_expired();
+                        // Sending only fails when nobody is subscribed.
+                        let _ = self.events.send(NoteEvent::Expired(id));
+                    }
+                    break;
+                }
+                match self.warning {
+                    Some(warning) if warned_for != Some(lifetime_start) => {
+                        let warn_at = expiry.checked_sub(warning).unwrap_or(now);
+                        if now >= warn_at {
+                            let _ = self.events.send(NoteEvent::Expiring(note.clone()));
+                            warned_for = Some(lifetime_start);
+                            expiry
+                        } else {
+                            warn_at
+                        }
+                    }
+                    _ => expiry,
+                }
+            };
+            tracing::debug!(note = id, ?wakeup, "sleeping until expiry");
+            // A clock set back meanwhile only delays expiry until the next check.
+            tokio::time::sleep(wakeup.duration_since(SystemTime::now()).unwrap_or_default()).await;
+        }
+        id
+    }
+}
+
+/// When `note`'s current lifetime began: its creation or latest touch, or
+/// with [`ServerConfig::idle_expiry`] its last read.
+fn lifetime_start(note: &Note, idle_expiry: bool) -> SystemTime {
+    if idle_expiry {
+        note.last_accessed()
+    } else {
+        note.refreshed_at()
     }
 }
 
+/// When `note` expires: its own TTL, or else the note timeout, after its
+/// lifetime started.
+fn expiry(note: &Note, config: &ServerConfig) -> SystemTime {
+    lifetime_start(note, config.idle_expiry) + note.ttl().unwrap_or(config.note_timeout)
+}
+
+fn describe_note(note: &Note, config: &ServerConfig) -> NoteDescription {
+    let created_at_ms = note
+        .created_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let expires_in = expiry(note, config)
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    NoteDescription {
+        id: note.id(),
+        body: note.body().to_owned(),
+        created_at_ms,
+        expires_in_ms: expires_in.as_millis() as u64,
+        tags: note.tags().clone(),
+        owner: note.owner(),
+        metadata: note.metadata().clone(),
+        hash: note.hash().to_owned(),
+    }
+}
+
+/// Unwrap a store result where the caller can't report errors, logging the
+/// failure and carrying on as if nothing was found.
+fn or_log<T: Default>(result: Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        tracing::error!("store failed: {e}");
+        T::default()
+    })
+}
+
+/// Write a frame the client did not ask for, e.g. an expiry warning. A socket
+/// that momentarily can't take the write is retried with backoff; other
+/// errors, like resets, fail immediately.
+async fn push_frame<W: AsyncWrite + Unpin>(
+    connection: &mut ConnectionWriter<W>,
+    frame: &Frame,
+) -> Result<()> {
+    connection.feed_frame(frame)?;
+    let mut backoff = PUSH_BACKOFF;
+    for attempt in 1.. {
+        let Err(e) = connection.flush().await else {
+            return Ok(());
+        };
+        let transient = e.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::TimedOut
+            )
+        });
+        if !transient || attempt == PUSH_ATTEMPTS {
+            return Err(e);
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+    unreachable!("the loop only ends by returning")
+}
+
 #[derive(Debug)]
 pub struct NotesHandler {
-    pub notes: Arc<AsyncMutex<BTreeMap<NoteID, Note>>>,
-    cleanup_sender: Sender<NoteID>,
+    config: watch::Receiver<Arc<ServerConfig>>,
+    pub notes: SharedStore,
+    /// Shared with the [`NotesServer`]; see its `next_id` and `next_seq`.
+    next_id: Arc<AtomicU64>,
+    next_seq: Arc<AtomicU64>,
+    used_bytes: Arc<AtomicUsize>,
+    metrics: Arc<Metrics>,
+    events: broadcast::Sender<NoteEvent>,
+    cleanup_sender: Sender<(NoteID, u64)>,
     disconnect_sender: Sender<ClientID>,
+    /// Shared with the [`NotesServer`], to kick other clients.
+    client_handlers: ClientHandlers,
+    /// Turns true when an admin kicks this handler's client.
+    kicked: watch::Receiver<bool>,
+    /// Shared with the [`NotesServer`], which sets it on close.
+    closed: Arc<AtomicBool>,
+    /// Turns true when the server starts shutting down.
+    shutdown: watch::Receiver<bool>,
+    /// Client served by this handler, recorded as the owner of the notes it creates.
+    client_id: Option<ClientID>,
+    /// When this client's creates within the last `create_rate_window` arrived.
+    recent_creates: VecDeque<Instant>,
+    /// Which commands the client may send next.
+    state: ConnectionState,
+    /// What the client authenticated as; `None` until it has. See [`role`](Self::role).
+    role: Option<Role>,
+    /// Set by `Command::Subscribe`; created notes matching it are pushed to the client.
+    subscription: Option<NoteQuery>,
+    /// Nonce and deadline of the keepalive ping still waiting for its pong.
+    awaiting_pong: Option<(u64, Instant)>,
+    /// Note stored by the command being handled, as opposed to one it
+    /// deduplicated to; only that note is removed if the reply is lost.
+    inserted: Option<NoteID>,
 }
 
 impl NotesHandler {
-    pub fn new(
-        notes: Arc<AsyncMutex<BTreeMap<NoteID, Note>>>,
-        cleanup_sender: Sender<NoteID>,
-        disconnect_sender: Sender<ClientID>,
-    ) -> Self {
-        Self {
-            notes,
-            cleanup_sender,
-            disconnect_sender,
+    /// The server's settings as of the latest [`NotesServer::reload`].
+    fn config(&self) -> Arc<ServerConfig> {
+        self.config.borrow().clone()
+    }
+
+    /// Record a create in the sliding window, warning as the client crosses
+    /// [`ServerConfig::create_rate_warning`]. Creates are never rejected here.
+    fn track_create_rate(&mut self) {
+        let config = self.config();
+        let Some(threshold) = config.create_rate_warning else {
+            return;
+        };
+        let now = Instant::now();
+        while self
+            .recent_creates
+            .front()
+            .is_some_and(|&created| now - created > config.create_rate_window)
+        {
+            self.recent_creates.pop_front();
+        }
+        self.recent_creates.push_back(now);
+        let creates = self.recent_creates.len();
+        if creates == threshold + 1 {
+            tracing::warn!(
+                creates,
+                window = ?config.create_rate_window,
+                "create rate exceeded"
+            );
+            let _ = self.events.send(NoteEvent::CreateRateExceeded {
+                client: self.client_id,
+                creates,
+            });
+        }
+    }
+
+    /// What the client may do: the role its token granted, or without
+    /// [`ServerConfig::auth_tokens`] a writer's. Only a token makes an admin.
+    fn role(&self) -> Option<Role> {
+        self.role
+            .or_else(|| self.config().auth_tokens.is_none().then_some(Role::Writer))
+    }
+
+    fn ensure_open(&self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(common::Error::ServerClosed.into());
         }
+        Ok(())
     }
+
+    /// Store `body`, after [`sanitize_body`], as a new note.
     pub async fn create_note(&mut self, body: &str) -> Result<NoteID> {
+        self.create_note_with_metadata(body, BTreeMap::new()).await
+    }
+
+    /// Like [`create_note`](Self::create_note), attaching `metadata` to the note.
+    pub async fn create_note_with_metadata(
+        &mut self,
+        body: &str,
+        metadata: BTreeMap<String, String>,
+    ) -> Result<NoteID> {
+        self.insert_note(body, metadata, None).await
+    }
+
+    /// Like [`create_note`](Self::create_note), with the note expiring `ttl`
+    /// after it is created instead of after the note timeout.
+    pub async fn create_note_with_ttl(&mut self, body: &str, ttl: Duration) -> Result<NoteID> {
+        self.insert_note(body, BTreeMap::new(), Some(ttl)).await
+    }
+
+    async fn insert_note(
+        &mut self,
+        body: &str,
+        metadata: BTreeMap<String, String>,
+        ttl: Option<Duration>,
+    ) -> Result<NoteID> {
+        self.ensure_open()?;
+        if ttl.is_some_and(|ttl| ttl.is_zero()) {
+            return Err(anyhow!("ttl must be positive"));
+        }
+        self.track_create_rate();
+        let config = self.config();
+        let body = sanitize_body(body, config.wire_format);
+        let body = body.as_str();
+        config.check_note_len(body.len())?;
+        if let Some(policy) = &config.content_policy {
+            policy.check(body)?;
+        }
         let mut notes = self.notes.lock().await;
-        let id = notes.keys().last().map_or(0, |k| k + 1);
-        let note = Note::new(id, body.to_owned());
-        notes.insert(id, note);
+        if config.dry_run {
+            let id = self.next_id.load(Ordering::SeqCst);
+            tracing::info!(note = id, body, "dry run: would create note");
+            return Ok(id);
+        }
+        if config.dedupe {
+            let hash = common::content_hash(body);
+            let same = notes.find_by_hash(&hash).await?;
+            if let Some(note) = same.iter().find(|note| note.owner() == self.client_id) {
+                return Ok(note.id());
+            }
+        }
+        if let Some(max_bytes) = config.max_bytes {
+            if body.len() > max_bytes {
+                return Err(anyhow!("note exceeds the {max_bytes} byte budget"));
+            }
+            let over_budget =
+                |used: &AtomicUsize| used.load(Ordering::SeqCst) + body.len() > max_bytes;
+            if over_budget(&self.used_bytes) {
+                if !config.evict_oldest {
+                    return Err(anyhow!("note would exceed the {max_bytes} byte budget"));
+                }
+                for oldest in notes.all().await? {
+                    if !over_budget(&self.used_bytes) {
+                        break;
+                    }
+                    notes.remove(oldest.id()).await?;
+                    self.used_bytes
+                        .fetch_sub(oldest.body().len(), Ordering::SeqCst);
+                    self.metrics.note_removed();
+                    let _ = self.events.send(NoteEvent::Deleted(oldest.id()));
+                }
+            }
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let note = Note::new(id, body.to_owned())
+            .with_metadata(metadata)
+            .with_owner(self.client_id)
+            .with_ttl(ttl);
+        notes.insert(note.clone()).await?;
+        self.metrics.note_created();
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let _ = self.events.send(NoteEvent::Created(note, seq));
+        self.used_bytes.fetch_add(body.len(), Ordering::SeqCst);
+        // Only fails once the server has closed and dropped its cleanup task.
         self.cleanup_sender
-            .send(id)
-            .map_err(|_| anyhow!("Failed to send id {id} through channel."))?;
+            .send((id, 0))
+            .map_err(|_| common::Error::ServerClosed)?;
+        self.inserted = Some(id);
         Ok(id)
     }
+
+    /// Render the named template with `vars` and store the result as a note.
+    pub async fn create_from_template(
+        &mut self,
+        name: &str,
+        vars: &BTreeMap<String, String>,
+    ) -> Result<NoteID> {
+        let body = self.config().templates.render(name, vars)?;
+        self.create_note(&body).await
+    }
+
+    /// Count a read of `note` towards [`ServerConfig::idle_expiry`], storing it
+    /// back to `notes` and rescheduling its cleanup.
+    async fn record_access(&self, notes: &mut dyn NoteStore, note: &mut Note) -> Result<()> {
+        let generation = note.mark_accessed();
+        notes.insert(note.clone()).await?;
+        // Only fails once the server has closed, when nothing expires anymore.
+        let _ = self.cleanup_sender.send((note.id(), generation));
+        Ok(())
+    }
+
     pub async fn get(&self, id: u64) -> Option<Note> {
-        let notes = self.notes.lock().await;
-        let note = notes.get(&id)?.to_owned();
+        let mut notes = self.notes.lock().await;
+        let mut note = or_log(notes.get(id).await)?;
+        if self.config().idle_expiry {
+            or_log(self.record_access(&mut **notes, &mut note).await);
+        }
         Some(note)
     }
-    pub async fn get_all(&self) -> Vec<Note> {
-        let notes = self.notes.lock().await;
-        notes.values().cloned().collect()
+    pub async fn describe(&self, id: NoteID) -> Option<NoteDescription> {
+        let note = self.get(id).await?;
+        Some(describe_note(&note, &self.config()))
     }
 
-    pub async fn remove(&mut self, id: u64) -> Option<Note> {
-        self.notes.lock().await.remove(&id)
+    /// Evaluate a [`Command::Query`]: up to `limit` of the notes matching
+    /// `filter` in `sort` order, skipping the first `offset`, and how many
+    /// matched in all.
+    pub async fn query(
+        &self,
+        filter: &NoteFilter,
+        sort: QuerySort,
+        offset: usize,
+        limit: usize,
+    ) -> QueryResult {
+        let config = self.config();
+        let mut notes = self.notes.lock().await;
+        let mut matches: Vec<Note> = or_log(notes.all().await)
+            .into_iter()
+            .filter(|note| filter.matches(note))
+            .collect();
+        match sort {
+            QuerySort::Id => {}
+            QuerySort::Created => matches.sort_by_key(|note| (note.created_at, note.id())),
+            QuerySort::Ttl => matches.sort_by_key(|note| (expiry(note, &config), note.id())),
+        }
+        let total = matches.len() as u64;
+        let mut page: Vec<Note> = matches.into_iter().skip(offset).take(limit).collect();
+        if config.idle_expiry && config.idle_expiry_counts_lists {
+            for note in &mut page {
+                or_log(self.record_access(&mut **notes, note).await);
+            }
+        }
+        QueryResult {
+            notes: page
+                .iter()
+                .map(|note| describe_note(note, &config))
+                .collect(),
+            total,
+        }
     }
 
-    async fn run(mut self, mut connection: Connection, id: u64) -> Result<()> {
-        println!("Running handler for {id}");
-        connection
-            .write_frame(&Command::Id(id).into())
-            .await
-            .map_err(|_| anyhow!("failed to write id"))?;
-        println!("Sent id: {}, awaiting commands", id);
-        loop {
-            if let Some(Frame(command)) = connection.read_frame().await? {
-                println!("[Handler {id}] Received command: {:?}", command);
-                match command {
-                    Command::Create(body) => {
-                        let body = body.as_str();
-                        self.create_note(body).await?;
-                    }
-                    Command::Read => {
-                        let notes = self.get_all().await;
-                        let notes = notes.iter().map(|note| note.body().to_owned()).collect();
-                        let frame = Command::List(notes).into();
-                        connection.write_frame(&frame).await?;
-                    }
-                    Command::Disconnect(id) => {
-                        self.disconnect_sender
-                            .send(id)
-                            .map_err(|_| anyhow!("Failed to send id {id} through channel."))?;
-                        return Ok(());
-                    }
-                    Command::Quit => {
-                        println!("Closing connection");
-                        todo!();
+    /// Find the note whose body has the content hash `hash`, as returned by
+    /// [`Note::hash`]; the oldest one if several do.
+    pub async fn get_by_hash(&self, hash: &str) -> Option<Note> {
+        let mut notes = self.notes.lock().await;
+        let mut note = or_log(notes.find_by_hash(hash).await).into_iter().next()?;
+        if self.config().idle_expiry {
+            or_log(self.record_access(&mut **notes, &mut note).await);
+        }
+        Some(note)
+    }
+
+    /// Look up every id in `ids`, reporting the ones without a note as missing.
+    pub async fn get_many(&self, ids: &[NoteID]) -> NoteBatch {
+        let idle_expiry = self.config().idle_expiry;
+        let mut notes = self.notes.lock().await;
+        let mut batch = NoteBatch::default();
+        for &id in ids {
+            match or_log(notes.get(id).await) {
+                Some(mut note) => {
+                    if idle_expiry {
+                        or_log(self.record_access(&mut **notes, &mut note).await);
                     }
-                    _ => {}
+                    batch.found.insert(id, note.body().to_owned());
                 }
+                None => batch.missing.push(id),
             }
         }
+        batch
     }
 
-    pub fn close(self) -> Result<()> {
-        drop(self.cleanup_sender);
-        Ok(())
+    /// Return up to `limit` notes after `cursor`, with the cursor to resume from
+    /// if more remain. Cursors hold the last id returned, so they stay valid
+    /// across reconnects.
+    pub async fn read_page(
+        &self,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<Note>, Option<String>)> {
+        let after = cursor
+            .map(|cursor| {
+                NoteID::from_str_radix(cursor, 16)
+                    .map_err(|_| anyhow!("invalid page cursor {cursor:?}"))
+            })
+            .transpose()?;
+        let config = self.config();
+        let mut notes = self.notes.lock().await;
+        let mut remaining = notes
+            .all()
+            .await?
+            .into_iter()
+            .filter(|note| after.is_none_or(|after| note.id() > after));
+        let mut page = Vec::new();
+        for mut note in remaining.by_ref().take(limit) {
+            if config.idle_expiry && config.idle_expiry_counts_lists {
+                self.record_access(&mut **notes, &mut note).await?;
+            }
+            page.push(note);
+        }
+        let cursor = match (page.last(), remaining.next()) {
+            (Some(last), Some(_)) => Some(format!("{:016x}", last.id())),
+            _ => None,
+        };
+        Ok((page, cursor))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Return up to `limit` notes in id order after skipping the first
+    /// `offset`, and whether any follow them.
+    pub async fn list_page(&self, offset: usize, limit: usize) -> (Vec<Note>, bool) {
+        let config = self.config();
+        let mut notes = self.notes.lock().await;
+        let mut remaining = or_log(notes.all().await).into_iter().skip(offset);
+        let mut page = Vec::new();
+        for mut note in remaining.by_ref().take(limit) {
+            if config.idle_expiry && config.idle_expiry_counts_lists {
+                or_log(self.record_access(&mut **notes, &mut note).await);
+            }
+            page.push(note);
+        }
+        (page, remaining.next().is_some())
+    }
 
-    #[tokio::test]
-    async fn add_100_notes() -> Result<()> {
-        unimplemented!("This test hangs");
-        let mut notes_server = NotesServer::new(Some(Duration::from_millis(100)));
-        let mut notes_handler = notes_server.create_handler();
+    pub async fn get_all(&self) -> Vec<Note> {
+        let config = self.config();
+        let mut notes = self.notes.lock().await;
+        let mut all = or_log(notes.all().await);
+        if config.idle_expiry && config.idle_expiry_counts_lists {
+            for note in &mut all {
+                or_log(self.record_access(&mut **notes, note).await);
+            }
+        }
+        all
+    }
 
-        for _ in 0..100 {
+    /// The notes whose body contains `text`, ignoring case; every note when
+    /// `text` is empty. Counts as reading them like [`get_all`](Self::get_all).
+    pub async fn search(&self, text: &str) -> Vec<Note> {
+        let query = NoteQuery {
+            contains: Some(text.to_owned()),
+            ..Default::default()
+        };
+        let config = self.config();
+        let mut notes = self.notes.lock().await;
+        let mut found: Vec<Note> = or_log(notes.all().await)
+            .into_iter()
+            .filter(|note| note.matches(&query))
+            .collect();
+        if config.idle_expiry && config.idle_expiry_counts_lists {
+            for note in &mut found {
+                or_log(self.record_access(&mut **notes, note).await);
+            }
+        }
+        found
+    }
+
+    /// Exchange the bodies of two notes under a single lock.
+    pub async fn swap(&mut self, a: NoteID, b: NoteID) -> Result<()> {
+        self.ensure_open()?;
+        let mut notes = self.notes.lock().await;
+        let mut note_a = notes
+            .get(a)
+            .await?
+            .ok_or_else(|| anyhow!("note {a} not found"))?;
+        let mut note_b = notes
+            .get(b)
+            .await?
+            .ok_or_else(|| anyhow!("note {b} not found"))?;
+        self.check_owner(&note_a)?;
+        self.check_owner(&note_b)?;
+        if a == b {
+            return Ok(());
+        }
+        if self.config().dry_run {
+            tracing::info!(a, b, "dry run: would swap notes");
+            return Ok(());
+        }
+        let body_a = note_a.set_body(note_b.body().to_owned());
+        note_b.set_body(body_a);
+        notes.insert(note_a).await?;
+        notes.insert(note_b).await?;
+        let _ = self.events.send(NoteEvent::Swapped(a, b));
+        Ok(())
+    }
+
+    /// Add `delta` to the integer stored in a note's body, returning the new
+    /// value. The lock makes the read-modify-write atomic. A counter may not
+    /// grow past the length limit or byte budget any more than other notes.
+    pub async fn increment(&mut self, id: NoteID, delta: i64) -> Result<i64> {
+        self.ensure_open()?;
+        let config = self.config();
+        let mut notes = self.notes.lock().await;
+        let mut note = notes
+            .get(id)
+            .await?
+            .ok_or_else(|| anyhow!("note {id} not found"))?;
+        self.check_owner(&note)?;
+        let value = note
+            .body()
+            .parse::<i64>()
+            .map_err(|_| anyhow!("note {id} is not a counter"))?
+            .checked_add(delta)
+            .ok_or_else(|| anyhow!("incrementing note {id} by {delta} overflows"))?;
+        let body = value.to_string();
+        config.check_note_len(body.len())?;
+        if let Some(max_bytes) = config.max_bytes {
+            let used = self.used_bytes.load(Ordering::SeqCst) - note.body().len();
+            if used + body.len() > max_bytes {
+                return Err(anyhow!(
+                    "increment would exceed the {max_bytes} byte budget"
+                ));
+            }
+        }
+        if config.dry_run {
+            tracing::info!(note = id, value, "dry run: would set note");
+            return Ok(value);
+        }
+        let previous = note.set_body(body);
+        let current = note.body().len();
+        notes.insert(note).await?;
+        self.used_bytes.fetch_sub(previous.len(), Ordering::SeqCst);
+        self.used_bytes.fetch_add(current, Ordering::SeqCst);
+        let _ = self.events.send(NoteEvent::Updated(id));
+        Ok(value)
+    }
+
+    /// Replace a note's body with `new_body`, after [`sanitize_body`], only if
+    /// it currently equals `expected_body`. The lock makes the compare and set atomic.
+    pub async fn compare_and_update(
+        &mut self,
+        id: NoteID,
+        expected_body: &str,
+        new_body: &str,
+    ) -> Result<bool> {
+        self.ensure_open()?;
+        let config = self.config();
+        let new_body = sanitize_body(new_body, self.config().wire_format);
+        let mut notes = self.notes.lock().await;
+        let mut note = notes
+            .get(id)
+            .await?
+            .ok_or_else(|| anyhow!("note {id} not found"))?;
+        self.check_owner(&note)?;
+        if note.body() != expected_body {
+            return Ok(false);
+        }
+        config.check_note_len(new_body.len())?;
+        if let Some(max_bytes) = config.max_bytes {
+            let used = self.used_bytes.load(Ordering::SeqCst) - note.body().len();
+            if used + new_body.len() > max_bytes {
+                return Err(anyhow!("update would exceed the {max_bytes} byte budget"));
+            }
+        }
+        if config.dry_run {
+            tracing::info!(note = id, body = new_body, "dry run: would set note");
+            return Ok(true);
+        }
+        let previous = note.set_body(new_body);
+        let current = note.body().len();
+        notes.insert(note).await?;
+        self.used_bytes.fetch_sub(previous.len(), Ordering::SeqCst);
+        self.used_bytes.fetch_add(current, Ordering::SeqCst);
+        let _ = self.events.send(NoteEvent::Updated(id));
+        Ok(true)
+    }
+
+    /// Add `text`, after [`sanitize_body`], to the end of a note's body on a
+    /// line of its own, returning the body's new length. The lock makes the
+    /// read-modify-write atomic.
+    pub async fn append(&mut self, id: NoteID, text: &str) -> Result<usize> {
+        self.ensure_open()?;
+        let config = self.config();
+        let text = sanitize_body(text, self.config().wire_format);
+        let mut notes = self.notes.lock().await;
+        let mut note = notes
+            .get(id)
+            .await?
+            .ok_or_else(|| anyhow!("note {id} not found"))?;
+        self.check_owner(&note)?;
+        let body = if note.body().is_empty() {
+            text
+        } else {
+            format!("{}\n{text}", note.body())
+        };
+        config.check_note_len(body.len())?;
+        if let Some(max_bytes) = config.max_bytes {
+            let used = self.used_bytes.load(Ordering::SeqCst) - note.body().len();
+            if used + body.len() > max_bytes {
+                return Err(anyhow!("append would exceed the {max_bytes} byte budget"));
+            }
+        }
+        if config.dry_run {
+            tracing::info!(note = id, body, "dry run: would set note");
+            return Ok(body.len());
+        }
+        let previous = note.set_body(body);
+        let current = note.body().len();
+        notes.insert(note).await?;
+        self.used_bytes.fetch_sub(previous.len(), Ordering::SeqCst);
+        self.used_bytes.fetch_add(current, Ordering::SeqCst);
+        let _ = self.events.send(NoteEvent::Updated(id));
+        Ok(current)
+    }
+
+    /// Store a previously persisted note under its own id.
+    async fn restore(&mut self, note: Note) -> Result<()> {
+        let (id, generation) = (note.id(), note.generation());
+        self.next_id.fetch_max(id + 1, Ordering::SeqCst);
+        let len = note.body().len();
+        self.notes.lock().await.insert(note).await?;
+        self.used_bytes.fetch_add(len, Ordering::SeqCst);
+        self.metrics.note_restored();
+        self.cleanup_sender
+            .send((id, generation))
+            .map_err(|_| common::Error::ServerClosed.into())
+    }
+
+    /// Restart a note's lifetime, superseding any expiry already scheduled for it.
+    pub async fn touch(&mut self, id: NoteID) -> Result<()> {
+        self.ensure_open()?;
+        let mut notes = self.notes.lock().await;
+        let mut note = notes
+            .get(id)
+            .await?
+            .ok_or_else(|| anyhow!("note {id} not found"))?;
+        let generation = note.touch();
+        notes.insert(note).await?;
+        self.cleanup_sender
+            .send((id, generation))
+            .map_err(|_| common::Error::ServerClosed.into())
+    }
+
+    /// Remove a note for a client's `Delete`; its pending expiry is dropped by cleanup.
+    pub async fn delete(&mut self, id: NoteID) -> Result<()> {
+        self.ensure_open()?;
+        let mut notes = self.notes.lock().await;
+        let note = notes
+            .get(id)
+            .await?
+            .ok_or_else(|| anyhow!("note {id} not found"))?;
+        self.check_owner(&note)?;
+        if self.config().dry_run {
+            tracing::info!(note = id, "dry run: would delete note");
+            return Ok(());
+        }
+        notes.remove(id).await?;
+        self.used_bytes
+            .fetch_sub(note.body().len(), Ordering::SeqCst);
+        self.metrics.note_removed();
+        let _ = self.events.send(NoteEvent::Deleted(id));
+        Ok(())
+    }
+
+    /// Make client `target` the owner of note `id`.
+    pub async fn transfer(&mut self, id: NoteID, target: ClientID) -> Result<()> {
+        self.ensure_open()?;
+        let mut notes = self.notes.lock().await;
+        let note = notes
+            .get(id)
+            .await?
+            .ok_or_else(|| anyhow!("note {id} not found"))?;
+        self.check_owner(&note)?;
+        if self.config().dry_run {
+            tracing::info!(note = id, target, "dry run: would transfer note");
+            return Ok(());
+        }
+        notes.insert(note.with_owner(Some(target))).await?;
+        let _ = self.events.send(NoteEvent::Updated(id));
+        Ok(())
+    }
+
+    /// Check that this client may change, delete or hand off `note`: it owns
+    /// the note, nobody does, or the client authenticated as an admin. This
+    /// holds with or without [`ServerConfig::auth_tokens`].
+    fn check_owner(&self, note: &Note) -> Result<()> {
+        match note.owner() {
+            Some(owner) if Some(owner) != self.client_id && self.role != Some(Role::Admin) => {
+                Err(anyhow!("note {} belongs to client {owner}", note.id()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn remove(&mut self, id: u64) -> Option<Note> {
+        let note = or_log(self.notes.lock().await.remove(id).await)?;
+        self.used_bytes
+            .fetch_sub(note.body().len(), Ordering::SeqCst);
+        self.metrics.note_removed();
+        let _ = self.events.send(NoteEvent::Deleted(id));
+        Some(note)
+    }
+
+    async fn run<S>(mut self, connection: Connection<S>, id: u64) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        tracing::info!("client connected");
+        // Held until the handler ends, even if it is aborted.
+        let _client = self.metrics.client_connected();
+        self.client_id = Some(id);
+        let (reader, mut writer) = connection.split();
+        writer
+            .write_frame(&Command::Id(id).into())
+            .await
+            .map_err(|_| {
+                let _ = self.disconnect_sender.send(id);
+                anyhow!("failed to write id")
+            })?;
+        tracing::debug!("sent id, awaiting commands");
+        // Frames read but not yet handled. Once the queue is full the reader
+        // stops reading, which pushes back on a client pipelining requests.
+        let (frames, queue) = mpsc::channel(self.config().max_in_flight);
+        // What each hello settled on, which the reader waits for before reading on.
+        let (negotiated, negotiation) = mpsc::channel(1);
+        let throttle = self.config().max_frames_per_sec.map(TokenBucket::new);
+        let reader = tokio::spawn(
+            Self::read_frames(reader, frames, negotiation, throttle, self.config.clone())
+                .in_current_span(),
+        );
+        let result = self.serve(queue, negotiated, &mut writer, id).await;
+        reader.abort();
+        let reason = *result.as_ref().unwrap_or(&CloseReason::Error);
+        tracing::info!(client = id, %reason, "connection closed");
+        if reason != CloseReason::ClientClosed {
+            // Best effort: the client may be gone already.
+            let _ = writer.write_frame(&Command::Closing(reason).into()).await;
+        }
+        // However the connection ended, stop tracking it. Sending only fails
+        // once the server has shut down.
+        let _ = self.disconnect_sender.send(id);
+        result.map(|_| ())
+    }
+
+    /// Forward frames from the client into `frames` until it closes the
+    /// connection or a read fails, taking a token from `throttle` before each.
+    /// After a hello, reading waits for `negotiation` to say which compression,
+    /// if any, the server agreed to. Frames are held to the
+    /// [`ServerConfig::max_client_frame`] of the latest `config`, even one
+    /// reloaded while a frame was coming in.
+    async fn read_frames<S: AsyncRead>(
+        mut reader: ConnectionReader<ReadHalf<S>>,
+        frames: mpsc::Sender<Result<Frame>>,
+        mut negotiation: mpsc::Receiver<Option<Compression>>,
+        mut throttle: Option<TokenBucket>,
+        mut config: watch::Receiver<Arc<ServerConfig>>,
+    ) {
+        reader.set_max_frame_size(config.borrow_and_update().max_client_frame());
+        loop {
+            if let Some(throttle) = &mut throttle {
+                throttle.acquire().await;
+            }
+            // A pending reload applies before more is read. Reading keeps what
+            // was buffered so far when a reload interrupts it.
+            let frame = loop {
+                tokio::select! {
+                    biased;
+                    // Errs once the server is dropped, disabling the branch.
+                    Ok(()) = config.changed() => {
+                        reader.set_max_frame_size(config.borrow_and_update().max_client_frame());
+                    }
+                    frame = reader.read_frame() => break frame,
+                }
+            };
+            let frame = frame.inspect_err(|e| {
+                if let Some(common::Error::UnexpectedEof { buffered }) = e.downcast_ref() {
+                    tracing::info!(buffered, "client closed mid-frame");
+                }
+            });
+            let frame = match frame {
+                Ok(Some(frame)) => Ok(frame),
+                Ok(None) => return,
+                Err(e) => Err(e),
+            };
+            tracing::debug!(?frame, "read frame");
+            // Malformed frames are skipped by the codec, so reading can go on.
+            let failed = frame.as_ref().is_err_and(|e| {
+                !matches!(e.downcast_ref(), Some(common::Error::MalformedFrame(_)))
+            });
+            let hello = matches!(frame, Ok(Frame(Command::Hello { .. })));
+            if frames.send(frame).await.is_err() || failed {
+                return;
+            }
+            // The client compresses everything after an accepted hello, so
+            // only read on once the server has answered it.
+            if hello {
+                match negotiation.recv().await {
+                    Some(Some(compression)) => reader.set_compression(compression),
+                    Some(None) => {}
+                    None => return,
+                }
+            }
+        }
+    }
+
+    async fn serve<S: AsyncWrite>(
+        &mut self,
+        mut queue: mpsc::Receiver<Result<Frame>>,
+        negotiated: mpsc::Sender<Option<Compression>>,
+        connection: &mut ConnectionWriter<WriteHalf<S>>,
+        id: u64,
+    ) -> Result<CloseReason> {
+        // Forwards expiry warnings and subscriptions; dropped with the
+        // connection, so a closed client stops receiving broadcasts.
+        let mut events = self.events.subscribe();
+        let mut correlation_id: u64 = 0;
+        // Cleared once the first command arrives.
+        let mut handshake_deadline = self
+            .config()
+            .handshake_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let mut last_activity = Instant::now();
+        let mut keepalive_nonce: u64 = 0;
+        let mut shutdown = self.shutdown.clone();
+        let mut kicked = self.kicked.clone();
+        loop {
+            let keepalive_deadline = match self.awaiting_pong {
+                Some((_, deadline)) => Some(deadline),
+                None => self
+                    .config()
+                    .keepalive_interval
+                    .map(|interval| last_activity + interval),
+            };
+            let frame = tokio::select! {
+                frame = queue.recv() => frame,
+                _ = tokio::time::sleep_until(keepalive_deadline.unwrap_or_else(Instant::now)),
+                    if keepalive_deadline.is_some() =>
+                {
+                    if self.awaiting_pong.is_some() {
+                        tracing::info!("no pong within the keepalive timeout, disconnecting");
+                        return Ok(CloseReason::KeepaliveTimeout);
+                    }
+                    keepalive_nonce += 1;
+                    push_frame(connection, &Command::Ping(keepalive_nonce).into()).await?;
+                    let deadline = Instant::now() + self.config().keepalive_timeout;
+                    self.awaiting_pong = Some((keepalive_nonce, deadline));
+                    continue;
+                }
+                _ = tokio::time::sleep_until(handshake_deadline.unwrap_or_else(Instant::now)),
+                    if handshake_deadline.is_some() =>
+                {
+                    tracing::info!("no command within the handshake timeout, disconnecting");
+                    return Ok(CloseReason::IdleTimeout);
+                }
+                // Errs once the server is dropped without shutting down, disabling the branch.
+                Ok(()) = async { shutdown.wait_for(|&shutting_down| shutting_down).await.map(drop) } => {
+                    return Ok(CloseReason::ServerShutdown);
+                }
+                // Errs for handlers that can't be kicked, disabling the branch.
+                Ok(()) = async { kicked.wait_for(|&kicked| kicked).await.map(drop) } => {
+                    tracing::info!("kicked by an admin");
+                    return Ok(CloseReason::Kicked);
+                }
+                event = events.recv() => {
+                    // Missed (lagged) events only cost their warnings and notifications.
+                    match event {
+                        Ok(NoteEvent::Expiring(note)) if note.owner() == self.client_id => {
+                            let warning = Command::Expiring(note.id()).into();
+                            push_frame(connection, &warning).await?;
+                        }
+                        Ok(NoteEvent::Created(note, seq))
+                            if self.subscription.as_ref().is_some_and(|query| note.matches(query)) =>
+                        {
+                            let notify = Command::Notify {
+                                seq,
+                                id: note.id(),
+                                body: note.body().to_owned(),
+                            };
+                            push_frame(connection, &notify.into()).await?;
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+            };
+            let Some(frame) = frame else {
+                tracing::info!("client closed the connection");
+                return Ok(CloseReason::ClientClosed);
+            };
+            let Frame(command) = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    let error = Command::Error(e.to_string());
+                    if matches!(e.downcast_ref(), Some(common::Error::MalformedFrame(_))) {
+                        tracing::warn!("skipped {e}");
+                        self.send_reply(connection, error).await?;
+                        continue;
+                    }
+                    // Reading stopped, so the connection ends here; tell the client why first.
+                    let _ = push_frame(connection, &error.into()).await;
+                    return Err(e);
+                }
+            };
+            handshake_deadline = None;
+            last_activity = Instant::now();
+            tracing::info!(command = command.name(), "received command");
+            let command_name = command.name();
+            self.metrics.count_command(&command);
+            let started = Instant::now();
+            // Frames don't carry correlation ids yet, so number commands per connection.
+            correlation_id += 1;
+            let span = tracing::info_span!(
+                "command",
+                client = id,
+                correlation_id,
+                command = command_name
+            );
+            let hello = matches!(command, Command::Hello { .. });
+            async {
+                let reply = self.handle_command(command).await;
+                let compression = match reply {
+                    Some(Command::Hello { compression, .. }) => Some(compression),
+                    _ => None,
+                };
+                if let Some(reply) = reply {
+                    self.send_reply(connection, reply).await?;
+                }
+                // Once the reply is out, so the writer has switched too. A
+                // rejected hello leaves both halves as they were.
+                if hello {
+                    let _ = negotiated.send(compression).await;
+                }
+                let elapsed = started.elapsed();
+                tracing::debug!(command = command_name, ?elapsed, "handled command");
+                self.metrics.record_latency(command_name, elapsed);
+                Ok::<_, color_eyre::Report>(())
+            }
+            .instrument(span)
+            .await?;
+            // Quit and Disconnect end the session once their reply is out.
+            if self.state == ConnectionState::Closing {
+                return Ok(CloseReason::ClientQuit);
+            }
+        }
+    }
+
+    /// Carry out one command from the client, returning the frame to answer it
+    /// with, if any. Commands that the connection's [`ConnectionState`] or the
+    /// client's [`Role`] do not allow are answered with an error.
+    pub async fn handle_command(&mut self, command: Command) -> Option<Command> {
+        self.inserted = None;
+        let id = self.client_id.unwrap_or_default();
+        if let Some(needed) =
+            auth::required_role(&command, id).filter(|&needed| Some(needed) > self.role())
+        {
+            tracing::warn!(command = command.name(), %needed, "rejected: role too low");
+            return Some(Command::Error(match self.role() {
+                Some(role) => format!("{command} needs the {needed} role, not {role}"),
+                None => format!("{command} needs authentication"),
+            }));
+        }
+        self.state = match self.state.next(&command, id) {
+            Ok(next) => next,
+            Err(e) => {
+                tracing::warn!(command = command.name(), "rejected: {e}");
+                return Some(Command::Error(e.to_string()));
+            }
+        };
+        match command {
+            Command::Create(body) => Some(self.serve_create(&body, BTreeMap::new(), None).await),
+            Command::CreateWithMetadata { body, metadata } => {
+                Some(self.serve_create(&body, metadata, None).await)
+            }
+            Command::CreateWithTtl(ttl, body) => {
+                Some(self.serve_create(&body, BTreeMap::new(), Some(ttl)).await)
+            }
+            Command::CreateFromTemplate { name, vars } => {
+                Some(match self.create_from_template(&name, &vars).await {
+                    Ok(note_id) => Command::Created(note_id),
+                    Err(e) => {
+                        tracing::warn!(template = name, "failed to create note: {e}");
+                        Command::Error(e.to_string())
+                    }
+                })
+            }
+            Command::Config => Some(Command::ConfigResult(self.config().effective())),
+            Command::Stats => {
+                let metrics = self.metrics.snapshot();
+                Some(Command::StatsResult(Stats {
+                    notes: or_log(self.notes.lock().await.count().await) as u64,
+                    used_bytes: self.used_bytes.load(Ordering::SeqCst) as u64,
+                    commands: metrics.commands,
+                    created: metrics.notes_created,
+                    expired: metrics.notes_expired,
+                    clients: metrics.active_clients,
+                }))
+            }
+            Command::Get(note_id) => Some(match self.get(note_id).await {
+                Some(note) => Command::Note(note_id, note.body().to_owned()),
+                None => Command::Error(format!("note {note_id} not found")),
+            }),
+            Command::Describe(note_id) => Some(match self.describe(note_id).await {
+                Some(description) => Command::Description(description),
+                None => Command::Error(format!("note {note_id} not found")),
+            }),
+            Command::GetByHash(hash) => {
+                let description = match self.get_by_hash(&hash).await {
+                    Some(note) => self.describe(note.id()).await,
+                    None => None,
+                };
+                Some(match description {
+                    Some(description) => Command::Description(description),
+                    None => Command::Error(format!("no note with hash {hash}")),
+                })
+            }
+            Command::GetMany(ids) => Some(Command::Batch(self.get_many(&ids).await)),
+            Command::Query {
+                filter,
+                sort,
+                offset,
+                limit,
+            } => Some(Command::QueryResult(
+                self.query(&filter, sort, offset as usize, limit as usize)
+                    .await,
+            )),
+            Command::ReadPage { cursor, limit } => Some(
+                match self.read_page(cursor.as_deref(), limit as usize).await {
+                    Ok((notes, cursor)) => Command::Page {
+                        notes: notes.iter().map(|note| note.body().to_owned()).collect(),
+                        cursor,
+                    },
+                    Err(e) => Command::Error(e.to_string()),
+                },
+            ),
+            Command::Subscribe { contains, tag } => {
+                self.subscription = Some(NoteQuery {
+                    contains: contains.clone(),
+                    tag: tag.clone(),
+                    ..Default::default()
+                });
+                Some(Command::Subscribe { contains, tag })
+            }
+            Command::Unsubscribe => {
+                self.subscription = None;
+                Some(Command::Unsubscribe)
+            }
+            Command::Ping(nonce) => Some(Command::Pong(nonce)),
+            Command::Pong(nonce)
+                if self
+                    .awaiting_pong
+                    .is_some_and(|(expected, _)| expected == nonce) =>
+            {
+                self.awaiting_pong = None;
+                None
+            }
+            Command::Read => {
+                let notes = self.get_all().await;
+                Some(Command::List(notes.iter().map(NoteSummary::from).collect()))
+            }
+            Command::ListPage { offset, limit } => {
+                let (notes, more) = self.list_page(offset as usize, limit as usize).await;
+                Some(Command::ListPageResult {
+                    notes: notes.iter().map(NoteSummary::from).collect(),
+                    more,
+                })
+            }
+            Command::Count => Some(Command::CountResult(or_log(
+                self.notes.lock().await.count().await,
+            ) as u64)),
+            Command::Search(text) => {
+                let notes = self.search(&text).await;
+                Some(Command::List(notes.iter().map(NoteSummary::from).collect()))
+            }
+            Command::Hello { version, .. } if version != PROTOCOL_VERSION => Some(Command::Error(
+                format!("protocol version {version} is not supported, only {PROTOCOL_VERSION}"),
+            )),
+            Command::Hello { compression, .. } => Some(Command::Hello {
+                version: PROTOCOL_VERSION,
+                compression,
+            }),
+            Command::Increment(note_id, delta) => {
+                Some(match self.increment(note_id, delta).await {
+                    Ok(value) => Command::Counter(note_id, value),
+                    Err(e) => Command::Error(e.to_string()),
+                })
+            }
+            Command::Append(note_id, text) => Some(match self.append(note_id, &text).await {
+                Ok(len) => Command::Appended(note_id, len as u64),
+                Err(e) => Command::Error(e.to_string()),
+            }),
+            Command::CompareAndUpdate {
+                id: note_id,
+                expected_body,
+                new_body,
+            } => Some(
+                match self
+                    .compare_and_update(note_id, &expected_body, &new_body)
+                    .await
+                {
+                    Ok(updated) => Command::CompareAndUpdateResult {
+                        id: note_id,
+                        updated,
+                    },
+                    Err(e) => Command::Error(e.to_string()),
+                },
+            ),
+            Command::Delete(note_id) => Some(match self.delete(note_id).await {
+                Ok(()) => Command::Delete(note_id),
+                Err(e) => Command::Error(e.to_string()),
+            }),
+            Command::Transfer(note_id, target) => {
+                Some(match self.transfer(note_id, target).await {
+                    Ok(()) => Command::Transfer(note_id, target),
+                    Err(e) => Command::Error(e.to_string()),
+                })
+            }
+            Command::Swap(a, b) => Some(match self.swap(a, b).await {
+                Ok(()) => Command::Swap(a, b),
+                Err(e) => Command::Error(e.to_string()),
+            }),
+            Command::Disconnect(client) if client != id => {
+                Some(match self.client_handlers.lock().await.get(&client) {
+                    Some(handler) => {
+                        tracing::info!(target = client, "kicking client");
+                        handler.kick.send_replace(true);
+                        Command::Disconnect(client)
+                    }
+                    None => Command::Error(format!("no client {client}")),
+                })
+            }
+            // The session ends here, and `run` stops tracking it on the way out.
+            Command::Disconnect(_) => None,
+            Command::Quit => {
+                tracing::info!("client quit");
+                None
+            }
+            Command::Auth(token) => Some(match &self.config().auth_tokens {
+                Some(tokens) => match tokens.role(&token) {
+                    Some(role) => {
+                        self.role = Some(role);
+                        Command::Authenticated(role)
+                    }
+                    None => Command::Error("invalid token".to_string()),
+                },
+                None => Command::Authenticated(Role::Writer),
+            }),
+            // A late answer to a keepalive that was already given up on.
+            Command::Pong(_) => None,
+            command => Some(Command::Error(format!("{command} is not handled here"))),
+        }
+    }
+
+    /// Create a note for a client's `Create`, returning the reply: the new
+    /// note's id, or its body in echo and dry-run modes.
+    async fn serve_create(
+        &mut self,
+        body: &str,
+        metadata: BTreeMap<String, String>,
+        ttl: Option<Duration>,
+    ) -> Command {
+        let created = self.insert_note(body, metadata, ttl).await;
+        if let Err(ref e) = created {
+            tracing::warn!("failed to create note: {e}");
+        }
+        let config = self.config();
+        match created {
+            Ok(_) if config.dry_run => {
+                let body = sanitize_body(body, config.wire_format);
+                Command::Create(format!("{DRY_RUN_MARKER}{body}"))
+            }
+            Ok(_) if config.echo_creates => {
+                Command::Create(sanitize_body(body, config.wire_format))
+            }
+            Ok(note_id) => Command::Created(note_id),
+            Err(e) => Command::Error(e.to_string()),
+        }
+    }
+
+    /// Write `reply` to the client. A `Created` that cannot be delivered
+    /// removes its note again if the create stored it, so a client retrying
+    /// the create leaves no orphan behind. A deduplicated create points at a
+    /// note that was already there, which is kept.
+    async fn send_reply<W: AsyncWrite + Unpin>(
+        &mut self,
+        connection: &mut ConnectionWriter<W>,
+        reply: Command,
+    ) -> Result<()> {
+        let (created, negotiated) = match reply {
+            Command::Created(id) => (
+                self.inserted.take().filter(|&inserted| inserted == id),
+                None,
+            ),
+            Command::Hello { compression, .. } => (None, Some(compression)),
+            _ => (None, None),
+        };
+        if let Err(e) = connection.write_frame(&reply.into()).await {
+            if let Some(id) = created {
+                self.remove(id).await;
+            }
+            return Err(e);
+        }
+        if let Some(compression) = negotiated {
+            connection.set_compression(compression);
+        }
+        Ok(())
+    }
+
+    pub fn close(self) -> Result<()> {
+        drop(self.cleanup_sender);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    /// How a note created moments ago is listed.
+    fn listed(id: NoteID, body: &str) -> NoteSummary {
+        NoteSummary {
+            id,
+            age_secs: 0,
+            body: body.to_string(),
+        }
+    }
+
+    /// Serve a new client over an in-memory pipe, returning the id it was
+    /// greeted with and its end of the connection.
+    async fn connect(server: &mut NotesServer) -> Result<(ClientID, Connection<DuplexStream>)> {
+        let (client, stream) = tokio::io::duplex(1 << 20);
+        server.handle_stream(stream).await?;
+        let mut connection = Connection::with_format(client, server.config().wire_format);
+        let Some(Frame(Command::Id(id))) = connection.read_frame().await? else {
+            panic!("expected an id");
+        };
+        Ok((id, connection))
+    }
+
+    #[tokio::test]
+    async fn add_100_notes() -> Result<()> {
+        let mut notes_server = NotesServer::new(Some(Duration::from_millis(100)));
+        let mut notes_handler = notes_server.create_handler();
+
+        for _ in 0..100 {
             println!("Creating note");
             notes_handler.create_note("test note").await?;
         }
@@ -233,4 +2112,2433 @@ mod tests {
         notes_server.close().await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn handler_serves_an_in_memory_pipe() -> Result<()> {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut notes_server = NotesServer::default();
+        notes_server.handle_stream(server).await?;
+
+        let mut connection = Connection::new(client);
+        assert!(matches!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Id(0)))
+        ));
+        connection
+            .write_frame(&Command::Create("piped".to_string()).into())
+            .await?;
+        assert!(matches!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Created(0)))
+        ));
+        connection.write_frame(&Command::Read.into()).await?;
+        let Some(Frame(Command::List(notes))) = connection.read_frame().await? else {
+            panic!("expected list");
+        };
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].body, "piped");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_after_close_reports_server_closed() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+        notes_server.close().await?;
+
+        let err = notes_handler.create_note("too late").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(common::Error::ServerClosed)
+        ));
+        assert!(notes_handler.get_all().await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn swap_after_close_reports_server_closed() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+        let a = notes_handler.create_note("first").await?;
+        let b = notes_handler.create_note("second").await?;
+        notes_server.close().await?;
+
+        let err = notes_handler.swap(a, b).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(common::Error::ServerClosed)
+        ));
+        assert_eq!(notes_handler.get(a).await.unwrap().body(), "first");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cleanup_spares_refreshed_note() -> Result<()> {
+        let timeout = Duration::from_millis(200);
+        let mut notes_server = NotesServer::new(Some(timeout));
+        let mut notes_handler = notes_server.create_handler();
+
+        let id = notes_handler.create_note("refresh me").await?;
+        tokio::time::sleep(timeout / 2).await;
+        notes_handler.touch(id).await?;
+        tokio::time::sleep(timeout * 3 / 4).await;
+        assert!(
+            notes_handler.get(id).await.is_some(),
+            "refreshed note was removed"
+        );
+
+        tokio::time::sleep(timeout).await;
+        assert!(notes_handler.get(id).await.is_none());
+        assert_eq!(notes_server.used_bytes(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_expiry_keeps_read_notes_alive() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            note_timeout: Duration::from_millis(300),
+            idle_expiry: true,
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+        let read = notes_handler.create_note("read often").await?;
+        let untouched = notes_handler.create_note("never read").await?;
+        for _ in 0..6 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            assert!(notes_handler.get(read).await.is_some());
+        }
+        let notes = notes_handler.notes.lock().await;
+        assert!(notes.get(read).await?.is_some());
+        assert!(notes.get(untouched).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_keep_one_expiry_timer_per_note() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            idle_expiry: true,
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+        let ids = [
+            notes_handler.create_note("first").await?,
+            notes_handler.create_note("second").await?,
+        ];
+        for _ in 0..50 {
+            for id in ids {
+                assert!(notes_handler.get(id).await.is_some());
+            }
+            notes_handler.get_all().await;
+        }
+        // Let cleanup reap the timers it replaced.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(notes_server.metrics().expiry_timers, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rapid_touches_expire_note_once_at_final_deadline() -> Result<()> {
+        let timeout = Duration::from_millis(200);
+        let mut notes_server = NotesServer::new(Some(timeout));
+        let mut events = notes_server.subscribe();
+        let mut notes_handler = notes_server.create_handler();
+
+        let id = notes_handler.create_note("touch me").await?;
+        let mut last_touch = Instant::now();
+        for _ in 0..5 {
+            tokio::time::sleep(timeout / 10).await;
+            notes_handler.touch(id).await?;
+            last_touch = Instant::now();
+        }
+
+        let expired_at = loop {
+            match events.recv().await? {
+                NoteEvent::Expired(expired) => {
+                    assert_eq!(expired, id);
+                    break Instant::now();
+                }
+                NoteEvent::Created(..) => {}
+                other => panic!("unexpected event {other:?}"),
+            }
+        };
+        assert!(expired_at >= last_touch + timeout);
+        assert!(notes_handler.get(id).await.is_none());
+        assert!(
+            tokio::time::timeout(timeout, events.recv()).await.is_err(),
+            "note expired more than once"
+        );
+        assert_eq!(notes_server.used_bytes(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notes_expire_on_time_whatever_order_they_were_scheduled_in() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut events = notes_server.subscribe();
+        let mut notes_handler = notes_server.create_handler();
+        let start = Instant::now();
+        let long = notes_handler
+            .create_note_with_ttl("long", Duration::from_millis(1500))
+            .await?;
+        let short = notes_handler
+            .create_note_with_ttl("short", Duration::from_millis(300))
+            .await?;
+
+        let mut expired = Vec::new();
+        while expired.len() < 2 {
+            if let NoteEvent::Expired(id) = events.recv().await? {
+                expired.push((id, start.elapsed()));
+            }
+        }
+        let [(first, first_at), (second, second_at)] = expired[..] else {
+            unreachable!();
+        };
+        assert_eq!((first, second), (short, long));
+        assert!(first_at >= Duration::from_millis(300), "{first_at:?}");
+        assert!(first_at < Duration::from_millis(1000), "{first_at:?}");
+        assert!(second_at >= Duration::from_millis(1500), "{second_at:?}");
+        Ok(())
+    }
+
+    /// Runs on the single-threaded test runtime, where a blocking receive in
+    /// the cleanup task would stall everything else.
+    #[tokio::test]
+    async fn flooded_cleanup_leaves_the_runtime_responsive() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        // Schedules for notes that don't exist, on top of those for real ones.
+        for id in 1_000_000..1_100_000 {
+            notes_server.cleanup_sender.send((id, 0))?;
+        }
+        let mut notes_handler = notes_server.create_handler();
+        for i in 0..1000 {
+            notes_handler.create_note(&format!("note {i}")).await?;
+        }
+
+        let greeting =
+            tokio::time::timeout(Duration::from_secs(1), connect(&mut notes_server)).await;
+        assert!(matches!(greeting, Ok(Ok(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn describe_reports_every_field() -> Result<()> {
+        let timeout = Duration::from_secs(60);
+        let mut notes_server = NotesServer::new(Some(timeout));
+        let mut notes_handler = notes_server.create_handler();
+        notes_handler.client_id = Some(4);
+
+        let before = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let id = notes_handler.create_note("buy milk").await?;
+        {
+            let mut notes = notes_handler.notes.lock().await;
+            let note = notes
+                .remove(id)
+                .await?
+                .unwrap()
+                .with_tags(["errand", "home"]);
+            notes.insert(note).await?;
+        }
+        let description = notes_handler.describe(id).await.unwrap();
+        let after = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+
+        assert_eq!(description.id, id);
+        assert_eq!(description.body, "buy milk");
+        assert!((before.saturating_sub(1)..=after).contains(&description.created_at_ms));
+        assert!(description.expires_in_ms > 59_000);
+        assert!(description.expires_in_ms <= timeout.as_millis() as u64);
+        assert_eq!(
+            description.tags,
+            ["errand", "home"].map(String::from).into_iter().collect()
+        );
+        assert_eq!(description.owner, Some(4));
+        assert_eq!(description.hash, common::content_hash("buy milk"));
+        assert!(notes_handler.describe(id + 1).await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metadata_round_trips_through_describe() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        let metadata = BTreeMap::from([
+            ("priority".to_string(), "3".to_string()),
+            ("source".to_string(), "email".to_string()),
+        ]);
+        let create = Command::CreateWithMetadata {
+            body: "call back".to_string(),
+            metadata: metadata.clone(),
+        };
+        connection.write_frame(&create.into()).await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Created(0)))
+        );
+        connection.write_frame(&Command::Describe(0).into()).await?;
+        let Some(Frame(Command::Description(description))) = connection.read_frame().await? else {
+            panic!("expected a description");
+        };
+        assert_eq!(description.body, "call back");
+        assert_eq!(description.metadata, metadata);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_filters_sorts_and_pages() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+        let mut ids = Vec::new();
+        for body in ["first", "second", "third", "untagged"] {
+            ids.push(notes_handler.create_note(body).await?);
+        }
+        {
+            let mut notes = notes_handler.notes.lock().await;
+            for &id in &ids[..3] {
+                let note = notes.remove(id).await?.unwrap().with_tags(["work"]);
+                notes.insert(note).await?;
+            }
+        }
+        // Restarting the first note's lifetime makes it the last to expire.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        notes_handler.touch(ids[0]).await?;
+
+        let filter = NoteFilter {
+            tag: Some("work".to_string()),
+            ..Default::default()
+        };
+        let bodies = |result: &QueryResult| -> Vec<String> {
+            result.notes.iter().map(|note| note.body.clone()).collect()
+        };
+        let by_ttl = notes_handler.query(&filter, QuerySort::Ttl, 0, 10).await;
+        assert_eq!(by_ttl.total, 3);
+        assert_eq!(bodies(&by_ttl), ["second", "third", "first"]);
+        let page = notes_handler.query(&filter, QuerySort::Ttl, 1, 1).await;
+        assert_eq!(page.total, 3);
+        assert_eq!(bodies(&page), ["third"]);
+        let past_the_end = notes_handler.query(&filter, QuerySort::Ttl, 3, 10).await;
+        assert_eq!(past_the_end.total, 3);
+        assert!(past_the_end.notes.is_empty());
+
+        let by_id = notes_handler
+            .query(&NoteFilter::default(), QuerySort::Id, 0, 10)
+            .await;
+        assert_eq!(bodies(&by_id), ["first", "second", "third", "untagged"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_returns_one_note_or_an_error() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        connection
+            .write_frame(&Command::Create("buy milk".to_string()).into())
+            .await?;
+        connection.read_frame().await?;
+        connection.write_frame(&Command::Get(0).into()).await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Note(0, "buy milk".to_string())))
+        );
+        connection.write_frame(&Command::Get(9).into()).await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Error("note 9 not found".to_string())))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipelined_pings_echo_their_nonces() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        let nonces = [42, 7, u64::MAX];
+        for nonce in nonces {
+            connection.feed_frame(&Command::Ping(nonce).into())?;
+        }
+        connection.flush().await?;
+        for nonce in nonces {
+            assert_eq!(
+                connection.read_frame().await?,
+                Some(Frame(Command::Pong(nonce)))
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ping_between_requests_keeps_replies_in_order() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        connection.feed_frame(&Command::Create("before".to_string()).into())?;
+        connection.feed_frame(&Command::Ping(9).into())?;
+        connection.feed_frame(&Command::Create("after".to_string()).into())?;
+        connection.feed_frame(&Command::Count.into())?;
+        connection.flush().await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Created(0)))
+        );
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Pong(9)))
+        );
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Created(1)))
+        );
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::CountResult(2)))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_says_goodbye_to_clients_and_stops_accepting() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(NotesServer::default().run_until_shutdown(listener, async {
+            let _ = stopped.await;
+        }));
+
+        let mut connection = Connection::new(TcpStream::connect(addr).await?);
+        connection.read_frame().await?;
+        connection
+            .write_frame(&Command::Create("in flight".to_string()).into())
+            .await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Created(0)))
+        );
+        let _ = stop.send(());
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Closing(CloseReason::ServerShutdown)))
+        );
+        let report = tokio::time::timeout(Duration::from_secs(2), server).await???;
+        assert!(TcpStream::connect(addr).await.is_err());
+        assert_eq!((report.clients, report.finished, report.aborted), (1, 1, 0));
+        assert_eq!(report.notes, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closing_says_goodbye_to_clients_and_reports_what_was_left() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            connections.push(connect(&mut notes_server).await?.1);
+        }
+        // A client that already left doesn't count as connected.
+        let mut gone = connections.pop().unwrap();
+        gone.write_frame(&Command::Quit.into()).await?;
+        while gone.read_frame().await?.is_some() {}
+        // Its handler finishes just after hanging up.
+        for _ in 0..100 {
+            if notes_server.connected_clients().await == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut notes_handler = notes_server.create_handler();
+        for body in ["one", "two"] {
+            notes_handler.create_note(body).await?;
+        }
+
+        let report = notes_server.close().await?;
+        for mut connection in connections {
+            assert_eq!(
+                connection.read_frame().await?,
+                Some(Frame(Command::Closing(CloseReason::ServerShutdown)))
+            );
+        }
+        assert_eq!(
+            (
+                report.notes,
+                report.clients,
+                report.finished,
+                report.aborted
+            ),
+            (2, 2, 2, 0)
+        );
+        assert!(report.uptime > Duration::ZERO);
+        assert!(
+            report.to_string().contains("2 notes and 2 clients"),
+            "{report}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fast_creates_emit_rate_warning() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            create_rate_warning: Some(3),
+            ..Default::default()
+        });
+        let mut events = notes_server.subscribe();
+        let mut notes_handler = notes_server.create_handler();
+        notes_handler.client_id = Some(9);
+
+        for i in 0..5 {
+            notes_handler.create_note(&format!("note {i}")).await?;
+        }
+        let mut warnings = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let NoteEvent::CreateRateExceeded { client, creates } = event {
+                warnings.push((client, creates));
+            }
+        }
+        assert_eq!(warnings, [(Some(9), 4)]);
+        assert_eq!(notes_handler.get_all().await.len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribers_only_receive_matching_creates() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut milk = connect(&mut notes_server).await?.1;
+        let mut eggs = connect(&mut notes_server).await?.1;
+        let mut writer = connect(&mut notes_server).await?.1;
+
+        for (subscriber, needle) in [(&mut milk, "milk"), (&mut eggs, "eggs")] {
+            let subscribe = Command::Subscribe {
+                contains: Some(needle.to_string()),
+                tag: None,
+            };
+            subscriber.write_frame(&subscribe.clone().into()).await?;
+            assert_eq!(subscriber.read_frame().await?, Some(Frame(subscribe)));
+        }
+        for body in ["buy milk", "buy eggs", "buy bread"] {
+            writer
+                .write_frame(&Command::Create(body.to_string()).into())
+                .await?;
+        }
+        // Matches both filters, so any unmatched note pushed earlier would arrive before it.
+        writer
+            .write_frame(&Command::Create("milk and eggs".to_string()).into())
+            .await?;
+
+        for (subscriber, expected) in [
+            (&mut milk, [(0, "buy milk"), (3, "milk and eggs")]),
+            (&mut eggs, [(1, "buy eggs"), (3, "milk and eggs")]),
+        ] {
+            for (id, body) in expected {
+                let notify = Command::Notify {
+                    seq: id,
+                    id,
+                    body: body.to_string(),
+                };
+                assert_eq!(subscriber.read_frame().await?, Some(Frame(notify)));
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconnecting_clients_get_unused_ids() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(connect(&mut notes_server).await?);
+        }
+        let (middle, mut leaving) = clients.remove(1);
+        leaving
+            .write_frame(&Command::Disconnect(middle).into())
+            .await?;
+        leaving.read_frame().await?;
+        let (fourth, _connection) = connect(&mut notes_server).await?;
+
+        let mut live: Vec<ClientID> = clients.iter().map(|(id, _)| *id).collect();
+        live.push(fourth);
+        assert!(!live.contains(&middle));
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                let mut tracked: Vec<_> = notes_server
+                    .client_handlers
+                    .lock()
+                    .await
+                    .keys()
+                    .copied()
+                    .collect();
+                tracked.sort();
+                if tracked == live {
+                    break;
+                }
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_kicks_another_client() -> Result<()> {
+        let mut tokens = AuthTokens::default();
+        tokens.insert("ops", Role::Admin);
+        tokens.insert("alice", Role::Writer);
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            auth_tokens: Some(tokens),
+            ..Default::default()
+        });
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            connections.push(connect(&mut notes_server).await?.1);
+        }
+        let [mut admin, mut victim] = <[_; 2]>::try_from(connections).unwrap();
+        for (connection, token, role) in [
+            (&mut admin, "ops", Role::Admin),
+            (&mut victim, "alice", Role::Writer),
+        ] {
+            connection
+                .write_frame(&Command::Auth(token.to_string()).into())
+                .await?;
+            assert_eq!(
+                connection.read_frame().await?,
+                Some(Frame(Command::Authenticated(role)))
+            );
+        }
+        victim.write_frame(&Command::Disconnect(0).into()).await?;
+        assert!(matches!(
+            victim.read_frame().await?,
+            Some(Frame(Command::Error(_)))
+        ));
+
+        admin.write_frame(&Command::Disconnect(1).into()).await?;
+        assert_eq!(
+            admin.read_frame().await?,
+            Some(Frame(Command::Disconnect(1)))
+        );
+        assert_eq!(
+            victim.read_frame().await?,
+            Some(Frame(Command::Closing(CloseReason::Kicked)))
+        );
+        assert_eq!(victim.read_frame().await?, None);
+
+        admin
+            .write_frame(&Command::Create("still here".to_string()).into())
+            .await?;
+        assert_eq!(admin.read_frame().await?, Some(Frame(Command::Created(0))));
+        admin.write_frame(&Command::Disconnect(9).into()).await?;
+        assert!(matches!(
+            admin.read_frame().await?,
+            Some(Frame(Command::Error(_)))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dropped_clients_are_reaped() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (id, connection) = connect(&mut notes_server).await?;
+        assert!(notes_server.client_handlers.lock().await.contains_key(&id));
+
+        // Hang up without a Disconnect or Quit.
+        drop(connection);
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while notes_server.client_handlers.lock().await.contains_key(&id) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn notifications_carry_increasing_sequence_numbers() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut subscriber = connect(&mut notes_server).await?.1;
+        let mut writers = [
+            connect(&mut notes_server).await?.1,
+            connect(&mut notes_server).await?.1,
+        ];
+
+        let subscribe = Command::Subscribe {
+            contains: Some("even".to_string()),
+            tag: None,
+        };
+        subscriber.write_frame(&subscribe.clone().into()).await?;
+        assert_eq!(subscriber.read_frame().await?, Some(Frame(subscribe)));
+        for i in 0..6 {
+            let parity = if i % 2 == 0 { "even" } else { "odd" };
+            let writer = &mut writers[i % 2];
+            let body = format!("{parity} {i}");
+            writer.write_frame(&Command::Create(body).into()).await?;
+            writer.read_frame().await?;
+        }
+
+        let mut seqs = Vec::new();
+        for i in [0, 2, 4] {
+            let Some(Frame(Command::Notify { seq, body, .. })) = subscriber.read_frame().await?
+            else {
+                panic!("expected notify");
+            };
+            assert_eq!(body, format!("even {i}"));
+            seqs.push(seq);
+        }
+        // The odd creates between them show up as gaps.
+        assert_eq!(seqs, [0, 2, 4]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unsubscribed_clients_stop_receiving_notifications() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut subscriber = connect(&mut notes_server).await?.1;
+        let mut writer = connect(&mut notes_server).await?.1;
+
+        let subscribe = Command::Subscribe {
+            contains: None,
+            tag: None,
+        };
+        for command in [subscribe, Command::Unsubscribe] {
+            subscriber.write_frame(&command.clone().into()).await?;
+            assert_eq!(subscriber.read_frame().await?, Some(Frame(command)));
+        }
+        writer
+            .write_frame(&Command::Create("unannounced".to_string()).into())
+            .await?;
+        assert_eq!(writer.read_frame().await?, Some(Frame(Command::Created(0))));
+
+        subscriber.write_frame(&Command::Read.into()).await?;
+        assert_eq!(
+            subscriber.read_frame().await?,
+            Some(Frame(Command::List(vec![listed(0, "unannounced")])))
+        );
+        let pushed =
+            tokio::time::timeout(Duration::from_millis(100), subscriber.read_frame()).await;
+        assert!(pushed.is_err(), "unsubscribed client was sent {pushed:?}");
+        Ok(())
+    }
+
+    /// Fails its first write with `WouldBlock`, then records everything written.
+    #[derive(Default)]
+    struct FlakyStream {
+        failed: bool,
+        written: Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    impl tokio::io::AsyncRead for FlakyStream {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for FlakyStream {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            if !self.failed {
+                self.failed = true;
+                return std::task::Poll::Ready(Err(std::io::ErrorKind::WouldBlock.into()));
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn push_survives_a_transient_write_error() -> Result<()> {
+        let stream = FlakyStream::default();
+        let written = stream.written.clone();
+        let (_reader, mut writer) = Connection::new(stream).split();
+        push_frame(&mut writer, &Command::Expiring(3).into()).await?;
+        push_frame(&mut writer, &Command::Expiring(4).into()).await?;
+        assert_eq!(*written.lock().unwrap(), b".3\r\n.4\r\n");
+        Ok(())
+    }
+
+    /// A peer that has gone away: every write fails with `BrokenPipe`.
+    struct BrokenStream;
+
+    impl AsyncWrite for BrokenStream {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    impl tokio::io::AsyncRead for BrokenStream {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn unsent_created_reply_removes_the_note() -> Result<()> {
+        let mut notes_server = NotesServer::new(None);
+        let mut notes_handler = notes_server.create_handler();
+        let (_reader, mut writer) = Connection::new(BrokenStream).split();
+        let reply = notes_handler
+            .handle_command(Command::Create("never acknowledged".to_string()))
+            .await;
+        assert_eq!(reply, Some(Command::Created(0)));
+        let sent = notes_handler.send_reply(&mut writer, reply.unwrap()).await;
+        assert!(sent.is_err());
+        assert!(notes_handler.get_all().await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unsent_deduplicated_reply_keeps_the_note() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            dedupe: true,
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+        let id = notes_handler.create_note("shared").await?;
+        let (_reader, mut writer) = Connection::new(BrokenStream).split();
+        let reply = notes_handler
+            .handle_command(Command::Create("shared".to_string()))
+            .await;
+        assert_eq!(reply, Some(Command::Created(id)));
+        let sent = notes_handler.send_reply(&mut writer, reply.unwrap()).await;
+        assert!(sent.is_err());
+        assert!(notes_handler.get(id).await.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reload_applies_new_byte_budget_to_existing_handlers() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            max_bytes: Some(100),
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+        notes_handler.create_note("fits either way").await?;
+
+        let mut config = notes_server.config().as_ref().clone();
+        settings::apply(&mut config, "# tighter\nmax_bytes = 20\nnote_timeout = 5\n")?;
+        assert_eq!(notes_server.reload(config), ["note_timeout"]);
+        assert_eq!(notes_server.config().note_timeout, NOTE_TIMEOUT);
+
+        let err = notes_handler.create_note("past the new budget").await;
+        assert!(err.is_err(), "create ignored the reloaded budget");
+        assert_eq!(notes_handler.get_all().await.len(), 1);
+        Ok(())
+    }
+
+    /// Appends everything a tracing subscriber writes to a shared buffer.
+    struct LogWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    /// What the current thread logs at `level` or above while this lives.
+    struct CapturedLogs {
+        buffer: Arc<std::sync::Mutex<Vec<u8>>>,
+        _guard: tracing::subscriber::DefaultGuard,
+    }
+
+    impl CapturedLogs {
+        fn start(level: tracing::Level) -> Self {
+            let buffer = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let subscriber = tracing_subscriber::fmt()
+                .with_ansi(false)
+                .with_max_level(level)
+                .with_writer({
+                    let buffer = buffer.clone();
+                    move || LogWriter(buffer.clone())
+                })
+                .finish();
+            Self {
+                buffer,
+                _guard: tracing::subscriber::set_default(subscriber),
+            }
+        }
+
+        fn text(&self) -> Result<String> {
+            Ok(String::from_utf8(self.buffer.lock().unwrap().clone())?)
+        }
+    }
+
+    impl std::io::Write for LogWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn command_events_carry_correlation_id() -> Result<()> {
+        let logs = CapturedLogs::start(tracing::Level::DEBUG);
+
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        // The second pong arrives after the first ping's span has closed.
+        for nonce in [1, 2] {
+            connection.write_frame(&Command::Ping(nonce).into()).await?;
+            connection.read_frame().await?;
+        }
+
+        let logs = logs.text()?;
+        let handled = logs
+            .lines()
+            .find(|line| line.contains("handled command"))
+            .expect("no command was logged");
+        assert!(
+            handled.contains("command{client=0 correlation_id=1 command=\"PING\"}"),
+            "{handled}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_events_carry_the_client_id() -> Result<()> {
+        let logs = CapturedLogs::start(tracing::Level::INFO);
+
+        let mut notes_server = NotesServer::default();
+        for _ in 0..2 {
+            let (client, server) = tokio::io::duplex(1024);
+            notes_server.handle_stream(server).await?;
+            let mut connection = Connection::new(client);
+            connection.read_frame().await?;
+            connection.write_frame(&Command::Count.into()).await?;
+            connection.read_frame().await?;
+        }
+
+        let logs = logs.text()?;
+        for client in 0..2 {
+            assert!(
+                logs.lines().any(|line| {
+                    line.contains(&format!("connection{{client={client}}}"))
+                        && line.contains("received command command=\"COUNT\"")
+                }),
+                "{logs}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_reason_is_logged_and_sent() -> Result<()> {
+        let logs = CapturedLogs::start(tracing::Level::INFO);
+
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            handshake_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            connections.push(connect(&mut notes_server).await?.1);
+        }
+        let [mut silent, mut quitting] = <[_; 2]>::try_from(connections).unwrap();
+        quitting.write_frame(&Command::Disconnect(1).into()).await?;
+        assert_eq!(
+            quitting.read_frame().await?,
+            Some(Frame(Command::Closing(CloseReason::ClientQuit)))
+        );
+        assert_eq!(
+            silent.read_frame().await?,
+            Some(Frame(Command::Closing(CloseReason::IdleTimeout)))
+        );
+
+        let logs = logs.text()?;
+        let closed = |client: u64| {
+            logs.lines()
+                .find(|line| {
+                    line.contains("connection closed") && line.contains(&format!("client={client}"))
+                })
+                .unwrap_or_else(|| panic!("no close logged for client {client}:\n{logs}"))
+                .to_owned()
+        };
+        assert!(closed(0).contains("reason=idle_timeout"), "{}", closed(0));
+        assert!(closed(1).contains("reason=client_quit"), "{}", closed(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribe_receives_note_events() -> Result<()> {
+        let mut notes_server = NotesServer::new(Some(Duration::from_millis(50)));
+        let mut events = notes_server.subscribe();
+        let mut notes_handler = notes_server.create_handler();
+
+        let kept = notes_handler.create_note("expires").await?;
+        let removed = notes_handler.create_note("removed").await?;
+        notes_handler.remove(removed).await;
+
+        let NoteEvent::Created(note, _) = events.recv().await? else {
+            panic!("expected created event");
+        };
+        assert_eq!((note.id(), note.body()), (kept, "expires"));
+        assert!(
+            matches!(events.recv().await?, NoteEvent::Created(note, _) if note.id() == removed)
+        );
+        assert!(matches!(events.recv().await?, NoteEvent::Deleted(id) if id == removed));
+        assert!(matches!(events.recv().await?, NoteEvent::Expired(id) if id == kept));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn owner_is_warned_before_expiry() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            note_timeout: Duration::from_millis(300),
+            expiry_warning: Some(Duration::from_millis(150)),
+            ..Default::default()
+        });
+        let mut events = notes_server.subscribe();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        connection
+            .write_frame(&Command::Create("soon gone".to_string()).into())
+            .await?;
+        connection.read_frame().await?;
+        let Some(Frame(Command::Expiring(id))) = connection.read_frame().await? else {
+            panic!("expected expiring warning");
+        };
+        let notes_handler = notes_server.create_handler();
+        assert!(
+            notes_handler.get(id).await.is_some(),
+            "warned after removal"
+        );
+
+        assert!(matches!(events.recv().await?, NoteEvent::Created(..)));
+        assert!(matches!(events.recv().await?, NoteEvent::Expiring(note) if note.id() == id));
+        assert!(matches!(events.recv().await?, NoteEvent::Expired(expired) if expired == id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn silent_client_is_disconnected_after_handshake_timeout() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            handshake_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Closing(CloseReason::IdleTimeout)))
+        );
+        let closed = tokio::time::timeout(Duration::from_secs(2), connection.read_frame()).await;
+        assert!(matches!(closed, Ok(Ok(None))), "{closed:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_client_is_pinged_and_dropped_without_pong() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            keepalive_interval: Some(Duration::from_millis(50)),
+            keepalive_timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        let Some(Frame(Command::Ping(nonce))) = connection.read_frame().await? else {
+            panic!("expected a keepalive ping");
+        };
+        connection.write_frame(&Command::Pong(nonce).into()).await?;
+        let Some(Frame(Command::Ping(next))) = connection.read_frame().await? else {
+            panic!("expected another ping after the pong");
+        };
+        assert_ne!(next, nonce);
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Closing(CloseReason::KeepaliveTimeout)))
+        );
+        let closed = tokio::time::timeout(Duration::from_secs(2), connection.read_frame()).await;
+        assert!(matches!(closed, Ok(Ok(None))), "{closed:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_echoes_sanitized_body() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            echo_creates: true,
+            ..Default::default()
+        });
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        connection
+            .write_frame(&Command::Create("  tab\there\nnext\u{7}  ".to_string()).into())
+            .await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Create("tab\there\nnext".to_string())))
+        );
+        let notes = notes_server.create_handler().get_all().await;
+        assert_eq!(notes[0].body(), "tab\there\nnext");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn length_prefixed_server_keeps_multi_line_bodies() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            wire_format: WireFormat::LengthPrefixed,
+            ..Default::default()
+        });
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        let body = "first line\r\nsecond\0line\nthird";
+        connection
+            .write_frame(&Command::Create(body.to_string()).into())
+            .await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Created(0)))
+        );
+        connection.write_frame(&Command::Read.into()).await?;
+        let Some(Frame(Command::List(notes))) = connection.read_frame().await? else {
+            panic!("expected list");
+        };
+        assert_eq!(notes[0].body.as_bytes(), body.as_bytes());
+        connection.write_frame(&Command::Get(0).into()).await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Note(0, body.to_string())))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dry_run_create_leaves_notes_empty() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            dry_run: true,
+            ..Default::default()
+        });
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        connection
+            .write_frame(&Command::Create("not stored".to_string()).into())
+            .await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Create(format!(
+                "{DRY_RUN_MARKER}not stored"
+            ))))
+        );
+        assert!(notes_server.create_handler().get_all().await.is_empty());
+        assert_eq!(notes_server.used_bytes(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn identical_bodies_share_hash_and_id_when_deduping() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            dedupe: true,
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+        let first = notes_handler.create_note("buy milk").await?;
+        let other = notes_handler.create_note("buy bread").await?;
+        let second = notes_handler.create_note("buy milk").await?;
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_eq!(notes_handler.get_all().await.len(), 2);
+
+        let hash = notes_handler.get(first).await.unwrap().hash().to_owned();
+        assert_eq!(hash, common::content_hash("buy milk"));
+        assert_eq!(notes_handler.get_by_hash(&hash).await.unwrap().id(), first);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deduping_only_matches_the_clients_own_notes() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            dedupe: true,
+            ..Default::default()
+        });
+        let (mut a, mut b) = (notes_server.create_handler(), notes_server.create_handler());
+        a.client_id = Some(1);
+        b.client_id = Some(2);
+        let theirs = a.create_note("buy milk").await?;
+        let mine = b.create_note("buy milk").await?;
+        assert_ne!(theirs, mine);
+        assert_eq!(b.create_note("buy milk").await?, mine);
+        b.delete(mine).await?;
+        let again = b.create_note("buy milk").await?;
+        assert!(again != mine && again != theirs);
+
+        let counter = b.create_note("1").await?;
+        b.increment(counter, 1).await?;
+        assert_eq!(b.create_note("2").await?, counter);
+        assert_ne!(b.create_note("1").await?, counter);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_by_hash_resolves_to_description() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        for (id, body) in ["buy milk", "buy bread"].into_iter().enumerate() {
+            connection
+                .write_frame(&Command::Create(body.to_string()).into())
+                .await?;
+            assert_eq!(
+                connection.read_frame().await?,
+                Some(Frame(Command::Created(id as NoteID)))
+            );
+        }
+        let hash = common::content_hash("buy bread");
+        connection
+            .write_frame(&Command::GetByHash(hash.clone()).into())
+            .await?;
+        let Some(Frame(Command::Description(description))) = connection.read_frame().await? else {
+            panic!("expected a description");
+        };
+        assert_eq!(description.id, 1);
+        assert_eq!(description.body, "buy bread");
+        assert_eq!(description.hash, hash);
+
+        connection
+            .write_frame(&Command::GetByHash(common::content_hash("buy eggs")).into())
+            .await?;
+        assert!(matches!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Error(_)))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn out_of_order_commands_are_rejected() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        connection
+            .write_frame(&Command::Create("accepted".to_string()).into())
+            .await?;
+        connection.read_frame().await?;
+        let hello = Command::Hello {
+            version: PROTOCOL_VERSION,
+            compression: Compression::None,
+        };
+        connection.write_frame(&hello.into()).await?;
+        assert!(matches!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Error(_)))
+        ));
+
+        connection.write_frame(&Command::Quit.into()).await?;
+        connection
+            .write_frame(&Command::Create("too late".to_string()).into())
+            .await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Closing(CloseReason::ClientQuit)))
+        );
+        let notes = notes_server.create_handler().get_all().await;
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].body(), "accepted");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quit_ends_the_handler_cleanly() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (client, stream) = tokio::io::duplex(64 * 1024);
+        let handler = notes_server.create_handler();
+        let handler = tokio::spawn(handler.run(Connection::new(stream), 7));
+
+        let mut connection = Connection::new(client);
+        assert_eq!(connection.read_frame().await?, Some(Frame(Command::Id(7))));
+        connection.write_frame(&Command::Quit.into()).await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Closing(CloseReason::ClientQuit)))
+        );
+        tokio::time::timeout(Duration::from_secs(2), handler).await???;
+        assert_eq!(connection.read_frame().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn get_many_reports_missing_ids() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+        let milk = notes_handler.create_note("buy milk").await?;
+        let bread = notes_handler.create_note("buy bread").await?;
+        let batch = notes_handler.get_many(&[bread, 7, milk, 9]).await;
+        assert_eq!(
+            batch.found,
+            BTreeMap::from([
+                (milk, "buy milk".to_string()),
+                (bread, "buy bread".to_string())
+            ])
+        );
+        assert_eq!(batch.missing, vec![7, 9]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_command_create_then_read() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+        let create = Command::Create("buy milk".to_string());
+        assert_eq!(
+            notes_handler.handle_command(create).await,
+            Some(Command::Created(0))
+        );
+        assert_eq!(
+            notes_handler.handle_command(Command::Read).await,
+            Some(Command::List(vec![listed(0, "buy milk")]))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn content_policy_rejects_creates_with_an_error() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            content_policy: Some(ContentPolicy::new("@", policy::PolicyMode::Forbid)?),
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+        let rejected = Command::Create("mail bob@example.com".to_string());
+        assert!(matches!(
+            notes_handler.handle_command(rejected).await,
+            Some(Command::Error(_))
+        ));
+        let accepted = Command::Create("call bob".to_string());
+        assert_eq!(
+            notes_handler.handle_command(accepted).await,
+            Some(Command::Created(0))
+        );
+        assert_eq!(notes_handler.get_all().await.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn config_reports_the_constructed_settings() -> Result<()> {
+        let mut templates = Templates::default();
+        templates.insert("standup", "{who}: {done}");
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            note_timeout: Duration::from_secs(90),
+            max_bytes: Some(4096),
+            templates,
+            dedupe: true,
+            keepalive_interval: Some(Duration::from_secs(20)),
+            content_policy: Some(ContentPolicy::new("@", PolicyMode::Forbid)?),
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+        notes_handler.role = Some(Role::Admin);
+        let Some(Command::ConfigResult(config)) =
+            notes_handler.handle_command(Command::Config).await
+        else {
+            panic!("expected config");
+        };
+        assert_eq!(
+            config,
+            EffectiveConfig {
+                note_timeout_ms: 90_000,
+                max_bytes: Some(4096),
+                max_note_len: Some(MAX_NOTE_SIZE as u64),
+                templates: vec!["standup".to_string()],
+                max_in_flight: 32,
+                handshake_timeout_ms: Some(30_000),
+                dedupe: true,
+                create_rate_window_ms: 10_000,
+                keepalive_interval_ms: Some(20_000),
+                keepalive_timeout_ms: 10_000,
+                idle_expiry_counts_lists: true,
+                content_regex: Some("@".to_string()),
+                content_regex_forbid: true,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bodies_up_to_max_note_len_are_accepted() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            max_note_len: Some(8),
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+        let at_limit = "x".repeat(8);
+        assert_eq!(
+            notes_handler
+                .handle_command(Command::Create(at_limit.clone()))
+                .await,
+            Some(Command::Created(0))
+        );
+        let over = Command::Create("x".repeat(9));
+        let Some(Command::Error(message)) = notes_handler.handle_command(over).await else {
+            panic!("expected an error");
+        };
+        assert!(message.contains("8 byte length limit"), "{message}");
+        let update = Command::CompareAndUpdate {
+            id: 0,
+            expected_body: at_limit,
+            new_body: "y".repeat(9),
+        };
+        assert!(matches!(
+            notes_handler.handle_command(update).await,
+            Some(Command::Error(_))
+        ));
+        assert_eq!(notes_server.used_bytes(), 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn frames_over_the_client_limit_close_the_connection() -> Result<()> {
+        let config = ServerConfig {
+            max_note_len: Some(16),
+            ..Default::default()
+        };
+        let limit = config.max_client_frame().unwrap();
+        let mut notes_server = NotesServer::with_config(config);
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        let oversized = Command::Create("x".repeat(limit + 100));
+        connection.write_frame(&oversized.into()).await?;
+        let Some(Frame(Command::Error(message))) = connection.read_frame().await? else {
+            panic!("expected an error frame");
+        };
+        assert!(
+            message.contains(&format!("exceeds the {limit} byte limit")),
+            "{message}"
+        );
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Closing(CloseReason::Error)))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reloaded_note_limit_reaches_live_connections() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            max_note_len: Some(16),
+            ..Default::default()
+        });
+        let (_, mut connection) = connect(&mut notes_server).await?;
+
+        let mut config = notes_server.config().as_ref().clone();
+        let old_limit = config.max_client_frame().unwrap();
+        config.max_note_len = Some(old_limit * 2);
+        assert!(notes_server.reload(config).is_empty());
+        // Over the frame limit the connection started with.
+        let body = "x".repeat(old_limit + 100);
+        connection
+            .write_frame(&Command::Create(body).into())
+            .await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Created(0)))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accepted_hello_compresses_pipelined_frames() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (mut reader, mut writer) = connect(&mut notes_server).await?.1.split();
+
+        let hello = Command::Hello {
+            version: PROTOCOL_VERSION,
+            compression: Compression::Lz4,
+        };
+        writer.write_frame(&hello.clone().into()).await?;
+        writer.set_compression(Compression::Lz4);
+        writer
+            .write_frame(&Command::Create("squeezed".to_string()).into())
+            .await?;
+        assert_eq!(reader.read_frame().await?, Some(Frame(hello)));
+        reader.set_compression(Compression::Lz4);
+        assert_eq!(reader.read_frame().await?, Some(Frame(Command::Created(0))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_hellos_leave_the_stream_uncompressed() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+
+        // A version mismatch, then a hello after the handshake is over.
+        for version in [PROTOCOL_VERSION + 1, PROTOCOL_VERSION] {
+            let hello = Command::Hello {
+                version,
+                compression: Compression::Lz4,
+            };
+            connection.write_frame(&hello.into()).await?;
+            let Some(Frame(Command::Error(message))) = connection.read_frame().await? else {
+                panic!("expected an error frame");
+            };
+            if version != PROTOCOL_VERSION {
+                assert!(message.contains("not supported"), "{message}");
+            }
+        }
+        connection
+            .write_frame(&Command::Create("still plain".to_string()).into())
+            .await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Created(0)))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_are_reported_and_skipped() -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut notes_server = NotesServer::default();
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+        notes_server.handle_stream(stream).await?;
+        // A garbage byte, a frame with a bad id, then a well-formed read.
+        client.write_all(b"\x00`not-an-id\r\n$").await?;
+
+        let mut connection = Connection::new(client);
+        assert!(matches!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Id(_)))
+        ));
+        for expected in ["invalid frame start byte", "invalid digit"] {
+            let Some(Frame(Command::Error(message))) = connection.read_frame().await? else {
+                panic!("expected an error frame");
+            };
+            assert!(message.contains(expected), "{message}");
+        }
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::List(Vec::new())))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reader_token_may_read_but_not_create() -> Result<()> {
+        let mut tokens = AuthTokens::default();
+        tokens.insert("peek", Role::Reader);
+        tokens.insert("ops", Role::Admin);
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            auth_tokens: Some(tokens),
+            ..Default::default()
+        });
+        let create = || Command::Create("buy milk".to_string());
+
+        let mut admin = notes_server.create_handler();
+        let Some(Command::Error(message)) = admin.handle_command(create()).await else {
+            panic!("expected an error");
+        };
+        assert!(message.contains("needs authentication"), "{message}");
+        assert_eq!(
+            admin.handle_command(Command::Auth("ops".to_string())).await,
+            Some(Command::Authenticated(Role::Admin))
+        );
+        assert_eq!(
+            admin.handle_command(create()).await,
+            Some(Command::Created(0))
+        );
+
+        let mut reader = notes_server.create_handler();
+        assert_eq!(
+            reader
+                .handle_command(Command::Auth("guess".to_string()))
+                .await,
+            Some(Command::Error("invalid token".to_string()))
+        );
+        assert_eq!(
+            reader
+                .handle_command(Command::Auth("peek".to_string()))
+                .await,
+            Some(Command::Authenticated(Role::Reader))
+        );
+        let Some(Command::Error(message)) = reader.handle_command(create()).await else {
+            panic!("expected an error");
+        };
+        assert!(message.contains("needs the writer role"), "{message}");
+        assert_eq!(
+            reader.handle_command(Command::Read).await,
+            Some(Command::List(vec![listed(0, "buy milk")]))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn without_tokens_nobody_is_an_admin() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+        notes_handler.client_id = Some(1);
+        for command in [Command::Disconnect(2), Command::Config] {
+            let Some(Command::Error(message)) = notes_handler.handle_command(command).await else {
+                panic!("expected an error");
+            };
+            assert!(
+                message.contains("needs the admin role, not writer"),
+                "{message}"
+            );
+        }
+        assert_eq!(
+            notes_handler
+                .handle_command(Command::Auth("anything".to_string()))
+                .await,
+            Some(Command::Authenticated(Role::Writer))
+        );
+        assert_eq!(
+            notes_handler
+                .handle_command(Command::Create("still allowed".to_string()))
+                .await,
+            Some(Command::Created(0))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_owners_change_their_notes_without_tokens() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (mut a, mut b) = (notes_server.create_handler(), notes_server.create_handler());
+        a.client_id = Some(1);
+        b.client_id = Some(2);
+        let counter = a.create_note("1").await?;
+        let mine = b.create_note("mine").await?;
+        for command in [
+            Command::Delete(counter),
+            Command::Transfer(counter, 2),
+            Command::Swap(counter, mine),
+            Command::Increment(counter, 1),
+            Command::CompareAndUpdate {
+                id: counter,
+                expected_body: "1".to_string(),
+                new_body: "2".to_string(),
+            },
+            Command::Append(counter, "more".to_string()),
+        ] {
+            assert_eq!(
+                b.handle_command(command).await,
+                Some(Command::Error(format!(
+                    "note {counter} belongs to client 1"
+                )))
+            );
+        }
+        assert_eq!(a.get(counter).await.unwrap().body(), "1");
+        assert_eq!(
+            a.handle_command(Command::Increment(counter, 1)).await,
+            Some(Command::Counter(counter, 2))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transferred_notes_may_only_be_deleted_by_their_new_owner() -> Result<()> {
+        let mut tokens = AuthTokens::default();
+        tokens.insert("alice", Role::Writer);
+        tokens.insert("bob", Role::Writer);
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            auth_tokens: Some(tokens),
+            ..Default::default()
+        });
+        let (mut a, mut b) = (notes_server.create_handler(), notes_server.create_handler());
+        for (id, handler, token) in [(1, &mut a, "alice"), (2, &mut b, "bob")] {
+            handler.client_id = Some(id);
+            assert_eq!(
+                handler
+                    .handle_command(Command::Auth(token.to_string()))
+                    .await,
+                Some(Command::Authenticated(Role::Writer))
+            );
+        }
+
+        let id = a.create_note("handed over").await?;
+        assert_eq!(
+            b.handle_command(Command::Transfer(id, 2)).await,
+            Some(Command::Error(format!("note {id} belongs to client 1")))
+        );
+        assert_eq!(
+            a.handle_command(Command::Transfer(id, 2)).await,
+            Some(Command::Transfer(id, 2))
+        );
+        assert_eq!(a.get(id).await.unwrap().owner(), Some(2));
+        assert_eq!(
+            a.handle_command(Command::Delete(id)).await,
+            Some(Command::Error(format!("note {id} belongs to client 2")))
+        );
+        assert_eq!(
+            b.handle_command(Command::Delete(id)).await,
+            Some(Command::Delete(id))
+        );
+        assert!(a.get(id).await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handle_command_disconnect_ends_the_session() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+        // Unregistered handlers serve client 0.
+        assert_eq!(
+            notes_handler.handle_command(Command::Disconnect(0)).await,
+            None
+        );
+        let late = Command::Create("too late".to_string());
+        assert!(matches!(
+            notes_handler.handle_command(late).await,
+            Some(Command::Error(_))
+        ));
+        assert!(notes_handler.get_all().await.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quitting_clients_are_reaped_once() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        for (id, goodbye) in [(0, Command::Quit), (1, Command::Disconnect(1))] {
+            let mut notes_handler = notes_server.create_handler();
+            let (disconnect_sender, mut disconnects) = mpsc::unbounded_channel();
+            notes_handler.disconnect_sender = disconnect_sender;
+            let (client, stream) = tokio::io::duplex(1 << 16);
+            let handler = tokio::spawn(notes_handler.run(Connection::new(stream), id));
+            let mut connection = Connection::new(client);
+            assert_eq!(connection.read_frame().await?, Some(Frame(Command::Id(id))));
+            connection.write_frame(&goodbye.into()).await?;
+            assert_eq!(
+                connection.read_frame().await?,
+                Some(Frame(Command::Closing(CloseReason::ClientQuit)))
+            );
+            handler.await??;
+            assert_eq!(disconnects.recv().await, Some(id));
+            assert!(disconnects.recv().await.is_none());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_ids_are_not_reused() -> Result<()> {
+        let timeout = Duration::from_millis(200);
+        let mut notes_server = NotesServer::new(Some(timeout));
+        let mut notes_handler = notes_server.create_handler();
+        let expired = notes_handler.create_note("expire me").await?;
+        tokio::time::sleep(timeout * 3 / 2).await;
+        assert!(notes_handler.get(expired).await.is_none());
+
+        let fresh = notes_handler.create_note("keep me").await?;
+        assert_ne!(fresh, expired);
+        tokio::time::sleep(timeout * 3 / 4).await;
+        assert!(
+            notes_handler.get(fresh).await.is_some(),
+            "new note was removed before its timeout"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleted_note_is_skipped_by_cleanup() -> Result<()> {
+        let mut notes_server = NotesServer::new(Some(Duration::from_millis(100)));
+        let mut events = notes_server.subscribe();
+        let mut notes_handler = notes_server.create_handler();
+        let deleted = notes_handler.create_note("delete me").await?;
+        let kept = notes_handler.create_note("let me expire").await?;
+        assert_eq!(
+            notes_handler.handle_command(Command::Delete(deleted)).await,
+            Some(Command::Delete(deleted))
+        );
+        assert!(matches!(
+            notes_handler.handle_command(Command::Delete(deleted)).await,
+            Some(Command::Error(_))
+        ));
+        assert!(notes_handler.get(deleted).await.is_none());
+
+        // Cleanup moves past the deleted note and still expires the next one.
+        let expired = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let NoteEvent::Expired(id) = events.recv().await? {
+                    return Ok::<_, color_eyre::Report>(id);
+                }
+            }
+        })
+        .await??;
+        assert_eq!(expired, kept);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn swap_exchanges_bodies() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+
+        let a = notes_handler.create_note("first").await?;
+        let b = notes_handler.create_note("second").await?;
+        notes_handler.swap(a, b).await?;
+        assert_eq!(notes_handler.get(a).await.unwrap().body(), "second");
+        assert_eq!(notes_handler.get(b).await.unwrap().body(), "first");
+
+        notes_handler.swap(a, a).await?;
+        assert_eq!(notes_handler.get(a).await.unwrap().body(), "second");
+        assert!(notes_handler.swap(a, 42).await.is_err());
+        assert_eq!(notes_handler.get(a).await.unwrap().body(), "second");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn increment_updates_numeric_note() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+
+        let id = notes_handler.create_note("9").await?;
+        assert_eq!(notes_handler.increment(id, 1).await?, 10);
+        assert_eq!(notes_handler.increment(id, -15).await?, -5);
+        assert_eq!(notes_handler.get(id).await.unwrap().body(), "-5");
+        assert_eq!(notes_server.used_bytes(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn increment_stays_within_the_note_limits() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            max_note_len: Some(3),
+            max_bytes: Some(4),
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+
+        let big = notes_handler.create_note("99").await?;
+        let small = notes_handler.create_note("5").await?;
+        assert_eq!(notes_handler.increment(big, 1).await?, 100);
+        assert_eq!(notes_server.used_bytes(), 4);
+        let err = notes_handler.increment(small, 10).await.unwrap_err();
+        assert!(err.to_string().contains("byte budget"), "{err}");
+        let err = notes_handler.increment(big, 900).await.unwrap_err();
+        assert!(err.to_string().contains("length limit"), "{err}");
+        assert_eq!(notes_handler.get(big).await.unwrap().body(), "100");
+        assert_eq!(notes_handler.get(small).await.unwrap().body(), "5");
+        assert_eq!(notes_server.used_bytes(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compare_and_update_only_replaces_expected_body() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+        let id = notes_handler.create_note("buy milk").await?;
+        assert!(
+            notes_handler
+                .compare_and_update(id, "buy milk", "buy oat milk")
+                .await?
+        );
+        assert_eq!(notes_server.used_bytes(), "buy oat milk".len());
+
+        // A second writer still expecting the old body loses.
+        let cas = Command::CompareAndUpdate {
+            id,
+            expected_body: "buy milk".to_string(),
+            new_body: "buy bread".to_string(),
+        };
+        assert_eq!(
+            notes_handler.handle_command(cas).await,
+            Some(Command::CompareAndUpdateResult { id, updated: false })
+        );
+        assert_eq!(notes_handler.get(id).await.unwrap().body(), "buy oat milk");
+        assert!(notes_handler
+            .compare_and_update(id + 1, "", "new")
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn increment_rejects_non_numeric_note() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+
+        let id = notes_handler.create_note("buy milk").await?;
+        let err = notes_handler.increment(id, 1).await.unwrap_err();
+        assert!(err.to_string().contains("not a counter"), "{err}");
+        assert_eq!(notes_handler.get(id).await.unwrap().body(), "buy milk");
+        assert!(notes_handler.increment(id + 1, 1).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn append_adds_a_line_to_the_note() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+
+        let id = notes_handler.create_note("started").await?;
+        assert_eq!(notes_handler.append(id, " step one ").await?, 16);
+        assert_eq!(
+            notes_handler
+                .handle_command(Command::Append(id, "done".to_string()))
+                .await,
+            Some(Command::Appended(id, 21))
+        );
+        assert_eq!(
+            notes_handler.get(id).await.unwrap().body(),
+            "started\nstep one\ndone"
+        );
+        assert_eq!(notes_server.used_bytes(), 21);
+        assert_eq!(
+            notes_handler
+                .handle_command(Command::Append(id + 1, "lost".to_string()))
+                .await,
+            Some(Command::Error(format!("note {} not found", id + 1)))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn append_past_max_note_len_is_rejected() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            max_note_len: Some(10),
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+
+        let id = notes_handler.create_note("12345").await?;
+        assert_eq!(notes_handler.append(id, "789").await?, 9);
+        let err = notes_handler.append(id, "x").await.unwrap_err();
+        assert!(err.to_string().contains("10 byte length limit"), "{err}");
+        assert_eq!(notes_handler.get(id).await.unwrap().body(), "12345\n789");
+        assert_eq!(notes_server.used_bytes(), 9);
+        assert!(notes_handler.create_note("much too long").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_from_template_renders_body() -> Result<()> {
+        let mut templates = Templates::default();
+        templates.insert("standup", "{who}: {done}");
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            templates,
+            ..Default::default()
+        });
+        let mut notes_handler = notes_server.create_handler();
+
+        let vars = BTreeMap::from([
+            ("who".to_string(), "bob".to_string()),
+            ("done".to_string(), "deploy".to_string()),
+        ]);
+        let id = notes_handler.create_from_template("standup", &vars).await?;
+        assert_eq!(notes_handler.get(id).await.unwrap().body(), "bob: deploy");
+        assert!(notes_handler
+            .create_from_template("missing", &vars)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn seed_from_file_creates_notes() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("tempo-seed-{}.txt", std::process::id()));
+        tokio::fs::write(&path, "first\nsecond\n\nthird\n").await?;
+        let mut notes_server = NotesServer::default();
+        let seeded = notes_server.seed_from_file(&path).await;
+        tokio::fs::remove_file(&path).await?;
+
+        assert_eq!(seeded?, 3);
+        let bodies: Vec<_> = notes_server
+            .create_handler()
+            .get_all()
+            .await
+            .iter()
+            .map(|note| note.body().to_owned())
+            .collect();
+        assert_eq!(bodies, ["first", "second", "third"]);
+        Ok(())
+    }
+
+    #[test]
+    fn serves_on_a_runtime_with_the_given_worker_threads() -> Result<()> {
+        let runtime = super::runtime(Some(3))?;
+        assert_eq!(runtime.metrics().num_workers(), 3);
+        runtime.block_on(async {
+            let mut notes_server = NotesServer::default();
+            let (_, mut connection) = connect(&mut notes_server).await?;
+            connection
+                .write_frame(&Command::Create("threaded".to_string()).into())
+                .await?;
+            assert_eq!(
+                connection.read_frame().await?,
+                Some(Frame(Command::Created(0)))
+            );
+            notes_server.close().await
+        })?;
+        let cpus = std::thread::available_parallelism()?.get();
+        assert_eq!(super::runtime(None)?.metrics().num_workers(), cpus);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persisted_notes_are_restored() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("tempo-persist-{}.jsonl", std::process::id()));
+        let mut notes_server = NotesServer::default();
+        assert_eq!(notes_server.persist_to(&path, false, false).await?, 0);
+        let mut notes_handler = notes_server.create_handler();
+        notes_handler.create_note("first").await?;
+        notes_handler
+            .create_note_with_metadata("second", BTreeMap::from([("k".into(), "v".into())]))
+            .await?;
+        notes_handler.remove(0).await;
+        let created_at = notes_handler.get(1).await.unwrap().created_at;
+        // Snapshots are written in the background.
+        for _ in 0..100 {
+            let saved = tokio::fs::read_to_string(&path).await?;
+            if saved.lines().count() == 1 && saved.contains("second") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        notes_server.close().await?;
+
+        let mut restarted = NotesServer::default();
+        let restored = restarted.persist_to(&path, false, false).await;
+        tokio::fs::remove_file(&path).await?;
+        assert_eq!(restored?, 1);
+        let note = restarted.create_handler().get(1).await.unwrap();
+        assert_eq!(note.body(), "second");
+        assert_eq!(note.metadata()["k"], "v");
+        // Kept to the millisecond, so its timeout carries on rather than restarting.
+        let drift = created_at.duration_since(note.created_at)?;
+        assert!(drift < Duration::from_millis(1), "{drift:?}");
+        assert_eq!(restarted.used_bytes(), "second".len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn closing_flushes_every_note_to_the_persistence_file() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("tempo-close-{}.jsonl", std::process::id()));
+        let mut notes_server = NotesServer::default();
+        notes_server.persist_to(&path, false, false).await?;
+        let mut notes_handler = notes_server.create_handler();
+        for i in 0..20 {
+            notes_handler.create_note(&format!("note {i}")).await?;
+        }
+        // No waiting for the background snapshot: close writes its own.
+        notes_server.close().await?;
+
+        let saved = persistence::load(&path).await;
+        tokio::fs::remove_file(&path).await?;
+        let bodies: Vec<_> = saved?.iter().map(|note| note.body().to_string()).collect();
+        assert_eq!(
+            bodies,
+            (0..20).map(|i| format!("note {i}")).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sqlite_notes_survive_a_restart_and_still_expire() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("tempo-store-{}.sqlite", std::process::id()));
+        let config = || ServerConfig {
+            note_timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let mut notes_server =
+            NotesServer::with_store(config(), sqlite::SqliteNoteStore::open(&path)?);
+        notes_server.create_handler().create_note("durable").await?;
+        notes_server.close().await?;
+
+        let mut restarted =
+            NotesServer::with_store(config(), sqlite::SqliteNoteStore::open(&path)?);
+        let resumed = restarted.resume_stored().await;
+        let mut events = restarted.subscribe();
+        let mut handler = restarted.create_handler();
+        let created = handler.create_note("fresh").await;
+        let note = handler.get(0).await;
+        let expired = tokio::time::timeout(Duration::from_secs(5), async {
+            while !matches!(events.recv().await, Ok(NoteEvent::Expired(0))) {}
+        })
+        .await;
+        let left = sqlite::SqliteNoteStore::open(&path)?.get(0).await;
+        tokio::fs::remove_file(&path).await?;
+
+        assert_eq!(resumed?, 1);
+        assert_eq!(created?, 1, "resumed ids are not reused");
+        assert_eq!(note.unwrap().body(), "durable");
+        assert!(expired.is_ok(), "resumed note never expired");
+        assert!(left?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gzipped_persistence_is_compressed_and_restored() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("tempo-persist-{}.jsonl.gz", std::process::id()));
+        let mut notes_server = NotesServer::default();
+        notes_server.persist_to(&path, false, false).await?;
+        let mut notes_handler = notes_server.create_handler();
+        for i in 0..50 {
+            notes_handler
+                .create_note(&format!("remember to water the plants, day {i}"))
+                .await?;
+        }
+        for _ in 0..100 {
+            if persistence::load(&path).await?.len() == 50 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        notes_server.close().await?;
+        let saved = tokio::fs::read(&path).await?;
+
+        let mut restarted = NotesServer::default();
+        let restored = restarted.persist_to(&path, false, false).await;
+        tokio::fs::remove_file(&path).await?;
+        assert_eq!(restored?, 50);
+        assert!(saved.starts_with(&[0x1f, 0x8b]), "snapshot is not gzipped");
+        let plain = persistence::encode(&restarted.create_handler().get_all().await);
+        assert!(saved.len() < plain.len() / 2);
+        let note = restarted.create_handler().get(49).await.unwrap();
+        assert_eq!(note.body(), "remember to water the plants, day 49");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unwritable_persistence_falls_back_to_memory_when_optional() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("tempo-missing-{}", std::process::id()))
+            .join("notes.jsonl");
+        assert!(NotesServer::default()
+            .persist_to(&path, false, false)
+            .await
+            .is_err());
+
+        let mut notes_server = NotesServer::default();
+        assert_eq!(notes_server.persist_to(&path, true, false).await?, 0);
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        connection
+            .write_frame(&Command::Create("still served".to_string()).into())
+            .await?;
+        connection.read_frame().await?;
+        connection.write_frame(&Command::Read.into()).await?;
+        assert_eq!(
+            connection.read_frame().await?,
+            Some(Frame(Command::List(vec![listed(0, "still served")])))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_latency_is_recorded() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        // The second reply guarantees the first read has been recorded.
+        for _ in 0..2 {
+            connection.write_frame(&Command::Read.into()).await?;
+            connection.read_frame().await?;
+        }
+        let metrics = notes_server.metrics();
+        assert!(metrics.latencies["READ"].count >= 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stats_counts_commands_by_type() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        for body in ["a", "bb", "ccc"] {
+            connection
+                .write_frame(&Command::Create(body.to_string()).into())
+                .await?;
+            connection.read_frame().await?;
+        }
+        for _ in 0..2 {
+            connection.write_frame(&Command::Read.into()).await?;
+            connection.read_frame().await?;
+        }
+        connection.write_frame(&Command::Stats.into()).await?;
+        let Some(Frame(Command::StatsResult(stats))) = connection.read_frame().await? else {
+            panic!("expected stats");
+        };
+        assert_eq!(stats.notes, 3);
+        assert_eq!(stats.used_bytes, 6);
+        assert_eq!(
+            stats.commands,
+            BTreeMap::from([
+                ("CREATE".to_string(), 3),
+                ("READ".to_string(), 2),
+                ("STATS".to_string(), 1),
+            ])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics_follow_creates_expiry_and_clients() -> Result<()> {
+        let mut notes_server = NotesServer::new(Some(Duration::from_millis(200)));
+        let mut notes_handler = notes_server.create_handler();
+        for body in ["one", "two", "three"] {
+            notes_handler.create_note(body).await?;
+        }
+        notes_handler.delete(0).await?;
+        let (client, server) = tokio::io::duplex(1024);
+        notes_server.handle_stream(server).await?;
+        let mut connection = Connection::new(client);
+        connection.read_frame().await?;
+        connection.write_frame(&Command::Count.into()).await?;
+        connection.read_frame().await?;
+
+        let metrics = notes_server.metrics();
+        assert_eq!(metrics.notes_created, 3);
+        assert_eq!(metrics.notes_live, 2);
+        assert_eq!(metrics.notes_expired, 0);
+        assert_eq!(metrics.active_clients, 1);
+        assert_eq!(metrics.commands_handled, 1);
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        drop(connection);
+        while notes_server.connected_clients().await > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let metrics = notes_server.metrics();
+        assert_eq!(metrics.notes_created, 3);
+        assert_eq!(metrics.notes_live, 0);
+        assert_eq!(metrics.notes_expired, 2);
+        assert_eq!(metrics.active_clients, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn serves_with_profiling_enabled() -> Result<()> {
+        profiling::init("127.0.0.1:0".parse()?)?;
+        let mut notes_server = NotesServer::default();
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        connection.write_frame(&Command::Read.into()).await?;
+        assert!(matches!(
+            connection.read_frame().await?,
+            Some(Frame(Command::List(_)))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn frame_rate_limit_throttles_reading() -> Result<()> {
+        let rate = 100;
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            max_frames_per_sec: Some(rate),
+            ..Default::default()
+        });
+        let (_, mut connection) = connect(&mut notes_server).await?;
+
+        // A second's worth of frames passes at once; the rest at `rate` per second.
+        let start = Instant::now();
+        let pings = rate as u64 * 3 / 2;
+        for nonce in 0..pings {
+            connection.feed_frame(&Command::Ping(nonce).into())?;
+        }
+        connection.flush().await?;
+        for nonce in 0..pings {
+            assert_eq!(
+                connection.read_frame().await?,
+                Some(Frame(Command::Pong(nonce)))
+            );
+        }
+        assert!(
+            start.elapsed() >= Duration::from_millis(450),
+            "{:?}",
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pipelined_frames_beyond_in_flight_limit_are_all_handled() -> Result<()> {
+        let mut notes_server = NotesServer::with_config(ServerConfig {
+            max_in_flight: 2,
+            ..Default::default()
+        });
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        let pipelined = 100;
+        for i in 0..pipelined {
+            connection.feed_frame(&Command::Swap(i, i).into())?;
+        }
+        connection.flush().await?;
+        for i in 0..pipelined {
+            // Both ids are missing, so each swap is answered with an error, in order.
+            let Some(Frame(Command::Error(message))) = connection.read_frame().await? else {
+                panic!("expected error reply");
+            };
+            assert_eq!(message, format!("note {i} not found"));
+        }
+        assert_eq!(notes_server.metrics().commands["SWAP"], pipelined);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_page_resumes_from_cursor_after_reconnect() -> Result<()> {
+        let mut notes_server = NotesServer::default();
+        let mut notes_handler = notes_server.create_handler();
+        for i in 0..5 {
+            notes_handler.create_note(&format!("note {i}")).await?;
+        }
+
+        async fn page(
+            connection: &mut Connection<DuplexStream>,
+            cursor: Option<String>,
+        ) -> Result<(Vec<String>, Option<String>)> {
+            let request = Command::ReadPage { cursor, limit: 2 };
+            connection.write_frame(&request.into()).await?;
+            let Some(Frame(Command::Page { notes, cursor })) = connection.read_frame().await?
+            else {
+                panic!("expected page");
+            };
+            Ok((notes, cursor))
+        }
+
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        let (mut seen, mut cursor) = page(&mut connection, None).await?;
+        drop(connection);
+
+        let (_, mut connection) = connect(&mut notes_server).await?;
+        while cursor.is_some() {
+            let (notes, next) = page(&mut connection, cursor).await?;
+            seen.extend(notes);
+            cursor = next;
+        }
+        let expected: Vec<_> = (0..5).map(|i| format!("note {i}")).collect();
+        assert_eq!(seen, expected);
+
+        connection
+            .write_frame(
+                &Command::ReadPage {
+                    cursor: Some("not a cursor".to_string()),
+                    limit: 2,
+                }
+                .into(),
+            )
+            .await?;
+        assert!(matches!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Error(_)))
+        ));
+        Ok(())
+    }
+
+    fn budget_server(max_bytes: usize, evict_oldest: bool) -> NotesServer {
+        NotesServer::with_config(ServerConfig {
+            max_bytes: Some(max_bytes),
+            evict_oldest,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn create_over_byte_budget_is_rejected() -> Result<()> {
+        let mut notes_server = budget_server(10, false);
+        let mut notes_handler = notes_server.create_handler();
+
+        notes_handler.create_note("12345").await?;
+        notes_handler.create_note("67890").await?;
+        assert_eq!(notes_server.used_bytes(), 10);
+        assert!(notes_handler.create_note("x").await.is_err());
+        assert_eq!(notes_handler.get_all().await.len(), 2);
+
+        notes_handler.remove(0).await;
+        assert_eq!(notes_server.used_bytes(), 5);
+        notes_handler.create_note("abcde").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_over_byte_budget_evicts_oldest() -> Result<()> {
+        let mut notes_server = budget_server(10, true);
+        let mut notes_handler = notes_server.create_handler();
+
+        notes_handler.create_note("first").await?;
+        notes_handler.create_note("second").await?;
+        let bodies: Vec<_> = notes_handler
+            .get_all()
+            .await
+            .iter()
+            .map(|note| note.body().to_owned())
+            .collect();
+        assert_eq!(bodies, ["second"]);
+        assert_eq!(notes_server.used_bytes(), 6);
+        assert!(notes_handler.create_note("way too long").await.is_err());
+        Ok(())
+    }
+
+    /// Records which store operations the server drives, on top of a [`MemoryStore`].
+    #[derive(Debug, Default)]
+    struct RecordingStore {
+        inner: MemoryStore,
+        calls: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl RecordingStore {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl NoteStore for RecordingStore {
+        async fn insert(&mut self, note: Note) -> Result<()> {
+            self.record(format!("insert {}", note.id()));
+            self.inner.insert(note).await
+        }
+        async fn get(&self, id: NoteID) -> Result<Option<Note>> {
+            self.record(format!("get {id}"));
+            self.inner.get(id).await
+        }
+        async fn remove(&mut self, id: NoteID) -> Result<Option<Note>> {
+            self.record(format!("remove {id}"));
+            self.inner.remove(id).await
+        }
+        async fn all(&self) -> Result<Vec<Note>> {
+            self.inner.all().await
+        }
+        async fn clear(&mut self) -> Result<()> {
+            self.inner.clear().await
+        }
+    }
+
+    #[tokio::test]
+    async fn server_drives_a_custom_store() -> Result<()> {
+        let store = RecordingStore::default();
+        let calls = store.calls.clone();
+        let timeout = Duration::from_millis(100);
+        let mut notes_server = NotesServer::with_store(
+            ServerConfig {
+                note_timeout: timeout,
+                ..Default::default()
+            },
+            store,
+        );
+        let mut events = notes_server.subscribe();
+        let mut notes_handler = notes_server.create_handler();
+
+        let kept = notes_handler.create_note("kept").await?;
+        let deleted = notes_handler.create_note("deleted").await?;
+        assert_eq!(notes_handler.get(kept).await.unwrap().body(), "kept");
+        notes_handler.delete(deleted).await?;
+        loop {
+            if let NoteEvent::Expired(id) = events.recv().await? {
+                assert_eq!(id, kept);
+                break;
+            }
+        }
+
+        let calls = calls.lock().unwrap().clone();
+        for call in [
+            format!("insert {kept}"),
+            format!("insert {deleted}"),
+            format!("get {kept}"),
+            format!("remove {deleted}"),
+            format!("remove {kept}"),
+        ] {
+            assert!(calls.contains(&call), "{call:?} missing from {calls:?}");
+        }
+        assert_eq!(notes_server.used_bytes(), 0);
+        Ok(())
+    }
 }
@@ -0,0 +1,19 @@
+use color_eyre::eyre::Result;
+use tracing::Subscriber;
+use tracing_subscriber::{prelude::*, registry::LookupSpan, EnvFilter, Layer};
+
+/// Log events to stdout, filtered by `RUST_LOG` when set and at info and
+/// above otherwise.
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt::layer().with_filter(filter)
+}
+
+/// Install a global subscriber that logs through [`layer`].
+pub fn init() -> Result<()> {
+    tracing_subscriber::registry().with(layer()).try_init()?;
+    Ok(())
+}
@@ -1,25 +1,97 @@
 use color_eyre::eyre::Result;
-use server::NotesServer;
-use tokio::net::TcpListener;
+use server::{logging, profiling, settings, sqlite::SqliteNoteStore, systemd, tls, NotesServer};
+use tokio::{
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+};
+use tracing::Instrument;
 mod cli;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     color_eyre::install()?;
     let args = cli::parse();
-    let mut notes_server = NotesServer::default();
+    server::runtime(args.threads.map(|threads| threads as usize))?.block_on(serve(args))
+}
 
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?;
-    println!("Listening at {}", listener.local_addr()?);
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        println!("Accepted client: {}", addr);
-        match notes_server.handle_connection(socket).await {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                continue;
+async fn serve(args: cli::Args) -> Result<()> {
+    if args.profile {
+        profiling::init(profiling::CONSOLE_ADDR)?;
+        tracing::info!(addr = %profiling::CONSOLE_ADDR, "serving tokio-console traces");
+    } else {
+        logging::init()?;
+    }
+    tracing::info!(
+        workers = tokio::runtime::Handle::current().metrics().num_workers(),
+        "running"
+    );
+    let config = args.config()?;
+    let mut notes_server = match &args.sqlite {
+        Some(path) => {
+            let mut store = SqliteNoteStore::open(path)?;
+            // With idle expiry a note's age says nothing about whether it expired.
+            if !config.idle_expiry {
+                let swept = store.sweep(config.note_timeout)?;
+                tracing::info!(swept, path = %path.display(), "swept expired notes");
             }
+            let mut notes_server = NotesServer::with_store(config, store);
+            let resumed = notes_server.resume_stored().await?;
+            tracing::info!(resumed, path = %path.display(), "resumed notes");
+            notes_server
         }
+        None => NotesServer::with_config(config),
+    };
+    if let Some(path) = &args.persist {
+        let restored = notes_server
+            .persist_to(path, args.persistence_optional, args.compress_storage)
+            .await?;
+        tracing::info!(restored, path = %path.display(), "restored notes");
+    }
+    if let Some(seed) = &args.seed {
+        let seeded = notes_server.seed_from_file(seed).await?;
+        tracing::info!(seeded, path = %seed.display(), "seeded notes");
+    }
+
+    if let (true, Some(cert), Some(key)) = (args.tls, &args.cert, &args.key) {
+        notes_server.serve_tls(tls::acceptor(cert, key)?);
+        tracing::info!(cert = %cert.display(), "serving TLS");
     }
+
+    let listener = if args.systemd {
+        systemd::listener()?
+    } else {
+        TcpListener::bind(format!("0.0.0.0:{}", args.port)).await?
+    };
+    tracing::info!(addr = %listener.local_addr()?, "listening");
+    let mut hangups = signal(SignalKind::hangup())?;
+    let reloader = notes_server.reloader();
+    let config_path = args.config.clone();
+    tokio::spawn(
+        async move {
+            while hangups.recv().await.is_some() {
+                let Some(path) = &config_path else {
+                    tracing::warn!("no --config file to reload");
+                    continue;
+                };
+                let mut config = reloader.config().as_ref().clone();
+                match settings::load(&mut config, path) {
+                    Ok(()) => {
+                        reloader.reload(config);
+                        tracing::info!(path = %path.display(), "reloaded settings");
+                    }
+                    Err(e) => tracing::error!("keeping current settings: {e}"),
+                }
+            }
+        }
+        .instrument(tracing::info_span!("reload")),
+    );
+    let mut terminations = signal(SignalKind::terminate())?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => tracing::info!("received SIGINT"),
+            _ = terminations.recv() => tracing::info!("received SIGTERM"),
+        }
+    };
+    // The report is logged as the server closes.
+    notes_server.run_until_shutdown(listener, shutdown).await?;
+    Ok(())
 }
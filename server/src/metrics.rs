@@ -0,0 +1,221 @@
+use common::protocol::Command;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the latency histogram buckets. Slower observations land in
+/// a final overflow bucket.
+pub const LATENCY_BUCKETS: [Duration; 10] = [
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// Observation counts per bucket in [`LATENCY_BUCKETS`], plus the overflow bucket.
+    pub buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count).ok().filter(|&count| count > 0)?;
+        Some(self.sum / count)
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    latencies: Mutex<BTreeMap<&'static str, Histogram>>,
+    /// Handled commands, indexed by command byte.
+    command_counts: [AtomicU64; 256],
+    notes_created: AtomicU64,
+    notes_expired: AtomicU64,
+    notes_live: AtomicU64,
+    expiry_timers: AtomicU64,
+    active_clients: Arc<AtomicU64>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            latencies: Mutex::default(),
+            command_counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            notes_created: AtomicU64::new(0),
+            notes_expired: AtomicU64::new(0),
+            notes_live: AtomicU64::new(0),
+            expiry_timers: AtomicU64::new(0),
+            active_clients: Arc::default(),
+        }
+    }
+}
+
+/// Counts a client handler as active until dropped, however the handler ends.
+#[derive(Debug)]
+pub struct ClientGuard(Arc<AtomicU64>);
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn note_created(&self) {
+        self.notes_created.fetch_add(1, Ordering::Relaxed);
+        self.notes_live.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a note taken over from persistence or a store, which was created in an earlier run.
+    pub fn note_restored(&self) {
+        self.notes_live.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a note deleted or evicted before it expired.
+    pub fn note_removed(&self) {
+        self.notes_live.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn note_expired(&self) {
+        self.notes_expired.fetch_add(1, Ordering::Relaxed);
+        self.notes_live.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Record how many expiry timers are running, including ones not yet reaped.
+    pub fn set_expiry_timers(&self, timers: usize) {
+        self.expiry_timers.store(timers as u64, Ordering::Relaxed);
+    }
+
+    pub fn client_connected(&self) -> ClientGuard {
+        self.active_clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(self.active_clients.clone())
+    }
+
+    pub fn count_command(&self, command: &Command) {
+        self.command_counts[command.byte() as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Handled commands per command name, omitting those never seen.
+    pub fn command_counts(&self) -> BTreeMap<String, u64> {
+        self.command_counts
+            .iter()
+            .enumerate()
+            .filter_map(|(byte, count)| {
+                let count = count.load(Ordering::Relaxed);
+                let command = Command::try_from(byte as u8).ok()?;
+                (count > 0).then(|| (command.name().to_string(), count))
+            })
+            .collect()
+    }
+
+    /// Record how long a command of the given type took to handle.
+    pub fn record_latency(&self, command: &'static str, latency: Duration) {
+        let mut latencies = self.latencies.lock().expect("metrics lock poisoned");
+        latencies.entry(command).or_default().record(latency);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let latencies = self.latencies.lock().expect("metrics lock poisoned");
+        let commands = self.command_counts();
+        MetricsSnapshot {
+            latencies: latencies
+                .iter()
+                .map(|(command, histogram)| (command.to_string(), histogram.clone()))
+                .collect(),
+            commands_handled: commands.values().sum(),
+            commands,
+            notes_created: self.notes_created.load(Ordering::Relaxed),
+            notes_expired: self.notes_expired.load(Ordering::Relaxed),
+            notes_live: self.notes_live.load(Ordering::Relaxed),
+            expiry_timers: self.expiry_timers.load(Ordering::Relaxed),
+            active_clients: self.active_clients.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of the server [`Metrics`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Handling latency per command type, keyed by command name.
+    pub latencies: BTreeMap<String, Histogram>,
+    /// Handled commands per command type, keyed by command name.
+    pub commands: BTreeMap<String, u64>,
+    /// Handled commands of every type together.
+    pub commands_handled: u64,
+    /// Notes created since start, whether or not they are still stored.
+    pub notes_created: u64,
+    /// Notes removed for outliving their timeout or ttl.
+    pub notes_expired: u64,
+    /// Notes stored now, including any restored on startup.
+    pub notes_live: u64,
+    /// Expiry timers running now, one per note waiting to expire.
+    pub expiry_timers: u64,
+    /// Client handlers running now.
+    pub active_clients: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.record(Duration::from_micros(50));
+        histogram.record(Duration::from_millis(3));
+        histogram.record(Duration::from_secs(5));
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[4], 1);
+        assert_eq!(histogram.buckets[LATENCY_BUCKETS.len()], 1);
+        assert_eq!(histogram.count, 3);
+        assert_eq!(histogram.mean(), Some(histogram.sum / 3));
+    }
+
+    #[test]
+    fn client_guards_count_active_clients() {
+        let metrics = Metrics::default();
+        let first = metrics.client_connected();
+        let second = metrics.client_connected();
+        assert_eq!(metrics.snapshot().active_clients, 2);
+        drop(first);
+        assert_eq!(metrics.snapshot().active_clients, 1);
+        drop(second);
+        assert_eq!(metrics.snapshot().active_clients, 0);
+    }
+
+    #[test]
+    fn command_counts_by_name() {
+        let metrics = Metrics::default();
+        metrics.count_command(&Command::Read);
+        metrics.count_command(&Command::Read);
+        metrics.count_command(&Command::Quit);
+        assert_eq!(
+            metrics.command_counts(),
+            BTreeMap::from([("QUIT".to_string(), 1), ("READ".to_string(), 2)])
+        );
+    }
+}
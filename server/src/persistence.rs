@@ -0,0 +1,141 @@
+use color_eyre::eyre::Result;
+use common::{ClientID, Note, NoteID};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Read, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// First bytes of every gzip stream.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// One line of the persistence file. Times are kept, so restored notes
+/// expire when they would have without the restart. Lines written before
+/// they were have no times, and those notes start their timeout afresh.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredNote {
+    id: NoteID,
+    body: String,
+    #[serde(default)]
+    tags: BTreeSet<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    owner: Option<ClientID>,
+    /// The note's own TTL in milliseconds, if it has one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl_ms: Option<u64>,
+    /// Milliseconds since the Unix epoch.
+    #[serde(default)]
+    created_at_ms: Option<u64>,
+    #[serde(default)]
+    refreshed_at_ms: Option<u64>,
+    #[serde(default)]
+    last_accessed_ms: Option<u64>,
+    #[serde(default)]
+    generation: u64,
+}
+
+fn to_unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn from_unix_ms(ms: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms)
+}
+
+/// Whether snapshots written to `path` are gzipped unless asked otherwise,
+/// i.e. whether it ends in `.gz`.
+pub fn is_gzip_path(path: impl AsRef<Path>) -> bool {
+    path.as_ref().extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Read the notes saved at `path`, one JSON object per line, gunzipping the
+/// file if it is compressed. A missing file holds no notes.
+pub async fn load(path: impl AsRef<Path>) -> Result<Vec<Note>> {
+    let bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let contents = if bytes.starts_with(&GZIP_MAGIC) {
+        let mut contents = String::new();
+        GzDecoder::new(&bytes[..]).read_to_string(&mut contents)?;
+        contents
+    } else {
+        String::from_utf8(bytes)?
+    };
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let stored: StoredNote = serde_json::from_str(line)?;
+            let mut note = Note::builder()
+                .id(stored.id)
+                .body(stored.body)
+                .generation(stored.generation);
+            if let Some(ms) = stored.created_at_ms {
+                note = note.created_at(from_unix_ms(ms));
+            }
+            if let Some(ms) = stored.refreshed_at_ms {
+                note = note.refreshed_at(from_unix_ms(ms));
+            }
+            if let Some(ms) = stored.last_accessed_ms {
+                note = note.last_accessed(from_unix_ms(ms));
+            }
+            Ok(note
+                .build()
+                .with_tags(stored.tags)
+                .with_metadata(stored.metadata)
+                .with_owner(stored.owner)
+                .with_ttl(stored.ttl_ms.map(Duration::from_millis)))
+        })
+        .collect()
+}
+
+/// Serialize `notes` in the format [`load`] reads.
+pub fn encode(notes: &[Note]) -> String {
+    notes
+        .iter()
+        .map(|note| {
+            let stored = StoredNote {
+                id: note.id(),
+                body: note.body().to_owned(),
+                tags: note.tags().clone(),
+                metadata: note.metadata().clone(),
+                owner: note.owner(),
+                ttl_ms: note.ttl().map(|ttl| ttl.as_millis() as u64),
+                created_at_ms: Some(to_unix_ms(note.created_at)),
+                refreshed_at_ms: Some(to_unix_ms(note.refreshed_at())),
+                last_accessed_ms: Some(to_unix_ms(note.last_accessed())),
+                generation: note.generation(),
+            };
+            serde_json::to_string(&stored).expect("notes always serialize") + "\n"
+        })
+        .collect()
+}
+
+/// Replace the file at `path` with `contents`, gzipped if `gzip`, writing and
+/// syncing a sibling file first so a failed write or a crash never leaves a
+/// truncated snapshot behind.
+pub async fn save(path: impl AsRef<Path>, contents: &str, gzip: bool) -> Result<()> {
+    let path = path.as_ref();
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let bytes = if gzip {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(contents.as_bytes())?;
+        encoder.finish()?
+    } else {
+        contents.as_bytes().to_vec()
+    };
+    let mut file = tokio::fs::File::create(&partial).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, &bytes).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&partial, path).await?;
+    Ok(())
+}
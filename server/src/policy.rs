@@ -0,0 +1,76 @@
+use color_eyre::eyre::{anyhow, Result};
+use regex::Regex;
+
+/// Whether note bodies must match a [`ContentPolicy`]'s pattern or must not.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PolicyMode {
+    /// Only bodies matching the pattern are stored.
+    #[default]
+    Require,
+    /// Bodies matching the pattern are rejected.
+    Forbid,
+}
+
+/// A regex every created note is checked against, compiled once when the
+/// server is configured.
+#[derive(Debug, Clone)]
+pub struct ContentPolicy {
+    pattern: Regex,
+    mode: PolicyMode,
+}
+
+impl ContentPolicy {
+    pub fn new(pattern: &str, mode: PolicyMode) -> Result<Self> {
+        let pattern = Regex::new(pattern).map_err(|e| anyhow!("invalid content regex: {e}"))?;
+        Ok(Self { pattern, mode })
+    }
+
+    pub fn pattern(&self) -> &str {
+        self.pattern.as_str()
+    }
+
+    pub fn mode(&self) -> PolicyMode {
+        self.mode
+    }
+
+    /// Fail with the reason `body` may not be stored, if it breaks the policy.
+    pub fn check(&self, body: &str) -> Result<()> {
+        match (self.mode, self.pattern.is_match(body)) {
+            (PolicyMode::Require, false) => Err(anyhow!(
+                "note does not match the required pattern /{}/",
+                self.pattern
+            )),
+            (PolicyMode::Forbid, true) => Err(anyhow!(
+                "note matches the forbidden pattern /{}/",
+                self.pattern
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_pattern_must_match() -> Result<()> {
+        let policy = ContentPolicy::new(r"^[A-Z]+-\d+: ", PolicyMode::Require)?;
+        policy.check("OPS-12: restart the cache")?;
+        assert!(policy.check("restart the cache").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn forbidden_pattern_must_not_match() -> Result<()> {
+        let policy = ContentPolicy::new(r"[\w.+-]+@[\w-]+\.\w+", PolicyMode::Forbid)?;
+        policy.check("call the vendor")?;
+        assert!(policy.check("mail bob@example.com").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_pattern_is_rejected() {
+        assert!(ContentPolicy::new("(unclosed", PolicyMode::Require).is_err());
+    }
+}
@@ -0,0 +1,25 @@
+use crate::logging;
+use color_eyre::eyre::Result;
+use std::net::SocketAddr;
+use tracing_subscriber::prelude::*;
+
+/// Default address `tokio-console` connects to.
+pub const CONSOLE_ADDR: SocketAddr = SocketAddr::new(
+    console_subscriber::Server::DEFAULT_IP,
+    console_subscriber::Server::DEFAULT_PORT,
+);
+
+/// Install a global subscriber that serves task and span data to `tokio-console`
+/// on `addr`, logging as [`logging::init`] does besides. Relies on the
+/// `tokio_unstable` cfg set in `.cargo/config.toml`.
+pub fn init(addr: SocketAddr) -> Result<()> {
+    let console = console_subscriber::ConsoleLayer::builder()
+        .with_default_env()
+        .server_addr(addr)
+        .spawn();
+    tracing_subscriber::registry()
+        .with(console)
+        .with(logging::layer())
+        .try_init()?;
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use color_eyre::eyre::{anyhow, Result};
+use std::{fs, path::Path, str::FromStr, time::Duration};
+
+use crate::ServerConfig;
+
+/// Override `config` with the settings in a file of `key = value` lines; blank
+/// lines and lines starting with `#` are skipped. Durations are in seconds and
+/// `none` clears an optional setting.
+pub fn load(config: &mut ServerConfig, path: impl AsRef<Path>) -> Result<()> {
+    apply(config, &fs::read_to_string(path)?)
+}
+
+/// Like [`load`], reading the settings from `source`.
+pub fn apply(config: &mut ServerConfig, source: &str) -> Result<()> {
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or(anyhow!("line {}: expected `key = value`", number + 1))?;
+        let (key, value) = (key.trim(), value.trim());
+        set(config, key, value).map_err(|e| anyhow!("line {}: {key}: {e}", number + 1))?;
+    }
+    Ok(())
+}
+
+fn set(config: &mut ServerConfig, key: &str, value: &str) -> Result<()> {
+    match key {
+        "note_timeout" => config.note_timeout = seconds(value)?,
+        "max_bytes" => config.max_bytes = optional(value)?,
+        "evict_oldest" => config.evict_oldest = value.parse()?,
+        "max_note_len" => config.max_note_len = optional(value)?,
+        "max_in_flight" => match value.parse()? {
+            0 => return Err(anyhow!("must be at least 1")),
+            max_in_flight => config.max_in_flight = max_in_flight,
+        },
+        "expiry_warning" => config.expiry_warning = optional(value)?.map(Duration::from_secs),
+        "handshake_timeout" => config.handshake_timeout = optional(value)?.map(Duration::from_secs),
+        "echo_creates" => config.echo_creates = value.parse()?,
+        "dry_run" => config.dry_run = value.parse()?,
+        "dedupe" => config.dedupe = value.parse()?,
+        "max_frames_per_sec" => match optional(value)? {
+            Some(0) => return Err(anyhow!("must be at least 1")),
+            max_frames_per_sec => config.max_frames_per_sec = max_frames_per_sec,
+        },
+        "create_rate_warning" => config.create_rate_warning = optional(value)?,
+        "create_rate_window" => config.create_rate_window = seconds(value)?,
+        "keepalive_interval" => {
+            config.keepalive_interval = optional(value)?.map(Duration::from_secs)
+        }
+        "keepalive_timeout" => config.keepalive_timeout = seconds(value)?,
+        "idle_expiry" => config.idle_expiry = value.parse()?,
+        "idle_expiry_counts_lists" => config.idle_expiry_counts_lists = value.parse()?,
+        _ => return Err(anyhow!("unknown setting")),
+    }
+    Ok(())
+}
+
+fn seconds(value: &str) -> Result<Duration> {
+    Ok(Duration::from_secs(value.parse()?))
+}
+
+fn optional<T>(value: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match value {
+        "none" => Ok(None),
+        value => Ok(Some(value.parse()?)),
+    }
+}
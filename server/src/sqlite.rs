@@ -0,0 +1,309 @@
+use crate::store::NoteStore;
+use async_trait::async_trait;
+use color_eyre::eyre::{anyhow, Result};
+use common::{Note, NoteID};
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use std::{
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Duration;
+
+/// The id is the table's rowid, so lookups by id need no index of their own.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS notes (
+        id INTEGER PRIMARY KEY,
+        body TEXT NOT NULL,
+        tags TEXT NOT NULL,
+        metadata TEXT NOT NULL,
+        owner INTEGER,
+        created_at INTEGER NOT NULL,
+        last_accessed INTEGER NOT NULL,
+        generation INTEGER NOT NULL,
+        ttl_ms INTEGER,
+        refreshed_at INTEGER,
+        hash TEXT
+    );
+    CREATE INDEX IF NOT EXISTS notes_by_created_at ON notes (created_at);
+";
+
+const COLUMNS: &str =
+    "id, body, tags, metadata, owner, created_at, last_accessed, generation, ttl_ms, refreshed_at, hash";
+
+/// Keeps notes in a SQLite database, so they survive restarts and can be
+/// queried with other tools. Times and TTLs are stored as milliseconds; tags
+/// and metadata as JSON.
+///
+/// Statements run on the calling task. They are short, and the server only
+/// runs one store operation at a time anyway.
+#[derive(Debug)]
+pub struct SqliteNoteStore {
+    /// Connections can't be shared between threads; the lock is never
+    /// contended since the server serializes store access.
+    connection: Mutex<Connection>,
+}
+
+impl SqliteNoteStore {
+    /// Open the database at `path`, creating it and its tables if needed.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// A database that only lives as long as the store.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self> {
+        connection.execute_batch(SCHEMA)?;
+        // Databases created before notes had their own TTLs, kept their
+        // touch time apart from their creation or were looked up by hash lack
+        // the columns.
+        for (column, kind) in [
+            ("ttl_ms", "INTEGER"),
+            ("refreshed_at", "INTEGER"),
+            ("hash", "TEXT"),
+        ] {
+            let exists: bool = connection.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('notes') WHERE name = ?1",
+                [column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                connection
+                    .execute_batch(&format!("ALTER TABLE notes ADD COLUMN {column} {kind}"))?;
+            }
+        }
+        let unhashed: Vec<(NoteID, String)> = connection
+            .prepare("SELECT id, body FROM notes WHERE hash IS NULL")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        for (id, body) in unhashed {
+            connection.execute(
+                "UPDATE notes SET hash = ?1 WHERE id = ?2",
+                params![common::content_hash(&body), id],
+            )?;
+        }
+        connection.execute_batch("CREATE INDEX IF NOT EXISTS notes_by_hash ON notes (hash)")?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>> {
+        self.connection
+            .lock()
+            .map_err(|_| anyhow!("sqlite connection poisoned"))
+    }
+
+    /// Delete every note created or last touched more than its TTL, or else
+    /// `timeout`, ago in one statement, returning how many were deleted.
+    pub fn sweep(&mut self, timeout: Duration) -> Result<usize> {
+        let deleted = self.connection()?.execute(
+            "DELETE FROM notes WHERE COALESCE(refreshed_at, created_at) + COALESCE(ttl_ms, ?1) < ?2",
+            [timeout.as_millis() as i64, to_unix_ms(SystemTime::now())],
+        )?;
+        Ok(deleted)
+    }
+}
+
+#[async_trait]
+impl NoteStore for SqliteNoteStore {
+    async fn insert(&mut self, note: Note) -> Result<()> {
+        self.connection()?.execute(
+            &format!(
+                "INSERT OR REPLACE INTO notes ({COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"
+            ),
+            params![
+                note.id(),
+                note.body(),
+                serde_json::to_string(note.tags())?,
+                serde_json::to_string(note.metadata())?,
+                note.owner(),
+                to_unix_ms(note.created_at),
+                to_unix_ms(note.last_accessed()),
+                note.generation(),
+                note.ttl().map(|ttl| ttl.as_millis() as i64),
+                to_unix_ms(note.refreshed_at()),
+                note.hash(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn get(&self, id: NoteID) -> Result<Option<Note>> {
+        let note = self
+            .connection()?
+            .query_row(
+                &format!("SELECT {COLUMNS} FROM notes WHERE id = ?1"),
+                [id],
+                read_note,
+            )
+            .optional()?;
+        Ok(note)
+    }
+
+    async fn remove(&mut self, id: NoteID) -> Result<Option<Note>> {
+        let note = self.get(id).await?;
+        if note.is_some() {
+            self.connection()?
+                .execute("DELETE FROM notes WHERE id = ?1", [id])?;
+        }
+        Ok(note)
+    }
+
+    async fn all(&self) -> Result<Vec<Note>> {
+        let connection = self.connection()?;
+        let mut statement =
+            connection.prepare(&format!("SELECT {COLUMNS} FROM notes ORDER BY id"))?;
+        let notes = statement
+            .query_map([], read_note)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(notes)
+    }
+
+    async fn find_by_hash(&self, hash: &str) -> Result<Vec<Note>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&format!(
+            "SELECT {COLUMNS} FROM notes WHERE hash = ?1 ORDER BY id"
+        ))?;
+        let notes = statement
+            .query_map([hash], read_note)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(notes)
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.connection()?.execute("DELETE FROM notes", [])?;
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<usize> {
+        let count = self
+            .connection()?
+            .query_row("SELECT COUNT(*) FROM notes", [], |row| row.get(0))?;
+        Ok(count)
+    }
+}
+
+/// Build a note from a row selected with [`COLUMNS`].
+fn read_note(row: &Row) -> rusqlite::Result<Note> {
+    let tags: Vec<String> = read_json(row, 2)?;
+    let created_at = from_unix_ms(row.get(5)?);
+    let refreshed_at = row.get::<_, Option<i64>>(9)?.map(from_unix_ms);
+    Ok(Note::builder()
+        .id(row.get(0)?)
+        .body(row.get::<_, String>(1)?)
+        .created_at(created_at)
+        .refreshed_at(refreshed_at.unwrap_or(created_at))
+        .last_accessed(from_unix_ms(row.get(6)?))
+        .generation(row.get(7)?)
+        .build()
+        .with_tags(tags)
+        .with_metadata(read_json(row, 3)?)
+        .with_owner(row.get(4)?)
+        .with_ttl(
+            row.get::<_, Option<i64>>(8)?
+                .map(|ms| Duration::from_millis(ms.max(0) as u64)),
+        ))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(row: &Row, column: usize) -> rusqlite::Result<T> {
+    let text: String = row.get(column)?;
+    serde_json::from_str(&text)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(column, Type::Text, e.into()))
+}
+
+fn to_unix_ms(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn from_unix_ms(ms: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms.max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn notes_round_trip_through_the_database() -> Result<()> {
+        let mut store = SqliteNoteStore::open_in_memory()?;
+        let note = Note::new(4, "buy milk".to_string())
+            .with_tags(["errand"])
+            .with_metadata(BTreeMap::from([("priority".into(), "3".into())]))
+            .with_owner(Some(7))
+            .with_ttl(Some(Duration::from_secs(5)));
+        store.insert(note).await?;
+        store.insert(Note::new(2, "call mum".to_string())).await?;
+
+        let stored = store.get(4).await?.unwrap();
+        assert_eq!(stored.body(), "buy milk");
+        assert!(stored.tags().contains("errand"));
+        assert_eq!(stored.metadata()["priority"], "3");
+        assert_eq!(stored.owner(), Some(7));
+        assert_eq!(stored.ttl(), Some(Duration::from_secs(5)));
+        assert!(stored.elapsed() < Duration::from_secs(1));
+        let ids: Vec<_> = store.all().await?.iter().map(Note::id).collect();
+        assert_eq!(ids, [2, 4]);
+
+        assert_eq!(store.remove(4).await?.unwrap().body(), "buy milk");
+        assert!(store.get(4).await?.is_none());
+        assert!(store.remove(4).await?.is_none());
+        assert_eq!(store.count().await?, 1);
+        store.clear().await?;
+        assert_eq!(store.count().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn inserting_an_existing_id_replaces_the_note() -> Result<()> {
+        let mut store = SqliteNoteStore::open_in_memory()?;
+        let mut note = Note::new(1, "draft".to_string());
+        store.insert(note.clone()).await?;
+        note.set_body("final".to_string());
+        let generation = note.touch();
+        let refreshed_at = note.refreshed_at();
+        store.insert(note).await?;
+
+        let stored = store.get(1).await?.unwrap();
+        assert_eq!(stored.body(), "final");
+        assert_eq!(stored.generation(), generation);
+        assert_eq!(to_unix_ms(stored.refreshed_at()), to_unix_ms(refreshed_at));
+        let draft = store.find_by_hash(&common::content_hash("draft")).await?;
+        assert!(draft.is_empty());
+        let found = store.find_by_hash(stored.hash()).await?;
+        assert_eq!(found.iter().map(Note::id).collect::<Vec<_>>(), [1]);
+        assert_eq!(store.count().await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sweep_deletes_expired_notes() -> Result<()> {
+        let mut store = SqliteNoteStore::open_in_memory()?;
+        let old = Note::builder()
+            .id(0)
+            .body("old")
+            .created_at(SystemTime::now() - Duration::from_secs(120))
+            .build();
+        store.insert(old).await?;
+        store.insert(Note::new(1, "new".to_string())).await?;
+        let short = Note::builder()
+            .id(2)
+            .body("short")
+            .ttl(Duration::from_secs(10))
+            .created_at(SystemTime::now() - Duration::from_secs(30))
+            .build();
+        store.insert(short).await?;
+
+        assert_eq!(store.sweep(Duration::from_secs(60))?, 2);
+        assert!(store.get(0).await?.is_none());
+        assert!(store.get(1).await?.is_some());
+        assert!(store.get(2).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use color_eyre::eyre::{anyhow, Result};
+use common::{protocol::Command, ClientID};
+
+/// Where a client connection is in its lifecycle, which decides the commands it may send.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The client was sent its id and has not answered yet. It may negotiate
+    /// with `Hello` here; any request acknowledges the id as well.
+    #[default]
+    AwaitingHandshake,
+    /// Serving requests.
+    Ready,
+    /// The client sent `Quit` or `Disconnect`; everything after it is rejected.
+    Closing,
+}
+
+impl ConnectionState {
+    /// Check that `client` may send `command` now, returning the state it
+    /// moves the connection to.
+    pub fn next(self, command: &Command, client: ClientID) -> Result<Self> {
+        match (self, command) {
+            (ConnectionState::Closing, _) => Err(anyhow!("connection is closing")),
+            (_, command) if !is_request(command) => {
+                Err(anyhow!("{command} is only sent by the server"))
+            }
+            (ConnectionState::AwaitingHandshake, Command::Hello { .. }) => {
+                Ok(ConnectionState::Ready)
+            }
+            (ConnectionState::Ready, Command::Hello { .. }) => {
+                Err(anyhow!("{command} is only valid during the handshake"))
+            }
+            (_, Command::Quit) => Ok(ConnectionState::Closing),
+            // Disconnecting another client kicks it and leaves this connection open.
+            (_, Command::Disconnect(target)) if *target == client => Ok(ConnectionState::Closing),
+            _ => Ok(ConnectionState::Ready),
+        }
+    }
+}
+
+/// Whether clients send `command`, as opposed to only receiving it from the server.
+/// Pings go both ways, so clients may answer the server's keepalives.
+fn is_request(command: &Command) -> bool {
+    match command {
+        Command::Create(_)
+        | Command::CreateWithMetadata { .. }
+        | Command::CreateFromTemplate { .. }
+        | Command::Read
+        | Command::ReadPage { .. }
+        | Command::Describe(_)
+        | Command::GetByHash(_)
+        | Command::GetMany(_)
+        | Command::Get(_)
+        | Command::Query { .. }
+        | Command::Append(..)
+        | Command::CreateWithTtl(..)
+        | Command::Transfer(..)
+        | Command::Search(_)
+        | Command::Count
+        | Command::ListPage { .. }
+        | Command::Auth(_)
+        | Command::Stats
+        | Command::Config
+        | Command::Swap(..)
+        | Command::Delete(_)
+        | Command::CompareAndUpdate { .. }
+        | Command::Increment(..)
+        | Command::Subscribe { .. }
+        | Command::Unsubscribe
+        | Command::Ping(_)
+        | Command::Pong(_)
+        | Command::Hello { .. }
+        | Command::Disconnect(_)
+        | Command::Quit => true,
+        Command::List(_)
+        | Command::Id(_)
+        | Command::Error(_)
+        | Command::StatsResult(_)
+        | Command::ConfigResult(_)
+        | Command::Description(_)
+        | Command::Batch(_)
+        | Command::Page { .. }
+        | Command::Expiring(_)
+        | Command::Notify { .. }
+        | Command::Counter(..)
+        | Command::CompareAndUpdateResult { .. }
+        | Command::Created(_)
+        | Command::Note(..)
+        | Command::QueryResult(_)
+        | Command::Appended(..)
+        | Command::Authenticated(_)
+        | Command::CountResult(_)
+        | Command::ListPageResult { .. }
+        | Command::Closing(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::protocol::{Compression, PROTOCOL_VERSION};
+
+    const HELLO: Command = Command::Hello {
+        version: PROTOCOL_VERSION,
+        compression: Compression::None,
+    };
+
+    #[test]
+    fn hello_is_only_accepted_during_handshake() -> Result<()> {
+        let state = ConnectionState::default().next(&HELLO, 0)?;
+        assert_eq!(state, ConnectionState::Ready);
+        assert!(state.next(&HELLO, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn first_request_acknowledges_handshake() -> Result<()> {
+        let state = ConnectionState::AwaitingHandshake.next(&Command::Read, 0)?;
+        assert_eq!(state, ConnectionState::Ready);
+        Ok(())
+    }
+
+    #[test]
+    fn nothing_is_accepted_after_quit() -> Result<()> {
+        let state = ConnectionState::Ready.next(&Command::Quit, 0)?;
+        assert_eq!(state, ConnectionState::Closing);
+        assert!(state.next(&Command::Create("late".to_string()), 0).is_err());
+        assert!(state.next(&Command::Quit, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn only_disconnecting_yourself_closes() -> Result<()> {
+        let ready = ConnectionState::Ready;
+        assert_eq!(
+            ready.next(&Command::Disconnect(3), 2)?,
+            ConnectionState::Ready
+        );
+        assert_eq!(
+            ready.next(&Command::Disconnect(2), 2)?,
+            ConnectionState::Closing
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn server_commands_are_rejected() {
+        for state in [ConnectionState::AwaitingHandshake, ConnectionState::Ready] {
+            assert!(state.next(&Command::Id(1), 0).is_err());
+            assert!(state.next(&Command::Counter(1, 0), 0).is_err());
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use async_trait::async_trait;
+use color_eyre::eyre::Result;
+use common::{Note, NoteID};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Debug,
+    sync::Arc,
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Where the server keeps its notes. The server serializes access to the
+/// store behind one lock, so implementations need no locking of their own and
+/// compound operations such as compare-and-update stay atomic.
+#[async_trait]
+pub trait NoteStore: Debug + Send + Sync {
+    /// Store `note` under its id, replacing any note already stored there.
+    async fn insert(&mut self, note: Note) -> Result<()>;
+
+    async fn get(&self, id: NoteID) -> Result<Option<Note>>;
+
+    /// Remove and return the note stored under `id`.
+    async fn remove(&mut self, id: NoteID) -> Result<Option<Note>>;
+
+    /// Every stored note, in id order.
+    async fn all(&self) -> Result<Vec<Note>>;
+
+    /// The notes whose [`Note::hash`] is `hash`, in id order. Defaults to
+    /// filtering [`all`](Self::all); stores should look them up by index.
+    async fn find_by_hash(&self, hash: &str) -> Result<Vec<Note>> {
+        let mut notes = self.all().await?;
+        notes.retain(|note| note.hash() == hash);
+        Ok(notes)
+    }
+
+    async fn clear(&mut self) -> Result<()>;
+
+    /// How many notes are stored. Defaults to counting [`all`](Self::all).
+    async fn count(&self) -> Result<usize> {
+        Ok(self.all().await?.len())
+    }
+}
+
+/// A [`NoteStore`] shared between the server and its handlers.
+pub type SharedStore = Arc<AsyncMutex<Box<dyn NoteStore>>>;
+
+/// Keeps notes in memory; the default store, losing notes on restart unless
+/// the server persists them.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    notes: BTreeMap<NoteID, Note>,
+    /// Ids of the notes with each content hash, kept in step with `notes`.
+    by_hash: HashMap<String, BTreeSet<NoteID>>,
+}
+
+impl MemoryStore {
+    fn unindex(&mut self, note: &Note) {
+        if let Some(ids) = self.by_hash.get_mut(note.hash()) {
+            ids.remove(&note.id());
+            if ids.is_empty() {
+                self.by_hash.remove(note.hash());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl NoteStore for MemoryStore {
+    async fn insert(&mut self, note: Note) -> Result<()> {
+        let (id, hash) = (note.id(), note.hash().to_owned());
+        if let Some(previous) = self.notes.insert(id, note) {
+            self.unindex(&previous);
+        }
+        self.by_hash.entry(hash).or_default().insert(id);
+        Ok(())
+    }
+
+    async fn get(&self, id: NoteID) -> Result<Option<Note>> {
+        Ok(self.notes.get(&id).cloned())
+    }
+
+    async fn remove(&mut self, id: NoteID) -> Result<Option<Note>> {
+        let note = self.notes.remove(&id);
+        if let Some(note) = &note {
+            self.unindex(note);
+        }
+        Ok(note)
+    }
+
+    async fn all(&self) -> Result<Vec<Note>> {
+        Ok(self.notes.values().cloned().collect())
+    }
+
+    async fn find_by_hash(&self, hash: &str) -> Result<Vec<Note>> {
+        let ids = self.by_hash.get(hash).into_iter().flatten();
+        Ok(ids.map(|id| self.notes[id].clone()).collect())
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.notes.clear();
+        self.by_hash.clear();
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<usize> {
+        Ok(self.notes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_store_keeps_notes_in_id_order() -> Result<()> {
+        let mut store = MemoryStore::default();
+        for id in [2, 0, 1] {
+            store.insert(Note::new(id, format!("note {id}"))).await?;
+        }
+        store.insert(Note::new(1, "replaced".to_string())).await?;
+        let ids: Vec<_> = store.all().await?.iter().map(Note::id).collect();
+        assert_eq!(ids, [0, 1, 2]);
+        assert_eq!(store.get(1).await?.unwrap().body(), "replaced");
+
+        assert_eq!(store.remove(0).await?.unwrap().body(), "note 0");
+        assert!(store.get(0).await?.is_none());
+        assert_eq!(store.count().await?, 2);
+        store.clear().await?;
+        assert_eq!(store.count().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memory_store_finds_notes_by_their_current_hash() -> Result<()> {
+        let mut store = MemoryStore::default();
+        for id in [3, 1, 2] {
+            store.insert(Note::new(id, "same".to_string())).await?;
+        }
+        let hash = common::content_hash("same");
+        let ids = |notes: Vec<Note>| notes.iter().map(Note::id).collect::<Vec<_>>();
+        assert_eq!(ids(store.find_by_hash(&hash).await?), [1, 2, 3]);
+
+        store.insert(Note::new(1, "changed".to_string())).await?;
+        store.remove(3).await?;
+        assert_eq!(ids(store.find_by_hash(&hash).await?), [2]);
+        let changed = common::content_hash("changed");
+        assert_eq!(ids(store.find_by_hash(&changed).await?), [1]);
+        store.clear().await?;
+        assert!(store.find_by_hash(&changed).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use color_eyre::eyre::{anyhow, Result};
+use listenfd::ListenFd;
+use tokio::net::TcpListener;
+
+/// Adopt the first socket passed by systemd socket activation (`LISTEN_FDS`)
+/// instead of binding one. Must be called from within the runtime.
+pub fn listener() -> Result<TcpListener> {
+    let listener = ListenFd::from_env()
+        .take_tcp_listener(0)?
+        .ok_or(anyhow!("no listening socket was passed in LISTEN_FDS"))?;
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::NotesServer;
+    use common::{
+        protocol::{Command, Frame},
+        Connection,
+    };
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn serves_on_adopted_socket() -> Result<()> {
+        use std::os::fd::IntoRawFd;
+
+        let passed = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = passed.local_addr()?;
+        // Hand the fd over the way systemd would, without assuming it is fd 3.
+        std::env::set_var("LISTEN_PID", std::process::id().to_string());
+        std::env::set_var("LISTEN_FDS", "1");
+        std::env::set_var("LISTEN_FDS_FIRST_FD", passed.into_raw_fd().to_string());
+
+        let listener = listener()?;
+        assert_eq!(listener.local_addr()?, addr);
+        let socket = TcpStream::connect(addr).await?;
+        let mut notes_server = NotesServer::default();
+        notes_server
+            .handle_connection(listener.accept().await?.0)
+            .await?;
+        let mut connection = Connection::new(socket);
+        assert!(matches!(
+            connection.read_frame().await?,
+            Some(Frame(Command::Id(_)))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use color_eyre::eyre::{anyhow, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+/// Named note bodies with `{placeholder}`s, filled in at creation time.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    templates: HashMap<String, String>,
+}
+
+impl Templates {
+    /// Load templates from a file of `name = body` lines; blank lines and
+    /// lines starting with `#` are skipped.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, body: impl Into<String>) {
+        self.templates.insert(name.into(), body.into());
+    }
+
+    /// Names of the loaded templates, sorted.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.templates.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Render the named template, replacing every `{key}` with `vars[key]`.
+    pub fn render(&self, name: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+        let template = self
+            .templates
+            .get(name)
+            .ok_or(anyhow!("unknown template {name}"))?;
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .ok_or(anyhow!("unclosed placeholder in template {name}"))?;
+            let key = &rest[start + 1..start + end];
+            let value = vars
+                .get(key)
+                .ok_or(anyhow!("missing value for {{{key}}} in template {name}"))?;
+            rendered.push_str(value);
+            rest = &rest[start + end + 1..];
+        }
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+}
+
+impl std::str::FromStr for Templates {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut templates = Self::default();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, body) = line
+                .split_once('=')
+                .ok_or(anyhow!("line {}: expected `name = body`", number + 1))?;
+            templates.insert(name.trim(), body.trim());
+        }
+        Ok(templates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_fills_placeholders() -> Result<()> {
+        let templates: Templates = "# daily notes\n\nstandup = {who}: {done} today\n".parse()?;
+        let vars = BTreeMap::from([
+            ("who".to_string(), "alice".to_string()),
+            ("done".to_string(), "reviews".to_string()),
+        ]);
+        assert_eq!(templates.render("standup", &vars)?, "alice: reviews today");
+        assert!(templates.render("standup", &BTreeMap::new()).is_err());
+        assert!(templates.render("retro", &vars).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use tokio::time::{Duration, Instant};
+
+/// Token bucket allowing `rate` operations per second on average, with bursts
+/// of up to a second's worth.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket refilled at `rate` tokens per second. `rate` must be positive.
+    pub fn new(rate: u32) -> Self {
+        assert!(rate > 0, "token bucket rate must be positive");
+        Self {
+            rate: rate.into(),
+            tokens: rate.into(),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take a token, waiting for the bucket to refill if it is empty.
+    pub async fn acquire(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            tokio::time::sleep(wait).await;
+            self.refill();
+        }
+        self.tokens = (self.tokens - 1.0).max(0.0);
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = (now - self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+    }
+}